@@ -0,0 +1,118 @@
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::{Error, ErrorKind};
+
+/// Bounds on `<<CMD:...>>` substitution, so a hung or runaway command can't stall loading.
+///
+/// Command substitution runs whatever program the config names, with the loading process's
+/// privileges, so only enable the `command-substitution` feature for config files you trust as
+/// much as your own code. Commands are never built from env values or file contents; only the
+/// literal text of the placeholder is run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandLimits {
+    /// How long a command may run before it's killed. Defaults to 10 seconds.
+    pub timeout: Duration,
+    /// The most bytes of output accepted. Defaults to 64 KiB.
+    pub max_output: usize
+}
+
+impl Default for CommandLimits {
+    fn default() -> CommandLimits {
+        CommandLimits { timeout: Duration::from_secs(10), max_output: 64 * 1024 }
+    }
+}
+
+/// Runs `command` and returns its stdout. The command is split on whitespace and run directly,
+/// not through a shell, so quoting, pipes, and variable expansion aren't supported. Fails if the
+/// command can't be started, exits unsuccessfully, runs past the timeout, or prints more than the
+/// output limit.
+pub fn run(command: &str, limits: &CommandLimits) -> Result<String, Error> {
+    let failed = |reason: String| Error::from(ErrorKind::CommandFailed(command.to_owned(), reason));
+
+    let mut args = command.split_whitespace();
+    let program = args.next().ok_or_else(|| failed("empty command".to_owned()))?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| failed(e.to_string()))?;
+
+    // Read on another thread so a chatty command can't block on a full pipe while we wait.
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let max_output = limits.max_output;
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = tx.send(stdout.take(max_output as u64 + 1).read_to_end(&mut output).map(|_| output));
+    });
+
+    let deadline = Instant::now() + limits.timeout;
+    let mut output = None;
+    let status = loop {
+        if output.is_none() {
+            output = rx.try_recv().ok();
+        }
+        if let Some(Ok(ref output)) = output {
+            if output.len() > max_output {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(failed(format!("output exceeded {} bytes", max_output)));
+            }
+        }
+        if let Some(status) = child.try_wait().map_err(|e| failed(e.to_string()))? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(failed(format!("timed out after {:?}", limits.timeout)));
+        }
+        thread::sleep(Duration::from_millis(5));
+    };
+
+    let output = match output {
+        Some(output) => output,
+        None => rx.recv().map_err(|_| failed("failed reading output".to_owned()))?
+    }.map_err(|e| failed(e.to_string()))?;
+    if output.len() > max_output {
+        return Err(failed(format!("output exceeded {} bytes", max_output)));
+    }
+    if !status.success() {
+        return Err(failed(status.to_string()));
+    }
+    String::from_utf8(output).map_err(|e| failed(e.to_string()))
+}
+
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::{run, CommandLimits};
+    use crate::ErrorKind;
+    use std::time::Duration;
+
+    fn reason(command: &str, limits: &CommandLimits) -> String {
+        match *run(command, limits).unwrap_err().kind() {
+            ErrorKind::CommandFailed(_, ref reason) => reason.clone(),
+            ref other => panic!("Expected command failure, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn it_captures_command_output() {
+        assert_eq!(run("echo hello   world", &CommandLimits::default()).unwrap(), "hello world\n");
+    }
+
+    #[test]
+    fn it_enforces_command_limits() {
+        let limits = CommandLimits { timeout: Duration::from_millis(100), max_output: 16 };
+        assert!(reason("sleep 5", &limits).contains("timed out"));
+        assert!(reason("yes", &limits).contains("exceeded 16 bytes"));
+        assert!(reason("false", &limits).contains("exit status"));
+        assert!(!reason("config_loader_no_such_command", &limits).is_empty());
+    }
+}
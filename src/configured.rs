@@ -0,0 +1,71 @@
+use serde::de::DeserializeOwned;
+use std::convert::TryFrom;
+use std::ops::Deref;
+use std::path::Path;
+use std::str::FromStr;
+use crate::{load_config, load_config_from_str, Error};
+
+/// A loaded config, so conversions can load one: `"port = 1".parse::<Configured<Config>>()`
+/// as by `load_config_from_str`, or `Configured::<Config>::try_from(path)` as by `load_config`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Configured<C>(pub C);
+
+impl<C> Configured<C> {
+    pub fn into_inner(self) -> C {
+        self.0
+    }
+}
+
+impl<C> Deref for Configured<C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.0
+    }
+}
+
+impl<C: DeserializeOwned> FromStr for Configured<C> {
+    type Err = Error;
+
+    fn from_str(config_str: &str) -> Result<Configured<C>, Error> {
+        load_config_from_str(config_str).map(Configured)
+    }
+}
+
+impl<'p, C: DeserializeOwned> TryFrom<&'p Path> for Configured<C> {
+    type Error = Error;
+
+    fn try_from(config_path: &'p Path) -> Result<Configured<C>, Error> {
+        load_config(Some(config_path)).map(Configured)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::Configured;
+    use crate::ErrorKind;
+    use std::convert::TryInto;
+    use std::env;
+    use std::fs;
+
+    #[derive(Debug, Deserialize)]
+    struct Config {
+        name: String
+    }
+
+    #[test]
+    fn it_loads_configs_through_conversions() {
+        let config: Configured<Config> = "name = \"parsed\"".parse().unwrap();
+        assert_eq!(&config.name, "parsed");
+
+        let config_path = env::temp_dir().join("config_loader_configured.toml");
+        fs::write(&config_path, "name = \"converted\"\n").unwrap();
+        let config: Configured<Config> = config_path.as_path().try_into().unwrap();
+        assert_eq!(config.into_inner().name, "converted");
+        fs::remove_file(&config_path).unwrap();
+
+        let result: Result<Configured<Config>, _> = config_path.as_path().try_into();
+        assert!(matches!(*result.unwrap_err().kind(), ErrorKind::ConfigNotFound(_)));
+    }
+}
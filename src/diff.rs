@@ -0,0 +1,101 @@
+use std::collections::BTreeSet;
+use crate::expand::{self, key_path, redact, ExpandOptions, ExpandedConfig};
+use crate::{Error, Format};
+
+/// A difference between two expanded configs at a dotted key path, e.g. `servers[0].port`.
+/// Values that came from secret placeholders like `<<SECRET_ENV:DB_PASS>>` are reported as `***`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigChange {
+    Added { path: String, value: toml::Value },
+    Removed { path: String, value: toml::Value },
+    Changed { path: String, old: toml::Value, new: toml::Value }
+}
+
+/// Expands two TOML configs and lists the keys added, removed, or changed between them, e.g. to
+/// audit-log a reload. Tables are compared key by key and arrays element by element; any other
+/// difference, including a change of type, is reported as a single `Changed`.
+pub fn config_diff(old_str: &str, new_str: &str) -> Result<Vec<ConfigChange>, Error> {
+    let options = ExpandOptions::default();
+    let old = expand::expand(&options, Format::Toml.parse(old_str)?)?;
+    let new = expand::expand(&options, Format::Toml.parse(new_str)?)?;
+
+    let mut changes = Vec::new();
+    diff_value(&old, &new, "", old.value(), new.value(), &mut changes);
+    Ok(changes)
+}
+
+fn diff_value(old: &ExpandedConfig, new: &ExpandedConfig, path: &str, old_value: &toml::Value, new_value: &toml::Value, changes: &mut Vec<ConfigChange>) {
+    match (old_value, new_value) {
+        (toml::Value::Table(old_table), toml::Value::Table(new_table)) => {
+            let keys = old_table.keys().chain(new_table.keys()).collect::<BTreeSet<_>>();
+            for k in keys {
+                let child = key_path(path, k);
+                match (old_table.get(k), new_table.get(k)) {
+                    (Some(o), Some(n)) => diff_value(old, new, &child, o, n, changes),
+                    (Some(o), None) => changes.push(ConfigChange::Removed { value: shown(old, &child, o), path: child }),
+                    (None, Some(n)) => changes.push(ConfigChange::Added { value: shown(new, &child, n), path: child }),
+                    (None, None) => ()
+                }
+            }
+        },
+        (toml::Value::Array(old_values), toml::Value::Array(new_values)) =>
+            for i in 0..old_values.len().max(new_values.len()) {
+                let child = format!("{}[{}]", path, i);
+                match (old_values.get(i), new_values.get(i)) {
+                    (Some(o), Some(n)) => diff_value(old, new, &child, o, n, changes),
+                    (Some(o), None) => changes.push(ConfigChange::Removed { value: shown(old, &child, o), path: child }),
+                    (None, Some(n)) => changes.push(ConfigChange::Added { value: shown(new, &child, n), path: child }),
+                    (None, None) => ()
+                }
+            },
+        _ if old_value != new_value =>
+            changes.push(ConfigChange::Changed {
+                path: path.to_owned(),
+                old: shown(old, path, old_value),
+                new: shown(new, path, new_value)
+            }),
+        _ => ()
+    }
+}
+
+/// `value` at `path` in `config`, with any secrets redacted.
+fn shown(config: &ExpandedConfig, path: &str, value: &toml::Value) -> toml::Value {
+    redact(config.secret_paths(), path, value.clone())
+}
+
+
+#[cfg(all(test, feature = "substitution"))]
+mod tests {
+    use super::{config_diff, ConfigChange};
+
+    #[test]
+    fn it_diffs_configs() {
+        let old = r#"
+            name = "app"
+            port = 80
+            hosts = ["a", "b"]
+            [db]
+            password = "<<SECRET_ENV:DIFF_PASSWORD_OLD:-old password>>"
+            pool = 5
+        "#;
+        let new = r#"
+            name = "app"
+            port = "80"
+            hosts = ["a", "c", "d"]
+            debug = true
+            [db]
+            password = "<<SECRET_ENV:DIFF_PASSWORD_NEW:-new password>>"
+        "#;
+
+        let string = |s: &str| toml::Value::String(s.to_owned());
+        assert_eq!(config_diff(old, new).unwrap(), vec![
+            ConfigChange::Changed { path: "db.password".to_owned(), old: string("***"), new: string("***") },
+            ConfigChange::Removed { path: "db.pool".to_owned(), value: toml::Value::Integer(5) },
+            ConfigChange::Added { path: "debug".to_owned(), value: toml::Value::Boolean(true) },
+            ConfigChange::Changed { path: "hosts[1]".to_owned(), old: string("b"), new: string("c") },
+            ConfigChange::Added { path: "hosts[2]".to_owned(), value: string("d") },
+            ConfigChange::Changed { path: "port".to_owned(), old: toml::Value::Integer(80), new: string("80") }
+        ]);
+        assert_eq!(config_diff(old, old).unwrap(), vec![]);
+    }
+}
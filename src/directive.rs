@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use crate::{Error, ErrorKind};
+
+/// Resolves the argument of a custom directive, e.g. the `/app/db_pass` of
+/// `<<AWS_SSM:/app/db_pass>>`, to its value.
+pub type DirectiveResolver = Box<dyn Fn(&str) -> Result<String, Error> + Send + Sync>;
+
+type SharedResolver = Arc<dyn Fn(&str) -> Result<String, Error> + Send + Sync>;
+
+lazy_static! {
+    static ref DIRECTIVES: RwLock<HashMap<String, SharedResolver>> = RwLock::new(HashMap::new());
+}
+
+/// Registers `resolver` for placeholders of the form `<<NAME:arg>>`, e.g. to fetch secrets from a
+/// store this crate doesn't support. Names are letters, digits, and underscores, starting with a
+/// letter; built-in directives like `ENV` always take precedence. Env placeholders within the
+/// argument are resolved before it's passed to `resolver`, and a placeholder naming a directive
+/// that isn't registered fails with `ErrorKind::UnregisteredDirective`. Registering a name again
+/// replaces its resolver. Resolved values are treated as secrets and hidden by
+/// `ExpandedConfig::redacted_value`, and errors from `resolver` are returned as is.
+pub fn register_directive(name: &str, resolver: DirectiveResolver) {
+    DIRECTIVES.write().unwrap_or_else(|e| e.into_inner()).insert(name.to_owned(), Arc::from(resolver));
+}
+
+/// Runs the resolver registered for `name` on the placeholder at key path `path`, failing with
+/// `ErrorKind::UnregisteredDirective` if there isn't one.
+pub fn resolve(name: &str, arg: &str, path: &str) -> Result<String, Error> {
+    let resolver = DIRECTIVES.read().unwrap_or_else(|e| e.into_inner()).get(name).cloned();
+    match resolver {
+        Some(resolver) => resolver(arg),
+        None => Err(ErrorKind::UnregisteredDirective(name.to_owned(), path.to_owned()).into())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{register_directive, resolve};
+    use crate::{load_config_debug, load_config_from_str, ErrorKind};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn it_resolves_custom_directives() {
+        register_directive("TEST_UPPER", Box::new(|arg| match arg {
+            "fail" => Err(ErrorKind::Validation("no such secret".to_owned()).into()),
+            arg => Ok(arg.to_uppercase())
+        }));
+
+        let (config, expanded) = load_config_debug::<BTreeMap<String, String>>("secret = \"<<TEST_UPPER:hunter2>>\"").unwrap();
+        assert_eq!(&config["secret"], "HUNTER2");
+        assert!(expanded.secret_paths().contains("secret"));
+
+        let err = load_config_debug::<BTreeMap<String, String>>("secret = \"<<TEST_UPPER:fail>>\"").unwrap_err();
+        assert!(matches!(*err.kind(), ErrorKind::Validation(_)));
+    }
+
+    #[test]
+    fn it_passes_directives_their_expanded_argument() {
+        register_directive("TEST_ECHO", Box::new(|arg| Ok(arg.to_owned())));
+
+        let config = load_config_from_str::<BTreeMap<String, String>>("secret = \"<<TEST_ECHO:/app/<<ENV:TEST_ECHO_STAGE:-prod>>/db>>\"").unwrap();
+        assert_eq!(&config["secret"], "/app/prod/db");
+    }
+
+    #[test]
+    fn it_fails_for_unregistered_directives_in_config() {
+        match *load_config_from_str::<BTreeMap<String, String>>("secret = \"<<TEST_UNREGISTERED:x>>\"").unwrap_err().kind() {
+            ErrorKind::UnregisteredDirective(ref name, ref path) => {
+                assert_eq!(name, "TEST_UNREGISTERED");
+                assert_eq!(path, "secret");
+            },
+            ref other => panic!("Expected unregistered directive, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn it_fails_to_resolve_unregistered_directives() {
+        match *resolve("TEST_NEVER_REGISTERED", "x", "db.password").unwrap_err().kind() {
+            ErrorKind::UnregisteredDirective(ref name, ref path) => {
+                assert_eq!(name, "TEST_NEVER_REGISTERED");
+                assert_eq!(path, "db.password");
+            },
+            ref other => panic!("Expected unregistered directive, got {:?}", other)
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use crate::{Error, ErrorKind};
+
+/// Reads the dotenv file at `path` and sets each variable in the process environment, unless it's
+/// already set there, so the real environment always wins.
+pub fn load_dotenv(path: &Path) -> Result<(), Error> {
+    for (key, value) in parse(&fs::read_to_string(path)?)? {
+        if env::var_os(&key).is_none() {
+            env::set_var(key, value);
+        }
+    }
+    Ok(())
+}
+
+/// Parses `KEY=VALUE` lines. Blank lines and `#` comments are skipped, as is a leading
+/// `export `. Values may be double quoted (with `\n`, `\"`, and `\\` escapes), single quoted
+/// (taken literally), or unquoted, in which case a trailing ` # comment` is dropped and the value
+/// is trimmed.
+pub fn parse(s: &str) -> Result<Vec<(String, String)>, Error> {
+    let mut vars = Vec::new();
+    for (i, line) in s.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+
+        let invalid = |reason: &str| Error::from(ErrorKind::DotenvParse(i + 1, reason.to_owned()));
+        let (key, value) = line.split_once('=').ok_or_else(|| invalid("expected KEY=VALUE"))?;
+        let key = key.trim();
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            return Err(invalid("invalid variable name"));
+        }
+
+        let value = value.trim_start();
+        let value = if let Some(quoted) = value.strip_prefix('"') {
+            unescape_double_quoted(quoted).ok_or_else(|| invalid("unterminated double quote"))?
+        } else if let Some(quoted) = value.strip_prefix('\'') {
+            let end = quoted.find('\'').ok_or_else(|| invalid("unterminated single quote"))?;
+            quoted[..end].to_owned()
+        } else {
+            let end = value.find(" #").unwrap_or(value.len());
+            value[..end].trim().to_owned()
+        };
+        vars.push((key.to_owned(), value));
+    }
+    Ok(vars)
+}
+
+/// Unescapes the contents of a double quoted value up to its closing quote, or `None` if there
+/// isn't one.
+fn unescape_double_quoted(s: &str) -> Option<String> {
+    let mut value = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Some(value),
+            '\\' => match chars.next()? {
+                'n' => value.push('\n'),
+                'r' => value.push('\r'),
+                't' => value.push('\t'),
+                other => value.push(other)
+            },
+            other => value.push(other)
+        }
+    }
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+    use crate::ErrorKind;
+
+    #[test]
+    fn it_parses_dotenv_lines() {
+        let vars = parse(r#"
+            # a comment
+            PLAIN=value # trailing comment
+            export EXPORTED = spaced value
+            DOUBLE="line one\nsays \"hi\" # not a comment"
+            SINGLE='literal \n # too'
+            EMPTY=
+        "#).unwrap();
+
+        assert_eq!(vars, vec![
+            ("PLAIN".to_owned(), "value".to_owned()),
+            ("EXPORTED".to_owned(), "spaced value".to_owned()),
+            ("DOUBLE".to_owned(), "line one\nsays \"hi\" # not a comment".to_owned()),
+            ("SINGLE".to_owned(), "literal \\n # too".to_owned()),
+            ("EMPTY".to_owned(), "".to_owned())
+        ]);
+    }
+
+    #[test]
+    fn it_reports_invalid_dotenv_lines() {
+        match *parse("A=1\nnot a var\n").unwrap_err().kind() {
+            ErrorKind::DotenvParse(line, _) => assert_eq!(line, 2),
+            ref other => panic!("Expected dotenv parse error, got {:?}", other)
+        }
+        assert!(parse("A=\"unterminated\n").is_err());
+    }
+}
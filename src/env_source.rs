@@ -0,0 +1,117 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt;
+use std::sync::Arc;
+
+/// Where env placeholders are resolved from. The loading functions read the process environment
+/// unless given another source, e.g. a map for tests, or a secret store client.
+pub trait EnvSource: Send + Sync {
+    /// The value of `key`, or `None` if it isn't set.
+    fn get(&self, key: &str) -> Option<String>;
+
+    /// Every var name that's set, used to match names case-insensitively. Sources that can't list
+    /// their vars may return none, in which case only exact names match.
+    fn keys(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// The process environment. Values that aren't valid unicode are treated as unset.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessEnv;
+
+impl EnvSource for ProcessEnv {
+    fn get(&self, key: &str) -> Option<String> {
+        env::var(key).ok()
+    }
+
+    fn keys(&self) -> Vec<String> {
+        env::vars_os().filter_map(|(key, _)| key.into_string().ok()).collect()
+    }
+}
+
+impl EnvSource for HashMap<String, String> {
+    fn get(&self, key: &str) -> Option<String> {
+        HashMap::get(self, key).cloned()
+    }
+
+    fn keys(&self) -> Vec<String> {
+        HashMap::keys(self).cloned().collect()
+    }
+}
+
+impl EnvSource for BTreeMap<String, String> {
+    fn get(&self, key: &str) -> Option<String> {
+        BTreeMap::get(self, key).cloned()
+    }
+
+    fn keys(&self) -> Vec<String> {
+        BTreeMap::keys(self).cloned().collect()
+    }
+}
+
+/// Layers two sources: variables set in the first override those in the second, e.g.
+/// `(overrides, ProcessEnv)`.
+impl<A: EnvSource, B: EnvSource> EnvSource for (A, B) {
+    fn get(&self, key: &str) -> Option<String> {
+        self.0.get(key).or_else(|| self.1.get(key))
+    }
+
+    fn keys(&self) -> Vec<String> {
+        let mut keys = self.0.keys();
+        keys.extend(self.1.keys());
+        keys
+    }
+}
+
+impl<T: EnvSource + ?Sized> EnvSource for Arc<T> {
+    fn get(&self, key: &str) -> Option<String> {
+        (**self).get(key)
+    }
+
+    fn keys(&self) -> Vec<String> {
+        (**self).keys()
+    }
+}
+
+impl<T: EnvSource + ?Sized> EnvSource for Box<T> {
+    fn get(&self, key: &str) -> Option<String> {
+        (**self).get(key)
+    }
+
+    fn keys(&self) -> Vec<String> {
+        (**self).keys()
+    }
+}
+
+/// A shared `EnvSource` that can be stored in cloneable, debuggable settings.
+#[derive(Clone)]
+pub struct SharedEnvSource(#[cfg_attr(not(feature = "substitution"), allow(dead_code))] pub Arc<dyn EnvSource>);
+
+impl fmt::Debug for SharedEnvSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SharedEnvSource")
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{EnvSource, ProcessEnv};
+    use std::collections::HashMap;
+    use std::env;
+
+    #[test]
+    fn it_layers_env_sources() {
+        env::set_var("ENV_SOURCE_LAYERED", "process");
+        env::set_var("ENV_SOURCE_PROCESS_ONLY", "process only");
+
+        let mut overrides = HashMap::new();
+        overrides.insert("ENV_SOURCE_LAYERED".to_owned(), "override".to_owned());
+        let layered = (overrides, ProcessEnv);
+
+        assert_eq!(layered.get("ENV_SOURCE_LAYERED"), Some("override".to_owned()));
+        assert_eq!(layered.get("ENV_SOURCE_PROCESS_ONLY"), Some("process only".to_owned()));
+        assert_eq!(layered.get("ENV_SOURCE_UNSET"), None);
+    }
+}
@@ -0,0 +1,844 @@
+use std::collections::{BTreeMap, BTreeSet};
+#[cfg(feature = "substitution")]
+use std::env;
+#[cfg(feature = "substitution")]
+use std::fs::File;
+#[cfg(feature = "substitution")]
+use std::io::{self, Read};
+#[cfg(feature = "substitution")]
+use std::thread;
+use std::time::Duration;
+#[cfg(feature = "command-substitution")]
+use crate::command::{self, CommandLimits};
+use crate::env_source::SharedEnvSource;
+#[cfg(feature = "substitution")]
+use crate::env_source::{EnvSource, ProcessEnv};
+#[cfg(feature = "substitution")]
+use crate::directive;
+#[cfg(feature = "substitution")]
+use crate::printf::FormatSpec;
+#[cfg(feature = "substitution")]
+use crate::syntax::Syntax;
+use crate::lazy::{lookup, lookup_mut};
+use crate::{combine_errors, empty_table, Error, ErrorKind};
+#[cfg(feature = "substitution")]
+use crate::ResultExt;
+
+/// How many times a resolved env value may itself be expanded before giving up on it as a cycle.
+pub const MAX_EXPANSION_DEPTH: usize = 10;
+
+/// Directive names that are never custom, even when their own pattern doesn't match, e.g.
+/// `<<CMD:...>>` without the `command-substitution` feature.
+#[cfg(feature = "substitution")]
+const BUILT_IN_DIRECTIVES: &[&str] = &["FILE", "CMD", "KEYRING"];
+
+/// Settings controlling how placeholders are expanded.
+#[derive(Debug, Clone, Default)]
+pub struct ExpandOptions {
+    /// Insert env values that look like booleans or numbers as those types instead of strings.
+    pub coerce: bool,
+    /// Whether to trim surrounding whitespace from resolved values. `None`, the default, trims
+    /// file contents (which usually end in a newline) but not env values; `Some(true)` trims
+    /// both and `Some(false)` neither.
+    pub trim: Option<bool>,
+    /// Fail with `EnvVarMissing` for unset optional vars like `<<ENV?:KEY>>` rather than dropping
+    /// the key, e.g. to catch an incomplete environment in development.
+    pub require_optional: bool,
+    /// Fail with `EnvVarEmpty` for required vars that are set to an empty string.
+    pub reject_empty_required: bool,
+    /// Keep the keys of unset optional vars, recording their paths in `ExpandedConfig` to
+    /// deserialize as null, rather than dropping them.
+    pub optional_as_null: bool,
+    /// Drop tables left empty once their unset optional values are removed, so they deserialize
+    /// as `None`. Tables written empty are kept.
+    pub prune_empty_tables: bool,
+    /// Format the values of placeholders like `<<ENV:PORT:%05d>>` printf-style. Otherwise
+    /// they're invalid placeholders.
+    #[cfg(feature = "substitution")]
+    pub printf_format: bool,
+    /// Where to resolve placeholders from instead of the process environment.
+    pub env: Option<SharedEnvSource>,
+    /// Fall back to matching env var names case-insensitively when there's no exact match.
+    pub case_insensitive_env: bool,
+    /// How many times to retry a failed `<<CMD:...>>`, `<<KEYRING:...>>`, or custom directive.
+    pub directive_retries: u32,
+    /// How long to wait before the first retry; the wait doubles after each one.
+    pub directive_backoff: Duration,
+    /// Resolve env placeholders only, failing with `DirectiveNotAllowed` for `<<FILE:...>>`,
+    /// `<<CMD:...>>`, `<<KEYRING:...>>`, and custom directives, e.g. for config from an
+    /// untrusted source.
+    #[cfg_attr(not(feature = "substitution"), allow(dead_code))]
+    pub env_only: bool,
+    /// Timeout and output limit for `<<CMD:...>>` substitution.
+    #[cfg(feature = "command-substitution")]
+    pub command_limits: CommandLimits,
+    #[cfg(feature = "substitution")]
+    pub syntax: Syntax
+}
+
+/// State carried through a single expansion of a document.
+struct ExpandContext<'a> {
+    #[cfg_attr(not(feature = "substitution"), allow(dead_code))]
+    options: &'a ExpandOptions,
+    /// Leave strings that fail to expand untouched and record the error in `errors`, rather than
+    /// failing the whole expansion.
+    lenient: bool,
+    errors: Vec<Error>,
+    /// In lenient mode, the key path and text of each string left unexpanded.
+    unresolved: Vec<(String, String)>,
+    /// Key paths, in the expanded document, of values that came from secret placeholders.
+    secrets: BTreeSet<String>,
+    /// Key paths, in the expanded document, of `<<ENV:KEY||path.to.key>>` placeholders whose var
+    /// was unset, with the key paths to fill them from once the document is expanded.
+    fallbacks: BTreeMap<String, String>,
+    /// With `optional_as_null`, key paths of unset optional values, which are kept as empty
+    /// strings. Only `expand` sets this, since the other entry points can't return the paths.
+    nulls: Option<BTreeSet<String>>,
+    report: LoadReport
+}
+
+impl<'a> ExpandContext<'a> {
+    fn new(options: &'a ExpandOptions, lenient: bool) -> ExpandContext<'a> {
+        ExpandContext { options, lenient, errors: Vec::new(), unresolved: Vec::new(), secrets: BTreeSet::new(), fallbacks: BTreeMap::new(), nulls: None, report: LoadReport::default() }
+    }
+}
+
+/// Text that secret values are replaced with by `ExpandedConfig::redacted_value`.
+pub const REDACTED: &str = "***";
+
+/// A fully expanded config document, along with which of its values came from secret
+/// placeholders like `<<SECRET_ENV:DB_PASS>>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpandedConfig {
+    value: toml::Value,
+    secrets: BTreeSet<String>,
+    nulls: BTreeSet<String>,
+    report: LoadReport
+}
+
+/// Which env vars supplied values while expanding a config, by the key path that referenced them
+/// (as written, before dropped optional array elements shift later ones). Fallback chains like
+/// `DATABASE_URL|DB_URL` list the var that was set, or the whole chain if none was.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoadReport {
+    /// Vars referenced by `<<ENV:KEY>>` or `<<SECRET_ENV:KEY>>` without a default.
+    pub required: BTreeMap<String, BTreeSet<String>>,
+    /// Optional or defaulted vars, like `<<ENV?:KEY>>` or `<<ENV:KEY:-default>>`, that were set.
+    pub optional: BTreeMap<String, BTreeSet<String>>,
+    /// Optional or defaulted vars that weren't set, so their key was dropped or the default used.
+    pub skipped: BTreeMap<String, BTreeSet<String>>
+}
+
+impl ExpandedConfig {
+    /// The expanded document, secrets included.
+    pub fn value(&self) -> &toml::Value {
+        &self.value
+    }
+
+    pub fn into_value(self) -> toml::Value {
+        self.value
+    }
+
+    /// Dotted key paths (e.g. `database.password` or `servers[1].token`) of secret values.
+    pub fn secret_paths(&self) -> &BTreeSet<String> {
+        &self.secrets
+    }
+
+    /// Dotted key paths of unset optional values kept with `ExpandOptions::optional_as_null`. The
+    /// document holds an empty string at each, since TOML has no null.
+    pub fn null_paths(&self) -> &BTreeSet<String> {
+        &self.nulls
+    }
+
+    /// Which env vars supplied values, and which optional ones were unset.
+    pub fn report(&self) -> &LoadReport {
+        &self.report
+    }
+
+    /// A copy of the expanded document with every secret value replaced by `***`, safe to log.
+    pub fn redacted_value(&self) -> toml::Value {
+        redact(&self.secrets, "", self.value.clone())
+    }
+}
+
+/// Replaces `value`, or any value nested in it, whose path is in `secrets` with `***`.
+pub fn redact(secrets: &BTreeSet<String>, path: &str, value: toml::Value) -> toml::Value {
+    if secrets.contains(path) {
+        return toml::Value::String(REDACTED.to_owned());
+    }
+
+    match value {
+        toml::Value::Table(table) =>
+            toml::Value::Table(table.into_iter()
+                .map(|(k, v)| {
+                    let v = redact(secrets, &key_path(path, &k), v);
+                    (k, v)
+                })
+                .collect()),
+        toml::Value::Array(values) =>
+            toml::Value::Array(values.into_iter().enumerate()
+                .map(|(i, v)| redact(secrets, &format!("{}[{}]", path, i), v))
+                .collect()),
+        other_value =>
+            other_value
+    }
+}
+
+/// Expands every placeholder in `value`, failing with all errors combined if any can't be
+/// resolved. `<<ENV:KEY||path.to.key>>` placeholders whose var is unset are then filled in from
+/// the expanded document, in a second pass.
+pub fn expand(options: &ExpandOptions, value: toml::Value) -> Result<ExpandedConfig, Error> {
+    let mut ctx = ExpandContext::new(options, false);
+    ctx.nulls = options.optional_as_null.then(BTreeSet::new);
+    let mut value = load_env_variable(&mut ctx, "", value)?.unwrap_or_else(empty_table);
+    resolve_config_fallbacks(&mut ctx, &mut value)?;
+    Ok(ExpandedConfig { value, secrets: ctx.secrets, nulls: ctx.nulls.unwrap_or_default(), report: ctx.report })
+}
+
+/// Expands the placeholders in `value`, found at key path `path` of a larger document. Returns
+/// `None` if `value` is an unset optional placeholder. Without the rest of the document,
+/// `<<ENV:KEY||path.to.key>>` fails with `ErrorKind::InvalidReference` if `KEY` is unset.
+pub fn expand_at(options: &ExpandOptions, path: &str, value: toml::Value) -> Result<Option<toml::Value>, Error> {
+    let mut ctx = ExpandContext::new(options, false);
+    let value = load_env_variable(&mut ctx, path, value)?;
+    match ctx.fallbacks.into_iter().next() {
+        Some((path, fallback)) => Err(ErrorKind::InvalidReference(fallback, path).into()),
+        None => Ok(value)
+    }
+}
+
+/// Expands every placeholder that can be resolved, leaving any string that fails untouched.
+/// Returns the partially expanded document along with every error encountered and the key paths
+/// of the strings left untouched.
+pub fn expand_lenient(options: &ExpandOptions, value: toml::Value) -> (toml::Value, Vec<Error>, BTreeSet<String>) {
+    let mut ctx = ExpandContext::new(options, true);
+    let mut expanded = match load_env_variable(&mut ctx, "", value) {
+        Ok(expanded) => expanded.unwrap_or_else(empty_table),
+        Err(e) => {
+            ctx.errors.push(e);
+            empty_table()
+        }
+    };
+    if let Err(e) = resolve_config_fallbacks(&mut ctx, &mut expanded) {
+        ctx.errors.push(e);
+    }
+    let unresolved = ctx.unresolved.into_iter().map(|(path, _)| path).collect();
+    (expanded, ctx.errors, unresolved)
+}
+
+/// Expands `value` leniently, returning the key path and text of every string that couldn't be
+/// expanded, including keys. Paths are as written, before dropped optional array elements
+/// shift later ones.
+pub fn find_unresolved(options: &ExpandOptions, value: toml::Value) -> Vec<(String, String)> {
+    let mut ctx = ExpandContext::new(options, true);
+    let _ = load_env_variable(&mut ctx, "", value);
+    ctx.unresolved
+}
+
+/// Fills in each `<<ENV:KEY||path.to.key>>` whose var was unset with the expanded value at its
+/// fallback key path. Fallbacks within the value being copied are filled in first, so they can
+/// chain, and one that leads back to itself fails with `ErrorKind::ReferenceCycle`. A copied
+/// secret stays secret.
+fn resolve_config_fallbacks(ctx: &mut ExpandContext, value: &mut toml::Value) -> Result<(), Error> {
+    let fallbacks = std::mem::take(&mut ctx.fallbacks);
+    let mut resolved = BTreeSet::new();
+    for path in fallbacks.keys() {
+        resolve_config_fallback(&fallbacks, path, value, &mut ctx.secrets, &mut resolved, &mut Vec::new())?;
+    }
+    Ok(())
+}
+
+fn resolve_config_fallback(fallbacks: &BTreeMap<String, String>, path: &str, value: &mut toml::Value, secrets: &mut BTreeSet<String>,
+                           resolved: &mut BTreeSet<String>, stack: &mut Vec<String>) -> Result<(), Error> {
+    if resolved.contains(path) {
+        return Ok(());
+    }
+    if stack.iter().any(|p| p == path) {
+        return Err(ErrorKind::ReferenceCycle(path.to_owned()).into());
+    }
+
+    let target = &fallbacks[path];
+    let within_target = |p: &str| p.strip_prefix(target.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with(['.', '[']));
+    stack.push(path.to_owned());
+    for nested in fallbacks.keys().filter(|p| within_target(p)) {
+        resolve_config_fallback(fallbacks, nested, value, secrets, resolved, stack)?;
+    }
+    stack.pop();
+
+    let fallback = lookup(value, target).cloned()
+        .ok_or_else(|| Error::from(ErrorKind::InvalidReference(target.clone(), path.to_owned())))?;
+    *lookup_mut(value, path).expect("fallback placeholder in expanded document") = fallback;
+    let copied_secrets = secrets.iter().filter(|p| within_target(p)).map(|p| p.replacen(target.as_str(), path, 1)).collect::<Vec<_>>();
+    secrets.extend(copied_secrets);
+    resolved.insert(path.to_owned());
+    Ok(())
+}
+
+/// `path` is the dotted key path of the value being expanded (e.g. `database.hosts[0]`), used
+/// to point errors at the offending key.
+/// Placeholders in keys are expanded too, e.g. `["<<ENV:TENANT>>"]`, and it's an error for two
+/// keys in a table to resolve to the same name.
+/// Inline tables like `{ host = "<<ENV:HOST>>" }` parse as ordinary tables, so they're expanded
+/// the same way.
+fn load_env_variables(ctx: &mut ExpandContext, path: &str, config: toml::value::Table) -> Result<toml::Value, Error> {
+    let entries = collect_results(config.into_iter().map(|(k, v)| {
+        let k = load_env_key(ctx, path, k)?;
+        load_env_variable(ctx, &key_path(path, &k), v).map(|new_v| new_v.map(|new_v| (k, new_v)))
+    }))?;
+
+    let mut table = toml::value::Table::new();
+    let mut duplicates = Vec::new();
+    for (k, v) in Iterator::flatten(entries.into_iter()) {
+        if table.contains_key(&k) {
+            duplicates.push(Err::<(), _>(ErrorKind::DuplicateKey(key_path(path, &k)).into()));
+        } else {
+            table.insert(k, v);
+        }
+    }
+    match collect_results(duplicates) {
+        Err(e) if ctx.lenient => ctx.errors.push(e),
+        Err(e) => return Err(e),
+        Ok(_) => ()
+    }
+    Ok(toml::Value::Table(table))
+}
+
+/// Expands any inline placeholders in a table key. In lenient mode a key that fails to resolve
+/// is kept as written.
+#[cfg(feature = "substitution")]
+fn load_env_key(ctx: &mut ExpandContext, path: &str, key: String) -> Result<String, Error> {
+    if !ctx.options.syntax.env_inline.is_match(&key) {
+        return Ok(ctx.options.syntax.unescape(&key));
+    }
+
+    match expand_inline(ctx, &key_path(path, &key), &key, 0) {
+        Err(e) if ctx.lenient => {
+            ctx.errors.push(e);
+            ctx.unresolved.push((key_path(path, &key), key.clone()));
+            Ok(key)
+        },
+        result => result
+    }
+}
+
+#[cfg(not(feature = "substitution"))]
+fn load_env_key(_ctx: &mut ExpandContext, _path: &str, key: String) -> Result<String, Error> {
+    Ok(key)
+}
+
+pub fn key_path(parent: &str, key: &str) -> String {
+    if parent.is_empty() {
+        key.to_owned()
+    } else {
+        format!("{}.{}", parent, key)
+    }
+}
+
+fn load_env_array(ctx: &mut ExpandContext, path: &str, values: Vec<toml::Value>) -> Result<toml::Value, Error> {
+    let mut kept = 0;
+    collect_results(values.into_iter().enumerate().map(|(i, v)| {
+        let element_path = format!("{}[{}]", path, i);
+        let secrets_before = ctx.secrets.clone();
+        let fallbacks_before = ctx.fallbacks.clone();
+        let result = load_env_variable(ctx, &element_path, v);
+
+        // Secrets and fallbacks are tracked by their path in the expanded document, which shifts
+        // if optional elements before this one were dropped.
+        if let Ok(Some(_)) = result {
+            if kept != i {
+                let kept_path = format!("{}[{}]", path, kept);
+                let added = ctx.secrets.difference(&secrets_before).cloned().collect::<Vec<_>>();
+                for secret in added {
+                    ctx.secrets.remove(&secret);
+                    ctx.secrets.insert(secret.replacen(&element_path, &kept_path, 1));
+                }
+                let added = ctx.fallbacks.keys().filter(|p| !fallbacks_before.contains_key(*p)).cloned().collect::<Vec<_>>();
+                for fallback_path in added {
+                    let fallback = ctx.fallbacks.remove(&fallback_path).expect("added fallback");
+                    ctx.fallbacks.insert(fallback_path.replacen(&element_path, &kept_path, 1), fallback);
+                }
+            }
+            kept += 1;
+        }
+        result
+    })).map(|values| toml::Value::Array(Iterator::flatten(values.into_iter()).collect()))
+}
+
+fn load_env_variable(ctx: &mut ExpandContext, path: &str, value: toml::Value) -> Result<Option<toml::Value>, Error> {
+    match value {
+        toml::Value::String(ref s) if ctx.lenient =>
+            match load_env_string(ctx, path, s.clone(), 0) {
+                Ok(expanded) => Ok(expanded),
+                Err(e) => {
+                    ctx.errors.push(e);
+                    ctx.unresolved.push((path.to_owned(), s.clone()));
+                    Ok(Some(value))
+                }
+            },
+        toml::Value::String(s) =>
+            match (load_env_string(ctx, path, s, 0)?, ctx.nulls.as_mut()) {
+                (None, Some(nulls)) => {
+                    nulls.insert(path.to_owned());
+                    Ok(Some(toml::Value::String(String::new())))
+                },
+                (expanded, _) => Ok(expanded)
+            },
+        toml::Value::Table(table) if ctx.options.prune_empty_tables && !table.is_empty() =>
+            load_env_variables(ctx, path, table)
+                .map(|table| Some(table).filter(|table| table.as_table().is_some_and(|table| !table.is_empty()))),
+        toml::Value::Table(table) =>
+            load_env_variables(ctx, path, table).map(Some),
+        toml::Value::Array(values) =>
+            load_env_array(ctx, path, values).map(Some),
+        other_value =>
+            Ok(Some(other_value))
+    }
+}
+
+#[cfg(feature = "substitution")]
+fn load_env_string(ctx: &mut ExpandContext, path: &str, s: String, depth: usize) -> Result<Option<toml::Value>, Error> {
+    let syntax = &ctx.options.syntax;
+    if let Some(captures) = syntax.whole(&syntax.env_req, &s) {
+        let default = captures.get(2).map(|m| m.as_str());
+        return load_env_placeholder(ctx, path, &captures[1], default, false, depth);
+    }
+
+    if let Some(captures) = syntax.whole(&syntax.env_opt, &s) {
+        return load_env_placeholder(ctx, path, &captures[1], None, true, depth);
+    }
+
+    if let Some(captures) = syntax.whole(&syntax.env_ref, &s) {
+        if let Some(env_var) = find_env_var(ctx, path, &captures[1], true)? {
+            return expand_env_value(ctx, path, &captures[1], env_var, depth);
+        }
+        report_var(&mut ctx.report.skipped, path, &captures[1]);
+        ctx.fallbacks.insert(path.to_owned(), captures[2].to_owned());
+        return Ok(Some(toml::Value::String(s)));
+    }
+
+    if let Some(captures) = syntax.whole(&syntax.env_format, &s).filter(|_| ctx.options.printf_format) {
+        let optional = !captures[1].is_empty();
+        return load_formatted_placeholder(ctx, path, &captures[2], &captures[3], optional, depth);
+    }
+
+    if let Some(captures) = syntax.whole(&syntax.secret_env, &s) {
+        let optional = !captures[1].is_empty();
+        let default = captures.get(3).map(|m| m.as_str());
+        let value = load_env_placeholder(ctx, path, &captures[2], default, optional, depth)?;
+        if value.is_some() {
+            ctx.secrets.insert(path.to_owned());
+        }
+        return Ok(value);
+    }
+
+    if let Some(captures) = syntax.whole(&syntax.env_typed, &s) {
+        let optional = !captures[2].is_empty();
+        let default = captures.get(4).map(|m| m.as_str());
+        return load_typed_placeholder(ctx, path, &captures[1], &captures[3], default, optional, depth);
+    }
+
+    if ctx.options.env_only {
+        if let Some(name) = syntax.directive_name(&s) {
+            return Err(ErrorKind::DirectiveNotAllowed(name, path.to_owned()).into());
+        }
+    }
+
+    if let Some(captures) = syntax.whole(&syntax.file_req, &s) {
+        return read_file_value(ctx.options, &captures[1]).map(|contents| Some(toml::Value::String(contents)));
+    }
+
+    if let Some(captures) = syntax.whole(&syntax.file_opt, &s) {
+        return match read_file_value(ctx.options, &captures[1]) {
+            Ok(contents) =>
+                Ok(Some(toml::Value::String(contents))),
+            Err(Error(ErrorKind::Io(ref e), _)) if e.kind() == io::ErrorKind::NotFound =>
+                Ok(None),
+            Err(e) =>
+                Err(e)
+        };
+    }
+
+    #[cfg(feature = "keyring")]
+    if let Some(captures) = syntax.whole(&syntax.keyring, &s) {
+        let secret = with_retries(ctx.options, &s, || read_keyring(&captures[1], &captures[2]))?;
+        ctx.secrets.insert(path.to_owned());
+        return Ok(Some(toml::Value::String(secret)));
+    }
+
+    #[cfg(feature = "command-substitution")]
+    if let Some(captures) = syntax.whole(&syntax.cmd, &s) {
+        let output = with_retries(ctx.options, &s, || command::run(&captures[1], &ctx.options.command_limits))?;
+        let output = if ctx.options.trim == Some(false) { output } else { output.trim().to_owned() };
+        return Ok(Some(toml::Value::String(output)));
+    }
+
+    // Custom directives come before inline expansion, so env placeholders in their argument, as
+    // in `<<AWS_SSM:/<<ENV:STAGE>>/db>>`, are resolved and passed to the directive.
+    if let Some(captures) = syntax.whole(&syntax.custom, &s) {
+        let is_custom = syntax.directive_name(&s).is_some() && !BUILT_IN_DIRECTIVES.contains(&&captures[1]);
+        if is_custom {
+            let arg = expand_inline(ctx, path, &captures[2], depth)?;
+            let value = with_retries(ctx.options, &s, || directive::resolve(&captures[1], &arg, path))?;
+            ctx.secrets.insert(path.to_owned());
+            return Ok(Some(toml::Value::String(value)));
+        }
+    }
+
+    if ctx.options.syntax.env_inline.is_match(&s) {
+        return expand_inline(ctx, path, &s, depth).map(|expanded| Some(toml::Value::String(expanded)));
+    }
+
+    if ctx.options.syntax.looks_like_placeholder(&s) {
+        return Err(ErrorKind::InvalidPlaceholder(s, path.to_owned()).into());
+    }
+
+    Ok(Some(toml::Value::String(ctx.options.syntax.unescape(&s))))
+}
+
+/// Without the `substitution` feature strings are kept as written.
+#[cfg(not(feature = "substitution"))]
+fn load_env_string(_ctx: &mut ExpandContext, _path: &str, s: String, _depth: usize) -> Result<Option<toml::Value>, Error> {
+    Ok(Some(toml::Value::String(s)))
+}
+
+/// Resolves a whole-string env placeholder. Unset optional vars without a default yield `None`,
+/// dropping the key.
+#[cfg(feature = "substitution")]
+fn load_env_placeholder(ctx: &mut ExpandContext, path: &str, env_key: &str, default: Option<&str>, optional: bool, depth: usize) -> Result<Option<toml::Value>, Error> {
+    match resolve_env_var(ctx, path, env_key, default, optional)? {
+        Some(env_var) => expand_env_value(ctx, path, env_key, env_var, depth),
+        None => Ok(None)
+    }
+}
+
+/// Resolves a typed placeholder like `<<ENV_INT:PORT>>`, parsing the env value (after expanding
+/// any placeholders inside it) into the requested type regardless of coercion settings.
+/// `<<ENV_AUTO:...>>` coerces the value as `coerce` would, never failing, and `<<ENV_B64:...>>`
+/// decodes base64 into a string (with the `base64` feature).
+#[cfg(feature = "substitution")]
+fn load_typed_placeholder(ctx: &mut ExpandContext, path: &str, kind: &str, env_key: &str, default: Option<&str>, optional: bool, depth: usize) -> Result<Option<toml::Value>, Error> {
+    let mut env_var = match resolve_env_var(ctx, path, env_key, default, optional)? {
+        Some(env_var) => env_var,
+        None => return Ok(None)
+    };
+    if ctx.options.syntax.env_inline.is_match(&env_var) {
+        if depth >= MAX_EXPANSION_DEPTH {
+            return Err(ErrorKind::ExpansionCycle(env_key.to_owned()).into());
+        }
+        env_var = expand_inline(ctx, path, &env_var, depth + 1)?;
+    }
+
+    if kind == "AUTO" {
+        return Ok(Some(coerce_value(env_var)));
+    }
+    #[cfg(feature = "base64")]
+    {
+        if kind == "B64" {
+            return decode_base64(path, env_key, &env_var).map(|decoded| Some(toml::Value::String(decoded)));
+        }
+    }
+
+    let trimmed = env_var.trim();
+    let (value, expected) = match kind {
+        "INT" => (trimmed.parse().ok().map(toml::Value::Integer), "an integer"),
+        "BOOL" => (trimmed.parse().ok().map(toml::Value::Boolean), "a boolean"),
+        _ => (trimmed.parse().ok().map(toml::Value::Float), "a float")
+    };
+    match value {
+        Some(value) => Ok(Some(value)),
+        None => Err(ErrorKind::EnvVarParse(env_key.to_owned(), path.to_owned(), expected.to_owned(), env_var).into())
+    }
+}
+
+/// Resolves a placeholder like `<<ENV:PORT:%05d>>`, formatting the env value (after expanding
+/// any placeholders inside it) with the printf-style `spec`. The result is always a string, so
+/// padding survives `coerce`. An invalid spec is an error even if the var is unset.
+#[cfg(feature = "substitution")]
+fn load_formatted_placeholder(ctx: &mut ExpandContext, path: &str, env_key: &str, spec: &str, optional: bool, depth: usize) -> Result<Option<toml::Value>, Error> {
+    let format = FormatSpec::parse(spec)
+        .map_err(|reason| Error::from(ErrorKind::InvalidFormatSpec(spec.to_owned(), path.to_owned(), reason)))?;
+    let mut env_var = match resolve_env_var(ctx, path, env_key, None, optional)? {
+        Some(env_var) => env_var,
+        None => return Ok(None)
+    };
+    if ctx.options.syntax.env_inline.is_match(&env_var) {
+        if depth >= MAX_EXPANSION_DEPTH {
+            return Err(ErrorKind::ExpansionCycle(env_key.to_owned()).into());
+        }
+        env_var = expand_inline(ctx, path, &env_var, depth + 1)?;
+    }
+
+    match format.apply(&env_var) {
+        Some(formatted) => Ok(Some(toml::Value::String(formatted))),
+        None => Err(ErrorKind::EnvVarParse(env_key.to_owned(), path.to_owned(), format.expected().to_owned(), env_var).into())
+    }
+}
+
+/// Decodes a standard, padded base64 value into a UTF-8 string.
+#[cfg(feature = "base64")]
+fn decode_base64(path: &str, env_key: &str, env_var: &str) -> Result<String, Error> {
+    use base64::Engine;
+
+    let invalid = |reason: String| Error::from(ErrorKind::Base64Decode(env_key.to_owned(), path.to_owned(), reason));
+    let bytes = base64::engine::general_purpose::STANDARD.decode(env_var.trim())
+        .map_err(|e| invalid(e.to_string()))?;
+    String::from_utf8(bytes).map_err(|e| invalid(e.to_string()))
+}
+
+/// Reads the password stored in the platform keyring for `service` and `user`.
+#[cfg(feature = "keyring")]
+fn read_keyring(service: &str, user: &str) -> Result<String, Error> {
+    let failed = |e: keyring::Error| Error::from(ErrorKind::KeyringError(format!("{}/{}", service, user), e.to_string()));
+    keyring::Entry::new(service, user).and_then(|entry| entry.get_password()).map_err(failed)
+}
+
+/// Runs `resolve` for `placeholder`, retrying failures up to `options.directive_retries` times
+/// and doubling the wait between attempts, starting from `options.directive_backoff`. If every
+/// retry fails the last error is chained with `ErrorKind::DirectiveRetriesExhausted`.
+#[cfg(feature = "substitution")]
+fn with_retries<T, F: FnMut() -> Result<T, Error>>(options: &ExpandOptions, placeholder: &str, mut resolve: F) -> Result<T, Error> {
+    let mut backoff = options.directive_backoff;
+    let mut attempts = 1;
+    loop {
+        match resolve() {
+            Ok(value) => return Ok(value),
+            Err(e) if options.directive_retries == 0 => return Err(e),
+            Err(e) if attempts > options.directive_retries =>
+                return Err(e).chain_err(|| ErrorKind::DirectiveRetriesExhausted(placeholder.to_owned(), attempts)),
+            Err(_) => {
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempts += 1;
+            }
+        }
+    }
+}
+
+/// Looks up `env_key`, falling back to `default`. Unset optional vars without a default yield
+/// `None`. `env_key` may list several vars separated by `|`, in which case the first one set is
+/// used.
+#[cfg(feature = "substitution")]
+fn resolve_env_var(ctx: &mut ExpandContext, path: &str, env_key: &str, default: Option<&str>, optional: bool) -> Result<Option<String>, Error> {
+    let options = ctx.options;
+    let env_var = find_env_var(ctx, path, env_key, optional || default.is_some())?;
+    if options.reject_empty_required && !optional && default.is_none() && env_var.as_deref() == Some("") {
+        return Err(ErrorKind::EnvVarEmpty(env_key.to_owned(), path.to_owned()).into());
+    }
+
+    match (env_var, default) {
+        (Some(env_var), _) => Ok(Some(env_var)),
+        (None, Some(default)) => {
+            report_var(&mut ctx.report.skipped, path, env_key);
+            Ok(Some(default.to_owned()))
+        },
+        (None, None) if optional && !options.require_optional => {
+            report_var(&mut ctx.report.skipped, path, env_key);
+            Ok(None)
+        },
+        (None, None) => Err(ErrorKind::EnvVarMissing(env_key.to_owned(), path.to_owned()).into())
+    }
+}
+
+/// Looks up the first var set of the `|`-separated names in `env_key`, trimming it if requested
+/// and reporting it as used by `path`.
+#[cfg(feature = "substitution")]
+fn find_env_var(ctx: &mut ExpandContext, path: &str, env_key: &str, optional: bool) -> Result<Option<String>, Error> {
+    let report = if optional { &mut ctx.report.optional } else { &mut ctx.report.required };
+    for key in env_key.split('|') {
+        if let Some(env_var) = lookup_env(ctx.options, key)? {
+            report_var(report, path, key);
+            return Ok(Some(if ctx.options.trim == Some(true) { env_var.trim().to_owned() } else { env_var }));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(feature = "substitution")]
+fn report_var(vars: &mut BTreeMap<String, BTreeSet<String>>, path: &str, env_key: &str) {
+    vars.entry(path.to_owned()).or_default().insert(env_key.to_owned());
+}
+
+/// Expands any env placeholders inside the value of `env_key`, so one variable can refer to
+/// another. Only env placeholders are resolved: a value that is otherwise a directive, like
+/// `<<CMD:cat <<ENV:FILE>>>>`, has its env placeholders filled in and is kept as text, so env
+/// values can never run commands or read files.
+#[cfg(feature = "substitution")]
+fn expand_env_value(ctx: &mut ExpandContext, path: &str, env_key: &str, env_var: String, depth: usize) -> Result<Option<toml::Value>, Error> {
+    if !ctx.options.syntax.env_inline.is_match(&env_var) {
+        return Ok(Some(env_value(ctx.options, env_var)));
+    }
+    if depth >= MAX_EXPANSION_DEPTH {
+        return Err(ErrorKind::ExpansionCycle(env_key.to_owned()).into());
+    }
+
+    let syntax = &ctx.options.syntax;
+    let env_patterns = [&syntax.env_req, &syntax.env_opt, &syntax.env_ref, &syntax.env_format, &syntax.secret_env, &syntax.env_typed];
+    if env_patterns.iter().any(|pattern| syntax.whole(pattern, &env_var).is_some()) {
+        load_env_string(ctx, path, env_var, depth + 1)
+    } else {
+        expand_inline(ctx, path, &env_var, depth + 1).map(|expanded| Some(toml::Value::String(expanded)))
+    }
+}
+
+/// Replaces every placeholder embedded in `s`, leaving the surrounding text intact. Optional
+/// variables that aren't set expand to the empty string.
+#[cfg(feature = "substitution")]
+fn expand_inline(ctx: &mut ExpandContext, path: &str, s: &str, depth: usize) -> Result<String, Error> {
+    let mut last_end = 0;
+    let placeholders = ctx.options.syntax.inline_placeholders(s);
+    let pieces = collect_results(placeholders.into_iter().map(|placeholder| {
+        let preceding = ctx.options.syntax.unescape(&s[last_end..placeholder.start]);
+        last_end = placeholder.end;
+
+        let env_key = placeholder.key;
+        let mut value = resolve_env_var(ctx, path, env_key, placeholder.default, placeholder.optional)?.unwrap_or_default();
+        if ctx.options.syntax.env_inline.is_match(&value) {
+            if depth >= MAX_EXPANSION_DEPTH {
+                return Err(ErrorKind::ExpansionCycle(env_key.to_owned()).into());
+            }
+            value = expand_inline(ctx, path, &value, depth + 1)?;
+        }
+        Ok(format!("{}{}", preceding, value))
+    }))?;
+
+    Ok(pieces.concat() + &ctx.options.syntax.unescape(&s[last_end..]))
+}
+
+/// Converts a resolved env value into a TOML value, coercing it if requested.
+#[cfg(feature = "substitution")]
+fn env_value(options: &ExpandOptions, env_var: String) -> toml::Value {
+    if options.coerce {
+        coerce_value(env_var)
+    } else {
+        toml::Value::String(env_var)
+    }
+}
+
+/// Parses `s` as a boolean, integer, or float if it looks like one, otherwise leaves it a string.
+/// Floats must be written with digits, and may include a sign, `.`, or exponent; `inf` and `nan`
+/// stay strings.
+pub fn coerce_value(s: String) -> toml::Value {
+    if let Ok(b) = s.parse::<bool>() {
+        return toml::Value::Boolean(b);
+    }
+    if let Ok(i) = s.parse::<i64>() {
+        return toml::Value::Integer(i);
+    }
+
+    let looks_numeric = s.chars().any(|c| c.is_ascii_digit())
+        && s.chars().all(|c| c.is_ascii_digit() || "+-.eE".contains(c));
+    match s.parse::<f64>() {
+        Ok(f) if looks_numeric => toml::Value::Float(f),
+        _ => toml::Value::String(s)
+    }
+}
+
+/// Reads the file at `path`, trimming surrounding whitespace such as a trailing newline unless
+/// trimming is turned off.
+#[cfg(feature = "substitution")]
+fn read_file_value(options: &ExpandOptions, path: &str) -> Result<String, Error> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    if options.trim == Some(false) {
+        Ok(contents)
+    } else {
+        Ok(contents.trim().to_owned())
+    }
+}
+
+/// Looks up `env_key`, falling back to a case-insensitive match if enabled. If several names
+/// differ only in case, the first in sorted order is used, e.g. `Db_Url` over `db_url`.
+#[cfg(feature = "substitution")]
+fn lookup_env(options: &ExpandOptions, env_key: &str) -> Result<Option<String>, Error> {
+    let env_var = lookup_env_exact(options, env_key)?;
+    if env_var.is_some() || !options.case_insensitive_env {
+        return Ok(env_var);
+    }
+
+    let mut keys = match options.env {
+        Some(SharedEnvSource(ref env)) => env.keys(),
+        None => ProcessEnv.keys()
+    };
+    keys.sort();
+    match keys.into_iter().find(|key| key.eq_ignore_ascii_case(env_key)) {
+        Some(key) => lookup_env_exact(options, &key),
+        None => Ok(None)
+    }
+}
+
+#[cfg(feature = "substitution")]
+fn lookup_env_exact(options: &ExpandOptions, env_key: &str) -> Result<Option<String>, Error> {
+    if let Some(SharedEnvSource(ref env)) = options.env {
+        return Ok(env.get(env_key));
+    }
+
+    match env::var(env_key) {
+        Ok(env_var) => Ok(Some(env_var)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(e.into())
+    }
+}
+
+/// Collects every result, combining all errors rather than stopping at the first one.
+pub fn collect_results<T, I: IntoIterator<Item = Result<T, Error>>>(results: I) -> Result<Vec<T>, Error> {
+    let mut values = Vec::new();
+    let mut error = None;
+    for result in results {
+        match (result, error.take()) {
+            (Ok(value), None) => values.push(value),
+            (Ok(_), existing_err) => error = existing_err,
+            (Err(e), None) => error = Some(e),
+            (Err(e), Some(existing_err)) => error = Some(combine_errors(existing_err, e))
+        }
+    }
+    match error {
+        Some(e) => Err(e),
+        None => Ok(values)
+    }
+}
+
+
+#[cfg(all(test, feature = "substitution"))]
+mod tests {
+    use super::{expand_at, ExpandOptions};
+    use crate::env_source::SharedEnvSource;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    #[test]
+    fn it_expands_strings_starting_with_a_directive_inline() {
+        let vars = [("WHOLE_A", "aval"), ("WHOLE_B", "bval")].iter()
+            .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+            .collect::<HashMap<_, _>>();
+        let options = ExpandOptions { env: Some(SharedEnvSource(Arc::new(vars))), printf_format: true, ..ExpandOptions::default() };
+        let expand = |s: &str| expand_at(&options, "key", toml::Value::String(s.to_owned())).unwrap().unwrap();
+
+        assert_eq!(expand("<<ENV:WHOLE_A:-x>> and <<ENV:WHOLE_B>>").as_str(), Some("aval and bval"));
+        assert_eq!(expand("<<ENV?:WHOLE_A>> and <<ENV:WHOLE_B>>").as_str(), Some("aval and bval"));
+        assert_eq!(expand("<<ENV:WHOLE_A:-<<ENV:WHOLE_UNSET>>>>").as_str(), Some("aval"));
+        // Other directives only expand as the whole string, so they're kept as written.
+        for directive in &["<<SECRET_ENV:WHOLE_A:-x>>", "<<ENV_INT:WHOLE_A:-1>>", "<<ENV:WHOLE_A||other>>",
+                           "<<ENV:WHOLE_A:%05s>>", "<<FILE:/nonexistent>>", "<<FILE?:/nonexistent>>"] {
+            let s = format!("{}-<<ENV:WHOLE_B>>", directive);
+            assert_eq!(expand(&s).as_str(), Some(format!("{}-bval", directive).as_str()));
+        }
+    }
+
+    #[test]
+    fn it_only_expands_env_placeholders_in_env_values() {
+        let dir = std::env::temp_dir();
+        let file_path = dir.join("config_loader_env_value_file");
+        std::fs::write(&file_path, "file contents").unwrap();
+        let nested_file = format!("<<FILE:{}/config_loader_env_value_<<ENV:NESTED_WORD>>>>", dir.display());
+        let vars = [("NESTED_FILE", nested_file.as_str()), ("NESTED_CMD", "<<CMD:echo <<ENV:NESTED_WORD>>>>"), ("NESTED_WORD", "file")].iter()
+            .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+            .collect::<HashMap<_, _>>();
+        let options = ExpandOptions { env: Some(SharedEnvSource(Arc::new(vars))), ..ExpandOptions::default() };
+        let expand = |s: &str| expand_at(&options, "key", toml::Value::String(s.to_owned())).unwrap().unwrap();
+
+        assert_eq!(expand("<<ENV:NESTED_FILE>>").as_str(), Some(format!("<<FILE:{}>>", file_path.display()).as_str()));
+        assert_eq!(expand("<<ENV:NESTED_CMD>>").as_str(), Some("<<CMD:echo file>>"));
+
+        std::fs::remove_file(file_path).unwrap();
+    }
+}
@@ -0,0 +1,137 @@
+use std::fmt;
+use std::path::Path;
+use crate::{empty_table, merge, Error, ErrorKind};
+#[cfg(feature = "substitution")]
+use crate::{syntax::Syntax, unquoted};
+#[cfg(feature = "json")]
+use crate::json;
+#[cfg(feature = "yaml")]
+use crate::yaml;
+
+/// A supported config file format. Every variant is always available, but parsing JSON or YAML
+/// fails with `ErrorKind::FormatNotEnabled` unless the matching Cargo feature is on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// TOML, always available.
+    Toml,
+    /// JSON, requires the `json` feature.
+    Json,
+    /// YAML, requires the `yaml` feature.
+    Yaml
+}
+
+impl Format {
+    /// Picks a format from the file extension: `.toml`, `.json`, or `.yaml`/`.yml`. Paths without
+    /// an extension are treated as TOML.
+    pub fn from_path(path: &Path) -> Result<Format, Error> {
+        let ext = match path.extension() {
+            Some(ext) => ext.to_string_lossy(),
+            None => return Ok(Format::Toml)
+        };
+
+        match ext.as_ref() {
+            "toml" => Ok(Format::Toml),
+            "json" => Ok(Format::Json),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            _ => Err(ErrorKind::UnknownFormat(ext.into_owned()).into())
+        }
+    }
+
+    /// Parses `config_str` into the TOML value tree that env substitution walks. A leading UTF-8
+    /// byte order mark, as some Windows editors write, is ignored.
+    pub fn parse(self, config_str: &str) -> Result<toml::Value, Error> {
+        let config_str = config_str.strip_prefix('\u{feff}').unwrap_or(config_str);
+        match self {
+            Format::Toml =>
+                parse_toml(config_str),
+            #[cfg(feature = "json")]
+            Format::Json =>
+                Ok(json::to_toml(serde_json::from_str(config_str)?).unwrap_or_else(empty_table)),
+            #[cfg(feature = "yaml")]
+            Format::Yaml =>
+                Ok(yaml::to_toml(serde_yaml::from_str(config_str)?)?.unwrap_or_else(empty_table)),
+            #[allow(unreachable_patterns)]
+            disabled =>
+                Err(ErrorKind::FormatNotEnabled(disabled).into())
+        }
+    }
+}
+
+/// Parses `config_str` as several documents split on lines consisting of just `separator`, e.g.
+/// `---`, deep-merged in order so later documents win. Blank documents are skipped.
+pub fn parse_documents(format: Format, config_str: &str, separator: &str) -> Result<toml::Value, Error> {
+    let mut merged = empty_table();
+    let mut document = String::new();
+    for line in config_str.split_inclusive('\n') {
+        if line.trim_end_matches(['\r', '\n']) == separator {
+            if !document.trim().is_empty() {
+                merged = merge::merge(merged, format.parse(&document)?);
+            }
+            document.clear();
+        } else {
+            document.push_str(line);
+        }
+    }
+    if !document.trim().is_empty() {
+        merged = merge::merge(merged, format.parse(&document)?);
+    }
+    Ok(merged)
+}
+
+/// Parses TOML, retrying with unquoted placeholders like `debug = <<ENV:DEBUG>>` quoted if the
+/// document isn't valid as written.
+#[cfg(feature = "substitution")]
+fn parse_toml(config_str: &str) -> Result<toml::Value, Error> {
+    let err = match toml::from_str(config_str) {
+        Ok(value) => return Ok(value),
+        Err(err) => err
+    };
+    match unquoted::quote_placeholders(&Syntax::default(), config_str) {
+        Some(quoted) => Ok(toml::from_str(&quoted)?),
+        None => Err(err.into())
+    }
+}
+
+#[cfg(not(feature = "substitution"))]
+fn parse_toml(config_str: &str) -> Result<toml::Value, Error> {
+    Ok(toml::from_str(config_str)?)
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Format::Toml => write!(f, "toml"),
+            Format::Json => write!(f, "json"),
+            Format::Yaml => write!(f, "yaml")
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::Format;
+    use std::path::Path;
+
+    #[test]
+    fn it_detects_format_from_extension() {
+        assert_eq!(Format::from_path(Path::new("Config.toml")).unwrap(), Format::Toml);
+        assert_eq!(Format::from_path(Path::new("config")).unwrap(), Format::Toml);
+        assert_eq!(Format::from_path(Path::new("settings.json")).unwrap(), Format::Json);
+        assert_eq!(Format::from_path(Path::new("settings.yaml")).unwrap(), Format::Yaml);
+        assert_eq!(Format::from_path(Path::new("/etc/app/settings.yml")).unwrap(), Format::Yaml);
+        assert!(Format::from_path(Path::new("settings.ini")).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_parses_toml_with_a_bom_and_crlf_line_endings() {
+        let config_str = "\u{feff}name = \"app\"\r\nport = <<ENV_INT:BOM_PORT:-80>>\r\nnotes = \"\"\"\r\nline one\r\nline two\"\"\"\r\n[db]\r\nhost = \"db\"\r\n";
+        let value = Format::Toml.parse(config_str).unwrap();
+
+        assert_eq!(value["name"].as_str(), Some("app"));
+        assert_eq!(value["port"].as_str(), Some("<<ENV_INT:BOM_PORT:-80>>"));
+        assert_eq!(value["notes"].as_str(), Some("line one\r\nline two"));
+        assert_eq!(value["db"]["host"].as_str(), Some("db"));
+    }
+}
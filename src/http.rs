@@ -0,0 +1,114 @@
+use serde::de::DeserializeOwned;
+use std::path::Path;
+use std::time::Duration;
+use crate::{load_config_from_value_with, Error, ErrorKind, ExpandOptions, Format};
+
+/// How long `load_config_from_url` waits for the whole request, including reading the body.
+pub const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Fetches config from `url`, e.g. a central config service, and loads it like `load_config`.
+/// The format comes from the response's `Content-Type` if it names JSON, YAML, or TOML, and
+/// otherwise from the extension of the URL's path. Only env placeholders are expanded, against
+/// the local environment rather than the server's, so a server can't make this read local files
+/// or run commands: `<<FILE:...>>`, `<<CMD:...>>`, `<<KEYRING:...>>`, and custom directives fail
+/// with `ErrorKind::DirectiveNotAllowed`. `include` keys aren't resolved. Request failures, error
+/// statuses, and timeouts after `HTTP_TIMEOUT` fail with `ErrorKind::Http`.
+pub fn load_config_from_url<C: DeserializeOwned>(url: &str) -> Result<C, Error> {
+    load_config_from_url_with_timeout(url, HTTP_TIMEOUT)
+}
+
+/// Like `load_config_from_url`, but gives up on the request after `timeout`.
+pub fn load_config_from_url_with_timeout<C: DeserializeOwned>(url: &str, timeout: Duration) -> Result<C, Error> {
+    let failed = |reason: String| Error::from(ErrorKind::Http(url.to_owned(), reason));
+    let agent: ureq::Agent = ureq::Agent::config_builder().timeout_global(Some(timeout)).build().into();
+
+    let mut response = agent.get(url).call().map_err(|e| failed(e.to_string()))?;
+    let content_type = response.headers().get("content-type").and_then(|v| v.to_str().ok()).map(str::to_ascii_lowercase);
+    let config_str = response.body_mut().read_to_string().map_err(|e| failed(e.to_string()))?;
+
+    let format = match content_type {
+        Some(ref content_type) if content_type.contains("json") => Format::Json,
+        Some(ref content_type) if content_type.contains("yaml") => Format::Yaml,
+        Some(ref content_type) if content_type.contains("toml") => Format::Toml,
+        _ => url_format(url)?
+    };
+    load_config_from_value_with(&ExpandOptions { env_only: true, ..ExpandOptions::default() }, format.parse(&config_str)?)
+}
+
+/// The format for the extension of `url`'s path, ignoring any query or fragment.
+fn url_format(url: &str) -> Result<Format, Error> {
+    let url = url.split(['?', '#']).next().unwrap_or(url);
+    let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    match without_scheme.split_once('/') {
+        Some((_, path)) => Format::from_path(Path::new(path)),
+        None => Ok(Format::Toml)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{load_config_from_url, url_format};
+    use crate::{ErrorKind, Format};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[derive(Debug, Deserialize)]
+    struct Config {
+        name: String,
+        port: i64
+    }
+
+    /// Serves one request on a local port with `status` and `body`, returning the base URL.
+    fn serve_once(status: &'static str, body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).unwrap();
+            write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body).unwrap();
+        });
+        format!("http://{}", addr)
+    }
+
+    #[test]
+    fn it_loads_config_from_a_url() {
+        let url = serve_once("200 OK", "name = \"<<ENV:HTTP_CONFIG_NAME:-remote>>\"\nport = 8080\n");
+
+        let config: Config = load_config_from_url(&format!("{}/config.toml?v=2", url)).unwrap();
+        assert_eq!(&config.name, "remote");
+        assert_eq!(config.port, 8080);
+    }
+
+    #[cfg(feature = "substitution")]
+    #[test]
+    fn it_only_expands_env_placeholders_from_a_url() {
+        let url = serve_once("200 OK", "name = \"<<FILE:/etc/hostname>>\"\nport = 8080\n");
+        match *load_config_from_url::<Config>(&format!("{}/config.toml", url)).unwrap_err().kind() {
+            ErrorKind::DirectiveNotAllowed(ref name, ref path) => {
+                assert_eq!(name, "FILE");
+                assert_eq!(path, "name");
+            },
+            ref other => panic!("Expected directive not allowed, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn it_fails_with_http_error_for_error_statuses() {
+        let url = serve_once("404 Not Found", "");
+        match *load_config_from_url::<Config>(&url).unwrap_err().kind() {
+            ErrorKind::Http(ref failed_url, _) => assert_eq!(failed_url, &url),
+            ref other => panic!("Expected HTTP error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn it_picks_the_format_from_the_url_path() {
+        assert_eq!(url_format("https://example.com").unwrap(), Format::Toml);
+        assert_eq!(url_format("https://example.com/app/config.json?rev=1#top").unwrap(), Format::Json);
+        assert_eq!(url_format("https://example.com/config.yml").unwrap(), Format::Yaml);
+        assert!(url_format("https://example.com/config.ini").is_err());
+    }
+}
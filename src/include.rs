@@ -0,0 +1,246 @@
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+#[cfg(feature = "async")]
+use std::pin::Pin;
+use crate::format::parse_documents;
+use crate::{empty_table, merge, Error, ErrorKind, Format, ResultExt};
+
+/// Top-level key naming other config files to merge in, e.g. `include = ["db.toml", "log.toml"]`.
+pub const INCLUDE_KEY: &str = "include";
+
+/// Reads the config at `path` as `format`, resolving its includes. With a `max_file_size`, any
+/// file, including an included one, over that many bytes fails with `ErrorKind::FileTooLarge`.
+/// With a `document_separator`, each file is split into documents on it; see `parse_documents`.
+pub fn read_with_includes(path: &Path, format: Format, max_file_size: Option<u64>, document_separator: Option<&str>) -> Result<toml::Value, Error> {
+    read(path, format, &ReadSettings { max_file_size, document_separator }, &mut Vec::new())
+}
+
+/// Settings applied to every file read, including included ones.
+#[derive(Default)]
+struct ReadSettings<'s> {
+    max_file_size: Option<u64>,
+    document_separator: Option<&'s str>
+}
+
+/// Async version of `read_with_includes`, without a size limit or document separator. Every file,
+/// included ones too, is read with `tokio::fs`, so loading never blocks the executor.
+#[cfg(feature = "async")]
+pub async fn read_with_includes_async(path: &Path, format: Format) -> Result<toml::Value, Error> {
+    read_async(path, format, &mut Vec::new()).await
+}
+
+/// Resolves the includes of an already parsed config read from `path`. Included files are
+/// deep-merged in order, then `value` is merged over them, so the including file wins. Include
+/// paths are relative to the directory of the file that includes them.
+#[cfg(feature = "checksum")]
+pub fn resolve_includes(path: &Path, value: toml::Value) -> Result<toml::Value, Error> {
+    resolve(path, value, &ReadSettings::default(), &mut Vec::new())
+}
+
+fn read(path: &Path, format: Format, settings: &ReadSettings, stack: &mut Vec<PathBuf>) -> Result<toml::Value, Error> {
+    let parse = |s: String| match settings.document_separator {
+        Some(separator) => parse_documents(format, &s, separator),
+        None => format.parse(&s)
+    };
+    let value = match read_file(path, settings.max_file_size) {
+        Err(ref e) if is_not_found(e) => return Err(ErrorKind::ConfigNotFound(path.to_path_buf()).into()),
+        Err(e @ Error(ErrorKind::FileTooLarge(..), _)) => return Err(e),
+        result => result.and_then(parse).chain_err(|| ErrorKind::LoadFailed(path.to_path_buf()))?
+    };
+
+    resolve(path, value, settings, stack)
+}
+
+fn read_file(path: &Path, max_file_size: Option<u64>) -> Result<String, Error> {
+    let mut file = File::open(path)?;
+    let mut s = String::new();
+    match max_file_size {
+        // Read one byte past the limit rather than trusting the file's metadata, which may be
+        // wrong for pipes and special files.
+        Some(limit) => if file.take(limit + 1).read_to_string(&mut s)? as u64 > limit {
+            return Err(ErrorKind::FileTooLarge(path.to_path_buf(), limit).into());
+        },
+        None => { file.read_to_string(&mut s)?; }
+    }
+    Ok(s)
+}
+
+pub fn is_not_found(e: &Error) -> bool {
+    matches!(*e.kind(), ErrorKind::Io(ref e) if e.kind() == io::ErrorKind::NotFound)
+}
+
+
+/// `stack` holds the canonical paths of the files currently being included, to detect cycles.
+fn resolve(path: &Path, mut value: toml::Value, settings: &ReadSettings, stack: &mut Vec<PathBuf>) -> Result<toml::Value, Error> {
+    let includes = match take_includes(path, &mut value)? {
+        Some(includes) => includes,
+        None => return Ok(value)
+    };
+    push_include(path, path.canonicalize()?, stack)?;
+
+    let mut merged = empty_table();
+    for include_path in includes {
+        let format = Format::from_path(&include_path)?;
+        merged = merge::merge(merged, read(&include_path, format, settings, stack)?);
+    }
+
+    stack.pop();
+    Ok(merge::merge(merged, value))
+}
+
+/// Like `read` and `resolve` together, reading with `tokio::fs`. Boxed since it recurses.
+#[cfg(feature = "async")]
+fn read_async<'a>(path: &'a Path, format: Format, stack: &'a mut Vec<PathBuf>) -> Pin<Box<dyn Future<Output = Result<toml::Value, Error>> + Send + 'a>> {
+    Box::pin(async move {
+        let value = match tokio::fs::read_to_string(path).await {
+            Ok(s) => format.parse(&s),
+            Err(e) => Err(e.into())
+        };
+        let mut value = match value {
+            Err(ref e) if is_not_found(e) => return Err(ErrorKind::ConfigNotFound(path.to_path_buf()).into()),
+            value => value.chain_err(|| ErrorKind::LoadFailed(path.to_path_buf()))?
+        };
+
+        let includes = match take_includes(path, &mut value)? {
+            Some(includes) => includes,
+            None => return Ok(value)
+        };
+        push_include(path, tokio::fs::canonicalize(path).await?, stack)?;
+
+        let mut merged = empty_table();
+        for include_path in includes {
+            let format = Format::from_path(&include_path)?;
+            merged = merge::merge(merged, read_async(&include_path, format, stack).await?);
+        }
+
+        stack.pop();
+        Ok(merge::merge(merged, value))
+    })
+}
+
+/// Removes the `include` key from `value`, returning the paths it names, relative to the
+/// directory of `path`, or `None` if there isn't one.
+fn take_includes(path: &Path, value: &mut toml::Value) -> Result<Option<Vec<PathBuf>>, Error> {
+    let includes = match *value {
+        toml::Value::Table(ref mut table) => table.remove(INCLUDE_KEY),
+        _ => None
+    };
+    let includes = match includes {
+        None => return Ok(None),
+        Some(toml::Value::String(include)) => vec![include],
+        Some(toml::Value::Array(includes)) =>
+            includes.into_iter()
+                .map(|include| match include {
+                    toml::Value::String(include) => Ok(include),
+                    other => Err(invalid_include(path, &other))
+                })
+                .collect::<Result<Vec<_>, Error>>()?,
+        Some(other) => return Err(invalid_include(path, &other))
+    };
+
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    Ok(Some(includes.into_iter().map(|include| dir.join(include)).collect()))
+}
+
+/// Pushes the `canonical` path of `path` onto `stack`, failing if it's already being included.
+fn push_include(path: &Path, canonical: PathBuf, stack: &mut Vec<PathBuf>) -> Result<(), Error> {
+    if stack.contains(&canonical) {
+        return Err(ErrorKind::IncludeCycle(path.display().to_string()).into());
+    }
+    stack.push(canonical);
+    Ok(())
+}
+
+fn invalid_include(path: &Path, include: &toml::Value) -> Error {
+    ErrorKind::InvalidInclude(path.display().to_string(), include.to_string()).into()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::read_with_includes;
+    use crate::{ErrorKind, Format};
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn it_merges_included_files_relative_to_the_includer() {
+        let dir = env::temp_dir().join("config_loader_include_test");
+        fs::create_dir_all(dir.join("parts")).unwrap();
+        fs::write(dir.join("main.toml"), "include = [\"parts/a.toml\", \"parts/b.toml\"]\nname = \"main\"\n").unwrap();
+        fs::write(dir.join("parts/a.toml"), "include = \"c.toml\"\nname = \"a\"\n[db]\nhost = \"a\"\nport = 1\n").unwrap();
+        fs::write(dir.join("parts/b.toml"), "[db]\nhost = \"b\"\n").unwrap();
+        fs::write(dir.join("parts/c.toml"), "level = \"c\"\n").unwrap();
+
+        let value = read_with_includes(&dir.join("main.toml"), Format::Toml, None, None).unwrap();
+        assert_eq!(value["name"].as_str(), Some("main"));
+        assert_eq!(value["level"].as_str(), Some("c"));
+        assert_eq!(value["db"]["host"].as_str(), Some("b"));
+        assert_eq!(value["db"]["port"].as_integer(), Some(1));
+        assert!(value.get("include").is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_rejects_include_cycles() {
+        let dir = env::temp_dir().join("config_loader_include_cycle_test");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.toml"), "include = \"b.toml\"\n").unwrap();
+        fs::write(dir.join("b.toml"), "include = \"./a.toml\"\n").unwrap();
+
+        match read_with_includes(&dir.join("a.toml"), Format::Toml, None, None).unwrap_err().kind() {
+            ErrorKind::IncludeCycle(path) => assert!(path.ends_with("a.toml")),
+            e => panic!("unexpected error {:?}", e)
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_names_the_file_that_failed_to_load() {
+        let dir = env::temp_dir().join("config_loader_include_missing_test");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("main.toml"), "include = [\"missing.toml\"]\n").unwrap();
+
+        match *read_with_includes(&dir.join("main.toml"), Format::Toml, None, None).unwrap_err().kind() {
+            ErrorKind::ConfigNotFound(ref path) => assert_eq!(path, &dir.join("missing.toml")),
+            ref other => panic!("Expected config not found, got {:?}", other)
+        }
+
+        fs::write(dir.join("main.toml"), "include = [\"invalid.toml\"]\n").unwrap();
+        fs::write(dir.join("invalid.toml"), "name = ").unwrap();
+        let err = read_with_includes(&dir.join("main.toml"), Format::Toml, None, None).unwrap_err();
+        assert!(matches!(*err.kind(), ErrorKind::LoadFailed(ref path) if path == &dir.join("invalid.toml")));
+        assert_eq!(err.to_string(), format!("failed loading {}", dir.join("invalid.toml").display()));
+        // The underlying parse error is kept as the cause.
+        assert!(err.iter().nth(1).is_some());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn it_reads_included_files_async() {
+        let dir = env::temp_dir().join("config_loader_include_async_test");
+        fs::create_dir_all(dir.join("parts")).unwrap();
+        fs::write(dir.join("main.toml"), "include = \"parts/a.toml\"\nname = \"main\"\n").unwrap();
+        fs::write(dir.join("parts/a.toml"), "include = \"../main.toml\"\nlevel = \"a\"\n").unwrap();
+
+        match *super::read_with_includes_async(&dir.join("main.toml"), Format::Toml).await.unwrap_err().kind() {
+            ErrorKind::IncludeCycle(ref path) => assert!(path.ends_with("main.toml")),
+            ref other => panic!("Expected include cycle, got {:?}", other)
+        }
+
+        fs::write(dir.join("parts/a.toml"), "level = \"a\"\n").unwrap();
+        let value = super::read_with_includes_async(&dir.join("main.toml"), Format::Toml).await.unwrap();
+        assert_eq!(value["name"].as_str(), Some("main"));
+        assert_eq!(value["level"].as_str(), Some("a"));
+        assert!(value.get("include").is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
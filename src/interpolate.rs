@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use crate::expand::key_path;
+use crate::lazy::lookup;
+use crate::{Error, ErrorKind};
+
+/// Replaces `${path.to.key}` references in the strings of `value` with the values at those key
+/// paths, e.g. `api_url = "${base_url}/v2"`. Referenced strings are interpolated first, and a
+/// string that's only a reference takes on the referenced value whatever its type. References
+/// embedded in longer strings must point at strings, numbers, booleans, or datetimes. `$${`
+/// is kept as a literal `${`.
+pub fn interpolate(value: toml::Value) -> Result<toml::Value, Error> {
+    let mut resolver = Resolver { document: &value, resolved: HashMap::new(), stack: Vec::new() };
+    resolver.value("", &value)
+}
+
+struct Resolver<'d> {
+    document: &'d toml::Value,
+    /// Interpolated strings by key path, so each is only resolved once.
+    resolved: HashMap<String, toml::Value>,
+    /// Key paths of the strings currently being resolved, to detect cycles.
+    stack: Vec<String>
+}
+
+impl<'d> Resolver<'d> {
+    fn value(&mut self, path: &str, value: &toml::Value) -> Result<toml::Value, Error> {
+        match *value {
+            toml::Value::String(ref s) =>
+                self.string(path, s),
+            toml::Value::Table(ref table) =>
+                table.iter()
+                    .map(|(k, v)| Ok((k.clone(), self.value(&key_path(path, k), v)?)))
+                    .collect::<Result<_, Error>>()
+                    .map(toml::Value::Table),
+            toml::Value::Array(ref values) =>
+                values.iter().enumerate()
+                    .map(|(i, v)| self.value(&format!("{}[{}]", path, i), v))
+                    .collect::<Result<_, Error>>()
+                    .map(toml::Value::Array),
+            ref other =>
+                Ok(other.clone())
+        }
+    }
+
+    fn string(&mut self, path: &str, s: &str) -> Result<toml::Value, Error> {
+        if !s.contains("${") {
+            return Ok(toml::Value::String(s.to_owned()));
+        }
+        if let Some(resolved) = self.resolved.get(path) {
+            return Ok(resolved.clone());
+        }
+        if self.stack.iter().any(|p| p == path) {
+            return Err(ErrorKind::ReferenceCycle(path.to_owned()).into());
+        }
+
+        self.stack.push(path.to_owned());
+        let result = self.interpolate_string(path, s);
+        self.stack.pop();
+
+        let resolved = result?;
+        self.resolved.insert(path.to_owned(), resolved.clone());
+        Ok(resolved)
+    }
+
+    fn interpolate_string(&mut self, path: &str, s: &str) -> Result<toml::Value, Error> {
+        if let Some(reference) = s.strip_prefix("${").and_then(|r| r.strip_suffix('}')) {
+            if !reference.contains('}') {
+                return self.reference(path, reference);
+            }
+        }
+
+        let mut interpolated = String::new();
+        let mut rest = s;
+        while let Some(start) = rest.find("${") {
+            if rest[..start].ends_with('$') {
+                interpolated.push_str(&rest[..start - 1]);
+                interpolated.push_str("${");
+                rest = &rest[start + 2..];
+                continue;
+            }
+            interpolated.push_str(&rest[..start]);
+            let end = rest[start..].find('}')
+                .ok_or_else(|| Error::from(ErrorKind::InvalidReference(rest[start..].to_owned(), path.to_owned())))?;
+            let reference = &rest[start + 2..start + end];
+            match self.reference(path, reference)? {
+                toml::Value::String(s) => interpolated.push_str(&s),
+                toml::Value::Table(_) | toml::Value::Array(_) =>
+                    return Err(ErrorKind::InvalidReference(reference.to_owned(), path.to_owned()).into()),
+                other => interpolated.push_str(&other.to_string())
+            }
+            rest = &rest[start + end + 1..];
+        }
+        interpolated.push_str(rest);
+        Ok(toml::Value::String(interpolated))
+    }
+
+    fn reference(&mut self, path: &str, reference: &str) -> Result<toml::Value, Error> {
+        let document = self.document;
+        match lookup(document, reference) {
+            Some(target) if !reference.is_empty() => self.value(reference, target),
+            _ => Err(ErrorKind::InvalidReference(reference.to_owned(), path.to_owned()).into())
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::interpolate;
+    use crate::ErrorKind;
+
+    fn interpolated(s: &str) -> Result<toml::Value, crate::Error> {
+        interpolate(toml::from_str(s).unwrap())
+    }
+
+    #[test]
+    fn it_interpolates_references_to_other_keys() {
+        let value = interpolated(r#"
+            api_url = "${base_url}/v${api.version}"
+            base_url = "https://${host}"
+            host = "example.com"
+            literal = "$${base_url}"
+            [api]
+            version = 2
+            port = "${api.ports[1]}"
+            ports = [80, 443]
+        "#).unwrap();
+
+        assert_eq!(value["api_url"].as_str(), Some("https://example.com/v2"));
+        assert_eq!(value["literal"].as_str(), Some("${base_url}"));
+        assert_eq!(value["api"]["port"].as_integer(), Some(443));
+    }
+
+    #[test]
+    fn it_rejects_invalid_references() {
+        match *interpolated("a = \"${b}\"\nb = \"x${a}\"\n").unwrap_err().kind() {
+            ErrorKind::ReferenceCycle(ref path) => assert_eq!(path, "a"),
+            ref other => panic!("Expected reference cycle, got {:?}", other)
+        }
+        match *interpolated("a = \"x${missing}\"\n").unwrap_err().kind() {
+            ErrorKind::InvalidReference(ref reference, ref path) => {
+                assert_eq!(reference, "missing");
+                assert_eq!(path, "a");
+            },
+            ref other => panic!("Expected invalid reference, got {:?}", other)
+        }
+        assert!(interpolated("a = \"x${t}\"\n[t]\nb = 1\n").is_err());
+    }
+}
@@ -0,0 +1,82 @@
+
+/// Converts a parsed JSON document into the TOML value tree that env substitution walks.
+///
+/// TOML has no null, so `null`s are dropped from objects and arrays the same way an unset
+/// `<<ENV?:...>>` placeholder is. Numbers that don't fit in an `i64` become floats.
+pub fn to_toml(value: serde_json::Value) -> Option<toml::Value> {
+    match value {
+        serde_json::Value::Null =>
+            None,
+        serde_json::Value::Bool(b) =>
+            Some(toml::Value::Boolean(b)),
+        serde_json::Value::Number(n) =>
+            Some(match n.as_i64() {
+                Some(i) => toml::Value::Integer(i),
+                None => toml::Value::Float(n.as_f64().unwrap_or(f64::NAN))
+            }),
+        serde_json::Value::String(s) =>
+            Some(toml::Value::String(s)),
+        serde_json::Value::Array(values) =>
+            Some(toml::Value::Array(values.into_iter().filter_map(to_toml).collect())),
+        serde_json::Value::Object(map) =>
+            Some(toml::Value::Table(map.into_iter()
+                .filter_map(|(k, v)| to_toml(v).map(|v| (k, v)))
+                .collect()))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::super::load_config_from_json_str;
+
+    #[derive(Debug, Deserialize)]
+    struct Server {
+        host: String,
+        port: u16
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Config {
+        name: String,
+        debug: bool,
+        ratio: f64,
+        missing: Option<String>,
+        servers: Vec<Server>
+    }
+
+    #[test]
+    fn it_loads_json_with_env_vars() {
+        let config_str = r#"{
+            "name": "<<ENV:JSON_NAME:-json name>>",
+            "debug": true,
+            "ratio": 0.5,
+            "missing": null,
+            "servers": [
+                { "host": "<<ENV:JSON_HOST:-json host>>", "port": 8080 },
+                { "host": "literal", "port": 8081 }
+            ]
+        }"#;
+
+        let config: Config = load_config_from_json_str(config_str).unwrap();
+        assert_eq!(&config.name, "json name");
+        assert!(config.debug);
+        assert_eq!(config.ratio, 0.5);
+        assert_eq!(&config.missing, &None);
+        assert_eq!(&config.servers[0].host, "json host");
+        assert_eq!(config.servers[0].port, 8080);
+        assert_eq!(&config.servers[1].host, "literal");
+    }
+
+    #[test]
+    fn it_fails_when_required_env_var_missing_in_json() {
+        let config_str = r#"{
+            "name": "<<ENV:JSON_MISSING>>",
+            "debug": true,
+            "ratio": 0.5,
+            "servers": []
+        }"#;
+
+        assert!(load_config_from_json_str::<Config>(config_str).is_err())
+    }
+}
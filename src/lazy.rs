@@ -0,0 +1,119 @@
+use serde::de::DeserializeOwned;
+use std::path::Path;
+use crate::expand::{self, ExpandOptions};
+use crate::{read_config_value, resolve_config_path, Error, ErrorKind, Format, DEFAULT_CONFIG_NAME};
+
+/// A config whose placeholders are only resolved when a value is read, so a missing env var
+/// fails just the reads that need it rather than the whole load. Each read resolves its
+/// placeholders again, seeing the environment as it is at that moment.
+#[derive(Debug, Clone)]
+pub struct LazyConfig {
+    value: toml::Value,
+    options: ExpandOptions
+}
+
+impl LazyConfig {
+    /// Reads the config the same way as `load_config`, including includes, without resolving any
+    /// placeholders.
+    pub fn load<P: AsRef<Path>>(config_path: Option<P>) -> Result<LazyConfig, Error> {
+        let config_path = resolve_config_path(config_path, DEFAULT_CONFIG_NAME)?;
+        Ok(LazyConfig { value: read_config_value(&config_path, None, None)?, options: ExpandOptions::default() })
+    }
+
+    /// Parses a TOML config without resolving any placeholders.
+    pub fn parse(config_str: &str) -> Result<LazyConfig, Error> {
+        Ok(LazyConfig { value: Format::Toml.parse(config_str)?, options: ExpandOptions::default() })
+    }
+
+    /// Resolves the value at `path`, e.g. `database.url` or `servers[0].host`, and deserializes
+    /// it. Fails with `ErrorKind::MissingKey` if there's no such key, or it's an unset optional
+    /// placeholder. Keys are matched as written, before any placeholders in them are expanded.
+    pub fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, Error> {
+        self.get_opt(path)?.ok_or_else(|| ErrorKind::MissingKey(path.to_owned()).into())
+    }
+
+    /// Like `get`, but returns `None` for a missing key or unset optional placeholder.
+    pub fn get_opt<T: DeserializeOwned>(&self, path: &str) -> Result<Option<T>, Error> {
+        let value = match lookup(&self.value, path) {
+            Some(value) => value.clone(),
+            None => return Ok(None)
+        };
+        match expand::expand_at(&self.options, path, value)? {
+            Some(expanded) => Ok(Some(expanded.try_into()?)),
+            None => Ok(None)
+        }
+    }
+}
+
+/// The value at a dotted key path with `[i]` array indexes; an empty path is the whole document.
+pub fn lookup<'v>(value: &'v toml::Value, path: &str) -> Option<&'v toml::Value> {
+    if path.is_empty() {
+        return Some(value);
+    }
+    path.split('.').try_fold(value, |value, segment| {
+        let (key, indexes) = segment.split_at(segment.find('[').unwrap_or(segment.len()));
+        let mut value = if key.is_empty() { value } else { value.get(key)? };
+        for index in indexes.split('[').skip(1) {
+            value = value.get(index.strip_suffix(']')?.parse::<usize>().ok()?)?;
+        }
+        Some(value)
+    })
+}
+
+/// Like `lookup`, but returns the value mutably.
+pub fn lookup_mut<'v>(value: &'v mut toml::Value, path: &str) -> Option<&'v mut toml::Value> {
+    if path.is_empty() {
+        return Some(value);
+    }
+    path.split('.').try_fold(value, |value, segment| {
+        let (key, indexes) = segment.split_at(segment.find('[').unwrap_or(segment.len()));
+        let mut value = if key.is_empty() { value } else { value.get_mut(key)? };
+        for index in indexes.split('[').skip(1) {
+            value = value.get_mut(index.strip_suffix(']')?.parse::<usize>().ok()?)?;
+        }
+        Some(value)
+    })
+}
+
+
+#[cfg(all(test, feature = "substitution"))]
+mod tests {
+    use super::LazyConfig;
+    use crate::env_source::SharedEnvSource;
+    use crate::ErrorKind;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    #[test]
+    fn it_resolves_placeholders_on_access() {
+        let mut config = LazyConfig::parse(r#"
+            name = "<<ENV:LAZY_NAME>>"
+            unused = "<<ENV:LAZY_UNSET>>"
+            optional = "<<ENV?:LAZY_UNSET>>"
+            [[servers]]
+            port = "<<ENV_INT:LAZY_PORT>>"
+        "#).unwrap();
+
+        let vars = vec![("LAZY_NAME", "lazy"), ("LAZY_PORT", "8080")].into_iter()
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect::<HashMap<_, _>>();
+        config.options.env = Some(SharedEnvSource(Arc::new(vars)));
+
+        assert_eq!(config.get::<String>("name").unwrap(), "lazy");
+        assert_eq!(config.get::<i64>("servers[0].port").unwrap(), 8080);
+        assert_eq!(config.get_opt::<String>("optional").unwrap(), None);
+        assert_eq!(config.get_opt::<String>("servers[1].port").unwrap(), None);
+
+        match *config.get::<String>("unused").unwrap_err().kind() {
+            ErrorKind::EnvVarMissing(ref key, ref path) => {
+                assert_eq!(key, "LAZY_UNSET");
+                assert_eq!(path, "unused");
+            },
+            ref other => panic!("Expected missing env var, got {:?}", other)
+        }
+        match *config.get::<String>("missing").unwrap_err().kind() {
+            ErrorKind::MissingKey(ref path) => assert_eq!(path, "missing"),
+            ref other => panic!("Expected missing key, got {:?}", other)
+        }
+    }
+}
@@ -1,141 +1,540 @@
 #![recursion_limit = "1024"]
 
 #[macro_use] extern crate error_chain;
-extern crate itertools;
+#[cfg(feature = "substitution")]
 #[macro_use] extern crate lazy_static;
+#[cfg(feature = "substitution")]
 extern crate regex;
 extern crate serde;
+extern crate serde_ignored;
+#[cfg(feature = "json")]
+extern crate serde_json;
+#[cfg(feature = "yaml")]
+extern crate serde_yaml;
+#[cfg(feature = "base64")]
+extern crate base64;
+#[cfg(feature = "keyring")]
+extern crate keyring;
+#[cfg(feature = "watch")]
+extern crate notify;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "checksum")]
+extern crate sha2;
+#[cfg(feature = "preserve-comments")]
+extern crate toml_edit;
+#[cfg(feature = "http")]
+extern crate ureq;
+#[cfg(feature = "prompt")]
+extern crate rpassword;
 extern crate toml;
 
 #[cfg(test)]
 #[macro_use]
 extern crate serde_derive;
 
+#[cfg(feature = "command-substitution")]
+mod command;
+mod configured;
+mod diff;
+#[cfg(feature = "substitution")]
+mod directive;
+mod dotenv;
+mod env_source;
+mod expand;
+mod format;
+#[cfg(feature = "http")]
+mod http;
+mod include;
+mod interpolate;
+mod lazy;
+mod loader;
+mod merge;
+mod nullable;
+#[cfg(feature = "prompt")]
+mod prompt;
+#[cfg(feature = "preserve-comments")]
+mod preserve;
+#[cfg(feature = "substitution")]
+mod printf;
+#[cfg(feature = "substitution")]
+mod requirements;
+mod sources;
+mod strict;
+#[cfg(feature = "substitution")]
+mod syntax;
+#[cfg(feature = "substitution")]
+mod unquoted;
+mod warning;
+#[cfg(feature = "json")]
+mod json;
+#[cfg(feature = "yaml")]
+mod yaml;
+#[cfg(feature = "watch")]
+mod watch;
 
-use std::fs::File;
+use std::collections::HashMap;
+use std::sync::Arc;
 use std::env;
-use std::io::Read;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read};
 use serde::de::DeserializeOwned;
 use std::path::{Path, PathBuf};
-use regex::Regex;
-use std::collections::BTreeMap;
-use itertools::Itertools;
+pub use configured::Configured;
+pub use diff::{config_diff, ConfigChange};
+#[cfg(feature = "substitution")]
+pub use directive::{register_directive, DirectiveResolver};
+pub use env_source::{EnvSource, ProcessEnv};
+pub use format::Format;
+#[cfg(feature = "http")]
+pub use http::{load_config_from_url, load_config_from_url_with_timeout, HTTP_TIMEOUT};
+#[cfg(feature = "command-substitution")]
+pub use command::CommandLimits;
+pub use merge::MergeStrategy;
+#[cfg(feature = "prompt")]
+pub use prompt::load_config_interactive;
+#[cfg(feature = "preserve-comments")]
+pub use preserve::resolve_to_string_preserving;
+pub use lazy::LazyConfig;
+pub use loader::ConfigLoader;
+#[cfg(feature = "substitution")]
+pub use requirements::{required_env_vars, EnvRequirements};
+pub use sources::{load_sources, ProvenanceMap, Source};
+use env_source::SharedEnvSource;
+use expand::{collect_results, ExpandOptions};
+use nullable::NullableValue;
+pub use expand::{ExpandedConfig, LoadReport};
+pub use warning::{load_config_collecting, Warning};
+#[cfg(feature = "watch")]
+pub use watch::{watch_config, ConfigWatcher, ReloadableConfig};
 
-lazy_static! {
-    static ref ENV_FLAG_REQ: Regex = Regex::new("^<<ENV:([a-zA-Z0-9_]*)>>$").unwrap();
-    static ref ENV_FLAG_OPT: Regex = Regex::new("^<<ENV\\?:([a-zA-Z0-9_]*)>>$").unwrap();
-}
 
+/// Environment variable consulted for the config path when none is passed to `load_config`.
+pub const CONFIG_PATH_ENV: &str = "CONFIG_PATH";
+
+/// File name looked for when neither a config path nor `CONFIG_PATH` is given.
+pub const DEFAULT_CONFIG_NAME: &str = "Config.toml";
+
+/// Environment variable naming the deployment environment, e.g. `production`. When it's set, the
+/// default lookup tries an environment-specific file like `Config.production.toml` first.
+pub const APP_ENV_VAR: &str = "APP_ENV";
+
+/// How the environment-specific default file is named: `{stem}` and `{ext}` are the default
+/// name's stem and extension (including the dot), and `{env}` is the value of `APP_ENV`.
+pub const DEFAULT_ENV_FILE_PATTERN: &str = "{stem}.{env}{ext}";
 
+/// Loads the config at `config_path`. If no path is given, the path in the `CONFIG_PATH`
+/// environment variable is used, falling back to a `Config.toml` found in the current directory
+/// or one of its parents, or next to the executable. If `APP_ENV` is set, e.g. to `production`,
+/// a `Config.production.toml` is looked for the same way before `Config.toml`. The format is
+/// chosen from the file extension; see `Format::from_path`.
+///
+/// A top-level `include` key (a path or array of paths, relative to the including file) names
+/// other config files to deep-merge underneath this one before placeholders are expanded. The
+/// `include` key itself is removed, and include cycles are rejected.
 pub fn load_config<C: DeserializeOwned, P: AsRef<Path>>(config_path: Option<P>) -> Result<C, Error> {
-    let mut config_file = open_config_file(config_path)?;
+    load_config_with_default_name(config_path, DEFAULT_CONFIG_NAME)
+}
+
+/// Like `load_config`, but if there's no config file (the given path doesn't exist, or no path
+/// was given and the default lookup finds nothing) loads `fallback_str` as TOML instead, e.g. a
+/// default config compiled in with `include_str!`. Placeholders in the fallback are expanded as
+/// usual.
+pub fn load_config_or<C: DeserializeOwned, P: AsRef<Path>>(config_path: Option<P>, fallback_str: &str) -> Result<C, Error> {
+    match resolve_config_path(config_path, DEFAULT_CONFIG_NAME) {
+        Ok(ref path) if path.exists() => load_config(Some(path)),
+        _ => load_config_from_str(fallback_str)
+    }
+}
+
+/// Loads config from the path given on the command line as `--config <path>`,
+/// `--config=<path>`, or `-c <path>`, falling back to the same lookup as `load_config(None)` when
+/// none of those are present. Other arguments are ignored, and if a flag is repeated the last
+/// one wins.
+pub fn load_config_from_args<C: DeserializeOwned>() -> Result<C, Error> {
+    load_config(config_path_from_args(env::args_os().skip(1))?)
+}
+
+/// Like `load_config`, but first loads the dotenv file at `dotenv_path` into the process
+/// environment so placeholders can resolve from it. Variables already set in the environment
+/// take precedence over the file. The file has `KEY=VALUE` lines, optionally prefixed with
+/// `export`, with `#` comments and single or double quoted values.
+pub fn load_config_with_dotenv<C: DeserializeOwned, P: AsRef<Path>, D: AsRef<Path>>(config_path: Option<P>, dotenv_path: D) -> Result<C, Error> {
+    dotenv::load_dotenv(dotenv_path.as_ref())?;
+    load_config(config_path)
+}
+
+/// Like `load_config`, but falls back to `default_name` instead of `Config.toml`.
+pub fn load_config_with_default_name<C: DeserializeOwned, P: AsRef<Path>>(config_path: Option<P>, default_name: &str) -> Result<C, Error> {
+    let config_path = resolve_config_path(config_path, default_name)?;
+    let format = Format::from_path(&config_path)?;
+
+    load_config_with_format(Some(config_path), format)
+}
+
+/// Async version of `load_config` for use inside a Tokio runtime. This only offloads reading the
+/// file and any files it includes, via `tokio::fs`; resolving the path, env var lookups, and
+/// parsing still run synchronously since they're cheap. `<<FILE:...>>` placeholders are still
+/// read with blocking IO.
+#[cfg(feature = "async")]
+pub async fn load_config_async<C: DeserializeOwned, P: AsRef<Path>>(config_path: Option<P>) -> Result<C, Error> {
+    let config_path = resolve_config_path(config_path, DEFAULT_CONFIG_NAME)?;
+    let format = Format::from_path(&config_path)?;
+
+    load_config_from_value(include::read_with_includes_async(&config_path, format).await?)
+}
+
+/// Like `load_config`, but first checks the SHA-256 of the file's raw contents against
+/// `expected_sha256` (hex, either case), failing with `ErrorKind::ChecksumMismatch` if it differs,
+/// e.g. to detect drift from a pinned known-good config. Only this file is hashed, not its
+/// includes or the env vars its placeholders read.
+#[cfg(feature = "checksum")]
+pub fn load_config_verified<C: DeserializeOwned, P: AsRef<Path>>(config_path: P, expected_sha256: &str) -> Result<C, Error> {
+    use sha2::{Digest, Sha256};
+
+    let config_path = config_path.as_ref();
+    let format = Format::from_path(config_path)?;
+    let config_str = match fs::read_to_string(config_path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Err(ErrorKind::ConfigNotFound(config_path.to_owned()).into()),
+        config_str => config_str.chain_err(|| ErrorKind::LoadFailed(config_path.to_owned()))?
+    };
+
+    let actual_sha256 = Sha256::digest(config_str.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    if !actual_sha256.eq_ignore_ascii_case(expected_sha256.trim()) {
+        return Err(ErrorKind::ChecksumMismatch(config_path.to_owned(), expected_sha256.to_owned(), actual_sha256).into());
+    }
+
+    let value = format.parse(&config_str).chain_err(|| ErrorKind::LoadFailed(config_path.to_owned()))?;
+    load_config_from_value(include::resolve_includes(config_path, value)?)
+}
+
+/// Like `load_config`, but parses the file as `format` regardless of its extension.
+pub fn load_config_with_format<C: DeserializeOwned, P: AsRef<Path>>(config_path: Option<P>, format: Format) -> Result<C, Error> {
+    let config_path = resolve_config_path(config_path, DEFAULT_CONFIG_NAME)?;
+
+    load_config_from_value(include::read_with_includes(&config_path, format, None, None)?)
+}
+
+/// Loads several config files, deep-merging each one over the ones before it so later files
+/// win. Tables are merged recursively, while scalars and arrays from a later file replace the
+/// earlier value outright (arrays are not concatenated). Each file's format is chosen from its
+/// extension, and placeholders are expanded once, on the merged document.
+pub fn load_configs<C: DeserializeOwned, P: AsRef<Path>>(config_paths: &[P]) -> Result<C, Error> {
+    load_configs_with_strategy(config_paths, &MergeStrategy::Replace)
+}
+
+/// Like `load_configs`, but combines arrays present in several files according to `strategy`,
+/// e.g. merging `[[servers]]` entries by name instead of replacing the whole array.
+pub fn load_configs_with_strategy<C: DeserializeOwned, P: AsRef<Path>>(config_paths: &[P], strategy: &MergeStrategy) -> Result<C, Error> {
+    let mut merged = empty_table();
+    for path in config_paths {
+        merged = merge::merge_with(strategy, merged, read_config_value(path.as_ref(), None, None)?);
+    }
+
+    load_config_from_value(merged)
+}
+
+/// Loads `base`, then deep-merges `overlay` over it (e.g. `Config.toml` followed by
+/// `Config.production.toml`). Passing `None` skips the overlay, but an overlay path that doesn't
+/// exist is an error. Merging follows the same rules as `load_configs`.
+pub fn load_layered_config<C: DeserializeOwned, P: AsRef<Path>>(base: P, overlay: Option<P>) -> Result<C, Error> {
+    let mut config_paths = vec![base];
+    config_paths.extend(overlay);
+
+    load_configs(&config_paths)
+}
+
+/// Loads every `*.toml` file directly inside `dir`, deep-merged in sorted filename order as by
+/// `load_configs`, e.g. fragments dropped into `/etc/app/conf.d/`. Other files and
+/// subdirectories are skipped.
+pub fn load_config_dir<C: DeserializeOwned, P: AsRef<Path>>(dir: P) -> Result<C, Error> {
+    load_configs(&config_dir_paths(dir.as_ref())?)
+}
+
+/// The `*.toml` files directly inside `dir`, sorted by file name byte by byte, so the order
+/// doesn't depend on the platform or locale.
+fn config_dir_paths(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let entries = fs::read_dir(dir).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => ErrorKind::ConfigNotFound(dir.to_owned()).into(),
+        _ => Error::from(e)
+    })?;
+
+    let mut config_paths = entries.map(|entry| entry.map(|entry| entry.path())).collect::<Result<Vec<_>, _>>()?;
+    config_paths.retain(|path| path.is_file() && path.extension() == Some("toml".as_ref()));
+    config_paths.sort();
+    Ok(config_paths)
+}
+
+pub fn load_config_from_str<C: DeserializeOwned>(config_str: &str) -> Result<C, Error> {
+    load_config_from_value(Format::Toml.parse(config_str)?)
+}
+
+/// Like `load_config_from_str`, then runs `validate` on the loaded config to enforce invariants
+/// the types can't, e.g. that at least one server is configured. A message returned by
+/// `validate` fails the load with `ErrorKind::Validation`.
+pub fn load_config_validated<C: DeserializeOwned, F: Fn(&C) -> Result<(), String>>(config_str: &str, validate: F) -> Result<C, Error> {
+    let config = load_config_from_str(config_str)?;
+    validate(&config).map_err(ErrorKind::Validation)?;
+    Ok(config)
+}
+
+/// Like `load_config_from_str`, but resolves env placeholders against `env` rather than the
+/// process environment, e.g. to keep tests deterministic and safe to run in parallel.
+pub fn load_config_from_str_with_env<C: DeserializeOwned>(config_str: &str, env: &HashMap<String, String>) -> Result<C, Error> {
+    let options = ExpandOptions { env: Some(SharedEnvSource(Arc::new(env.clone()))), ..ExpandOptions::default() };
+    load_config_from_value_with(&options, Format::Toml.parse(config_str)?)
+}
+
+/// Loads config from flat `KEY=VALUE` lines, like a systemd `EnvironmentFile` or `.env` file,
+/// with the same quoting and comment rules as `load_config_with_dotenv`. Placeholders in keys and
+/// values are expanded, and values that look like booleans or numbers (whether written directly
+/// or resolved from a placeholder) deserialize as those types. Keys are kept as written, so
+/// uppercase keys need e.g. `#[serde(rename_all = "SCREAMING_SNAKE_CASE")]` on the config type.
+pub fn load_config_from_env_format<C: DeserializeOwned>(config_str: &str) -> Result<C, Error> {
+    let options = ExpandOptions { coerce: true, ..ExpandOptions::default() };
+    let table = dotenv::parse(config_str)?.into_iter()
+        .map(|(key, value)| {
+            #[cfg(feature = "substitution")]
+            let has_placeholder = options.syntax.env_inline.is_match(&value);
+            #[cfg(not(feature = "substitution"))]
+            let has_placeholder = false;
+            let value = if has_placeholder {
+                toml::Value::String(value)
+            } else {
+                expand::coerce_value(value)
+            };
+            (key, value)
+        })
+        .collect();
+
+    load_config_from_value_with(&options, toml::Value::Table(table))
+}
+
+/// Reads TOML config from `reader`, e.g. stdin or an in-memory buffer.
+pub fn load_config_from_reader<C: DeserializeOwned, R: Read>(mut reader: R) -> Result<C, Error> {
     let mut s = String::new();
-    config_file.read_to_string(&mut s)?;
+    reader.read_to_string(&mut s)?;
 
     load_config_from_str(&s)
 }
 
-pub fn load_config_from_str<C: DeserializeOwned>(config_str: &str) -> Result<C, Error> {
-    let loaded_config = load_env_variables(toml::from_str(config_str)?)?;
+/// Like `load_config_from_str`, but env values that look like booleans, integers, or floats are
+/// inserted as those types, so `bar = "<<ENV:BAR>>"` can fill an `i32` field. Note that a coerced
+/// value no longer deserializes into a `String` field.
+pub fn load_config_from_str_coerced<C: DeserializeOwned>(config_str: &str) -> Result<C, Error> {
+    let options = ExpandOptions { coerce: true, ..ExpandOptions::default() };
+    load_config_from_value_with(&options, Format::Toml.parse(config_str)?)
+}
+
+/// Expands placeholders in an already-parsed document and deserializes it directly, without
+/// round-tripping through a string.
+pub fn load_config_from_value<C: DeserializeOwned>(value: toml::Value) -> Result<C, Error> {
+    load_config_from_value_with(&ExpandOptions::default(), value)
+}
 
-    // is there a better way to do this than shortcutting through string?
-    let loaded_config_str = toml::to_string(&loaded_config)?;
-    let config = toml::from_str(&loaded_config_str)?;
+/// Expands every placeholder in an already-parsed document and returns the result, without
+/// deserializing it, e.g. to inspect or transform the value before deserializing it yourself.
+/// Unset optional placeholders are dropped from their table or array, and all resolution errors
+/// are combined as with `load_config_from_value`.
+pub fn expand_env(value: toml::Value) -> Result<toml::Value, Error> {
+    Ok(expand::expand(&ExpandOptions::default(), value)?.into_value())
+}
 
-    Ok(config)
+fn load_config_from_value_with<C: DeserializeOwned>(options: &ExpandOptions, value: toml::Value) -> Result<C, Error> {
+    Ok(expand::expand(options, value)?.into_value().try_into()?)
 }
 
-fn open_config_file<T: AsRef<Path>>(path: Option<T>) -> Result<File, Error> {
-    match path {
-        Some(path) => File::open(path),
-        None => {
-            let default_path = get_default_config_path()
-                .ok_or(String::from("Default config file not found"))?;
-            File::open(default_path)
-        }
-    }.map_err(|e| e.into())
+/// Like `load_config`, but also reports which env vars supplied values and which optional ones
+/// were unset, e.g. for a dashboard showing where each setting came from.
+pub fn load_config_with_report<C: DeserializeOwned, P: AsRef<Path>>(config_path: Option<P>) -> Result<(C, LoadReport), Error> {
+    let config_path = resolve_config_path(config_path, DEFAULT_CONFIG_NAME)?;
+    let expanded = expand::expand(&ExpandOptions::default(), read_config_value(&config_path, None, None)?)?;
+    let report = expanded.report().clone();
+
+    Ok((expanded.into_value().try_into()?, report))
 }
 
+/// Like `load_config_from_str`, but also returns the fully expanded document, e.g. for logging
+/// the effective config after every placeholder has been resolved. Use
+/// `ExpandedConfig::redacted_value` to hide values that came from `<<SECRET_ENV:...>>`
+/// placeholders.
+pub fn load_config_debug<C: DeserializeOwned>(config_str: &str) -> Result<(C, ExpandedConfig), Error> {
+    let expanded = expand::expand(&ExpandOptions::default(), Format::Toml.parse(config_str)?)?;
+    let config = expanded.value().clone().try_into()?;
 
-fn get_default_config_path() -> Option<PathBuf> {
-    let mut path = env::current_dir()
-        .expect("Error finding executable directory");
-    path.push("Config.toml");
-    if path.exists() {
-        Some(path)
-    } else {
-        None
-    }
+    Ok((config, expanded))
 }
 
-fn load_env_variables(config: toml::value::Table) -> Result<toml::Value, Error> {
-    config.into_iter().fold(Ok(BTreeMap::new()), |mut result, (k, v)| {
-        match load_env_variable(v) {
-            Ok(Some(new_v)) => {
-                if let Ok(ref mut m) = result.as_mut() {
-                    m.insert(k, new_v);
-                }
-                result
-            },
-            Ok(None) =>
-                result,
-            Err(e) => {
-                match result {
-                    Ok(_) => Err(e),
-                    Err(existing_err) => Err(combine_errors(existing_err, e))
-                }
+/// Expands every placeholder in a TOML config and serializes the result back to TOML, e.g. to
+/// snapshot the effective config or generate a derived file. Comments and formatting are lost;
+/// see `resolve_to_string_preserving` with the `preserve-comments` feature to keep them.
+///
+/// This is the only place config is serialized, and so the only source of
+/// `ErrorKind::Serialization`: loading deserializes the expanded `toml::Value` directly.
+pub fn resolve_to_string(config_str: &str) -> Result<String, Error> {
+    let expanded = expand::expand(&ExpandOptions::default(), Format::Toml.parse(config_str)?)?;
+    to_toml_string(expanded.value())
+}
+
+/// Serializes `value`, chaining the `toml::ser::Error` of a failure with
+/// `ErrorKind::Serialization` for the innermost value that can't be serialized.
+fn to_toml_string(value: &toml::Value) -> Result<String, Error> {
+    toml::to_string(value).chain_err(|| ErrorKind::Serialization(unserializable_path("", value)))
+}
+
+/// The key path of the innermost value inside `value`, found at `path`, that fails to serialize
+/// on its own, or `path` if none of its children do.
+fn unserializable_path(path: &str, value: &toml::Value) -> String {
+    let children = match *value {
+        toml::Value::Table(ref table) => table.iter().map(|(k, v)| (expand::key_path(path, k), v)).collect(),
+        toml::Value::Array(ref values) => values.iter().enumerate().map(|(i, v)| (format!("{}[{}]", path, i), v)).collect(),
+        _ => Vec::new()
+    };
+    children.into_iter()
+        .find(|&(_, v)| toml::to_string(&toml::Value::Table(std::iter::once(("value".to_owned(), v.clone())).collect())).is_err())
+        .map(|(child_path, v)| unserializable_path(&child_path, v))
+        .unwrap_or_else(|| path.to_owned())
+}
+
+/// Runs a full load of `config_str` into `C`, including env substitution and deserialization,
+/// then discards the result. Every unresolved placeholder is reported together with any
+/// deserialization error as `ErrorKind::Multiple`. A field holding a placeholder that failed to
+/// resolve reports only that failure, not also a type error.
+pub fn validate_config<C: DeserializeOwned>(config_str: &str) -> Result<(), Error> {
+    let (expanded, errors, unresolved) = expand::expand_lenient(&ExpandOptions::default(), Format::Toml.parse(config_str)?);
+    let deserialized = match C::deserialize(NullableValue::unresolved(expanded, &unresolved)) {
+        Err(ref e) if e.message() == nullable::UNRESOLVED => Ok(()),
+        result => result.map(|_| ()).map_err(Error::from)
+    };
+
+    collect_results(errors.into_iter().map(Err).chain(Some(deserialized))).map(|_| ())
+}
+
+/// Lists the key path and literal text of every placeholder in a TOML config that can't be
+/// resolved in the current environment, without failing on them, e.g. to prompt for the missing
+/// values. A string holding several placeholders is listed once, as a whole. Only parse errors
+/// fail.
+pub fn unresolved_placeholders(config_str: &str) -> Result<Vec<(String, String)>, Error> {
+    Ok(expand::find_unresolved(&ExpandOptions::default(), Format::Toml.parse(config_str)?))
+}
+
+#[cfg(feature = "json")]
+pub fn load_config_from_json_str<C: DeserializeOwned>(config_str: &str) -> Result<C, Error> {
+    load_config_from_value(Format::Json.parse(config_str)?)
+}
+
+#[cfg(feature = "yaml")]
+pub fn load_config_from_yaml_str<C: DeserializeOwned>(config_str: &str) -> Result<C, Error> {
+    load_config_from_value(Format::Yaml.parse(config_str)?)
+}
+
+fn empty_table() -> toml::Value {
+    toml::Value::Table(toml::value::Table::new())
+}
+
+fn read_config_value(path: &Path, max_file_size: Option<u64>, document_separator: Option<&str>) -> Result<toml::Value, Error> {
+    include::read_with_includes(path, Format::from_path(path)?, max_file_size, document_separator)
+}
+
+fn config_path_from_args<I: IntoIterator<Item = OsString>>(args: I) -> Result<Option<PathBuf>, Error> {
+    let mut config_path = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--config" || arg == "-c" {
+            match args.next() {
+                Some(path) => config_path = Some(PathBuf::from(path)),
+                None => return Err(ErrorKind::MissingArgValue(arg.to_string_lossy().into_owned()).into())
             }
+        } else if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            config_path = Some(PathBuf::from(path));
         }
-    }).map(|table| toml::Value::Table(table))
+    }
+    Ok(config_path)
 }
 
-fn load_env_variable(value: toml::Value) -> Result<Option<toml::Value>, Error> {
-    match value {
-        toml::Value::String(ref s) if ENV_FLAG_REQ.is_match(s) => {
-            let env_key = s
-                .trim_left_matches("<<ENV:")
-                .trim_right_matches(">>");
+fn resolve_config_path<T: AsRef<Path>>(path: Option<T>, default_name: &str) -> Result<PathBuf, Error> {
+    resolve_config_path_for_env(path, default_name, APP_ENV_VAR, DEFAULT_ENV_FILE_PATTERN, &ProcessEnv)
+}
 
-            match env::var(env_key) {
-                Ok(env_var) =>
-                    Ok(Some(toml::Value::String(env_var))),
-                Err(env::VarError::NotPresent) =>
-                    Err(ErrorKind::EnvVarMissing(env_key.to_owned()).into()),
-                Err(e) =>
-                    Err(e.into())
-            }
-        },
-        toml::Value::String(ref s) if ENV_FLAG_OPT.is_match(&s) => {
-            let env_key = s
-                .trim_left_matches("<<ENV?:")
-                .trim_right_matches(">>");
-            match env::var(env_key) {
-                Ok(env_var) =>
-                    Ok(Some(toml::Value::String(env_var))),
-                Err(env::VarError::NotPresent) =>
-                    Ok(None),
-                Err(e) =>
-                    Err(e.into())
+/// Like `resolve_config_path`, but reads `CONFIG_PATH` and the environment name (from
+/// `app_env_var`) from `env`, and names the environment-specific file with `env_file_pattern`.
+fn resolve_config_path_for_env<T: AsRef<Path>>(path: Option<T>, default_name: &str, app_env_var: &str, env_file_pattern: &str, env: &dyn EnvSource) -> Result<PathBuf, Error> {
+    match path {
+        Some(path) => Ok(path.as_ref().to_path_buf()),
+        None => match env.get(CONFIG_PATH_ENV) {
+            Some(ref env_path) if !env_path.is_empty() => Ok(PathBuf::from(env_path)),
+            _ => {
+                let app_env = env.get(app_env_var).filter(|app_env| !app_env.is_empty());
+                get_env_config_path(default_name, app_env.as_deref(), env_file_pattern)
             }
-        },
-        toml::Value::Table(table) =>
-            load_env_variables(table).map(|vs| Some(vs)),
-        other_value =>
-            Ok(Some(other_value))
+        }
+    }
+}
+
+/// Looks for the environment-specific default file for `app_env`, then for `default_name`.
+fn get_env_config_path(default_name: &str, app_env: Option<&str>, env_file_pattern: &str) -> Result<PathBuf, Error> {
+    match app_env {
+        Some(app_env) => get_default_config_path(&env_file_name(default_name, app_env, env_file_pattern))
+            .or_else(|_| get_default_config_path(default_name)),
+        None => get_default_config_path(default_name)
     }
 }
 
-fn combine_errors(e1: Error, e2: Error) -> Error {
+/// Fills in `env_file_pattern` for `default_name`, e.g. `Config.production.toml` for
+/// `Config.toml` in `production`.
+fn env_file_name(default_name: &str, app_env: &str, env_file_pattern: &str) -> String {
+    let path = Path::new(default_name);
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    let ext = path.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+    let name = env_file_pattern.replace("{stem}", &stem).replace("{env}", app_env).replace("{ext}", &ext);
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+
+/// How many directories, starting with the current one, are searched for the default config.
+const MAX_SEARCH_DEPTH: usize = 32;
+
+/// Looks for `default_name` in the current directory and each of its parents, like Cargo does for
+/// `Cargo.toml`, then in the executable's directory. Fails with `DefaultConfigNotFound`, caused
+/// by the underlying IO error, if the current directory can't be read, e.g. it was deleted.
+fn get_default_config_path(default_name: &str) -> Result<PathBuf, Error> {
+    let current_dir = env::current_dir()
+        .chain_err(|| ErrorKind::DefaultConfigNotFound(default_name.to_owned()))?;
+
+    find_upward(&current_dir, default_name)
+        .or_else(|| {
+            env::current_exe().ok()
+                .and_then(|exe| exe.parent().map(|dir| dir.join(default_name)))
+                .filter(|path| path.exists())
+        })
+        .ok_or_else(|| ErrorKind::DefaultConfigNotFound(default_name.to_owned()).into())
+}
+
+fn find_upward(start: &Path, name: &str) -> Option<PathBuf> {
+    start.ancestors()
+        .take(MAX_SEARCH_DEPTH)
+        .map(|dir| dir.join(name))
+        .find(|path| path.exists())
+}
+
+/// Combines two errors into one `ErrorKind::Multiple`, flattening either if it's already
+/// `Multiple` and keeping `e1`'s errors first. Useful for reporting your own post-load validation
+/// failures alongside loading errors.
+pub fn combine_errors(e1: Error, e2: Error) -> Error {
     match (e1, e2) {
         (Error(ErrorKind::Multiple(mut es1), _), Error(ErrorKind::Multiple(es2), _)) => {
             es1.extend(es2);
             ErrorKind::Multiple(es1).into()
         },
-        (Error(ErrorKind::Multiple(mut es), _), other) |
-        (other, Error(ErrorKind::Multiple(mut es), _)) => {
+        (Error(ErrorKind::Multiple(mut es), _), other) => {
             es.push(other);
             ErrorKind::Multiple(es).into()
         },
+        (other, Error(ErrorKind::Multiple(mut es), _)) => {
+            es.insert(0, other);
+            ErrorKind::Multiple(es).into()
+        },
         (e1, e2) => {
             ErrorKind::Multiple(vec![e1, e2]).into()
         }
@@ -151,17 +550,224 @@ error_chain! {
         Io(::std::io::Error);
         Env(env::VarError);
         Deserialization(toml::de::Error);
-        Serialization(toml::ser::Error);
+        Json(serde_json::Error) #[cfg(feature = "json")];
+        Yaml(serde_yaml::Error) #[cfg(feature = "yaml")];
+        Watch(notify::Error) #[cfg(feature = "watch")];
+        TomlEdit(toml_edit::TomlError) #[cfg(feature = "preserve-comments")];
     }
 
     errors {
-        EnvVarMissing(key: String) {
+        EnvVarMissing(key: String, path: String) {
             description("Required environment variable missing")
-            display("Required environment variable '{}' not set", key)
+            display("Required environment variable '{}' not set (referenced by '{}')", key, path)
+        }
+        EnvVarEmpty(key: String, path: String) {
+            description("Required environment variable empty")
+            display("Environment variable '{}' (referenced by '{}') is set but empty", key, path)
+        }
+        ConfigNotFound(path: PathBuf) {
+            description("Config file not found")
+            display("Config file {} not found", path.display())
+        }
+        FileTooLarge(path: PathBuf, limit: u64) {
+            description("Config file too large")
+            display("Config file {} is larger than the limit of {} bytes", path.display(), limit)
+        }
+        LoadFailed(path: PathBuf) {
+            description("Failed loading config file")
+            display("failed loading {}", path.display())
+        }
+        WatchPathInvalid(path: PathBuf) {
+            description("Invalid path to watch")
+            display("Cannot watch {}; expected a file", path.display())
+        }
+        DefaultConfigNotFound(name: String) {
+            description("Default config file not found")
+            display("Default config file {} not found", name)
+        }
+        DotenvParse(line: usize, reason: String) {
+            description("Invalid dotenv file")
+            display("Invalid dotenv file at line {}: {}", line, reason)
+        }
+        UnknownFormat(ext: String) {
+            description("Unknown config format")
+            display("Unsupported config file extension '{}'", ext)
+        }
+        FormatNotEnabled(format: Format) {
+            description("Config format not enabled")
+            display("Loading {} config requires the '{}' feature", format, format)
+        }
+        EnvVarParse(key: String, path: String, expected: String, value: String) {
+            description("Environment variable has the wrong type")
+            display("Environment variable '{}' (referenced by '{}') must be {}, got '{}'", key, path, expected, value)
+        }
+        InvalidPlaceholder(placeholder: String, path: String) {
+            description("Malformed placeholder")
+            display("'{}' (at '{}') looks like a placeholder but isn't a valid directive; escape it to keep it literal", placeholder, path)
+        }
+        InvalidFormatSpec(spec: String, path: String, reason: String) {
+            description("Invalid format spec")
+            display("Invalid format '{}' (at '{}'): {}", spec, path, reason)
+        }
+        Base64Decode(key: String, path: String, reason: String) {
+            description("Invalid base64 env value")
+            display("Environment variable '{}' (referenced by '{}') is not valid base64 UTF-8: {}", key, path, reason)
+        }
+        CommandFailed(command: String, reason: String) {
+            description("Command substitution failed")
+            display("Command '{}' failed: {}", command, reason)
+        }
+        KeyringError(entry: String, reason: String) {
+            description("Keyring lookup failed")
+            display("Keyring entry '{}' could not be read: {}", entry, reason)
+        }
+        DirectiveRetriesExhausted(placeholder: String, attempts: u32) {
+            description("Directive failed after retrying")
+            display("'{}' failed after {} attempts", placeholder, attempts)
+        }
+        UnregisteredDirective(name: String, path: String) {
+            description("Custom directive not registered")
+            display("No directive named '{}' is registered (at '{}')", name, path)
+        }
+        DirectiveNotAllowed(name: String, path: String) {
+            description("Directive not allowed")
+            display("The {} directive (at '{}') isn't allowed here; only env placeholders are", name, path)
+        }
+        InconsistentEnvUsage(key: String, required_path: String, optional_path: String) {
+            description("Env var used as both required and optional")
+            display("Environment variable '{}' is required by '{}' but optional for '{}'", key, required_path, optional_path)
+        }
+        Http(url: String, reason: String) {
+            description("Fetching config over HTTP failed")
+            display("Fetching config from '{}' failed: {}", url, reason)
+        }
+        ChecksumMismatch(path: PathBuf, expected: String, actual: String) {
+            description("Config checksum mismatch")
+            display("Config file {} has SHA-256 {}, expected {}", path.display(), actual, expected)
+        }
+        MissingKey(path: String) {
+            description("Config key not found")
+            display("Config key '{}' not found", path)
+        }
+        Serialization(path: String) {
+            description("Config serialization failed")
+            display("Couldn't serialize '{}' of the expanded config to TOML", path)
+        }
+        Validation(reason: String) {
+            description("Invalid config")
+            display("Invalid config: {}", reason)
+        }
+        InvalidReference(reference: String, path: String) {
+            description("Invalid config reference")
+            display("'${{{}}}' (at '{}') doesn't refer to a config value that can be interpolated", reference, path)
+        }
+        ReferenceCycle(path: String) {
+            description("Config reference cycle")
+            display("Config value '{}' refers back to itself", path)
+        }
+        ExpansionCycle(key: String) {
+            description("Env var expansion cycle")
+            display("Expanding env var '{}' exceeded the maximum depth of {}; check for a cycle", key, expand::MAX_EXPANSION_DEPTH)
+        }
+        InvalidDelimiter(reason: String) {
+            description("Invalid placeholder delimiter")
+            display("Invalid placeholder delimiters: {}", reason)
+        }
+        DuplicateKey(path: String) {
+            description("Duplicate config key")
+            display("Config key '{}' appears more than once after expanding placeholders", path)
+        }
+        MissingArgValue(flag: String) {
+            description("Command line flag missing its value")
+            display("Expected a config path after '{}'", flag)
+        }
+        IncludeCycle(path: String) {
+            description("Config include cycle")
+            display("Config file '{}' includes itself", path)
+        }
+        InvalidInclude(path: String, include: String) {
+            description("Invalid config include")
+            display("Config file '{}' has an invalid include {}; expected a path or array of paths", path, include)
+        }
+        UnknownKey(path: String) {
+            description("Unknown config key")
+            display("Unknown config key '{}'", path)
+        }
+        InvalidKey(key: String) {
+            description("Invalid config key")
+            display("Config key {} cannot be converted to a string", key)
         }
         Multiple(errs: Vec<Error>) {
             description("Multiple errors")
-            display("Errors: {}", errs.iter().join(", "))
+            display("Errors: {}", errs.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "))
+        }
+    }
+}
+
+/// Errors are matchable through `kind()`. Variants are only ever added, never changed or removed
+/// outside a major release, but `ErrorKind` is non-exhaustive, so matches need a wildcard arm.
+impl Error {
+    /// An `ErrorKind::EnvVarMissing` for `key`, referenced at key path `path`, e.g. for reporting
+    /// your own env requirements the same way as the loader.
+    pub fn missing_env(key: &str, path: &str) -> Error {
+        ErrorKind::EnvVarMissing(key.to_owned(), path.to_owned()).into()
+    }
+
+    /// Combines `errors` into an `ErrorKind::Multiple`, flattening any that are `Multiple`
+    /// themselves. A single error, including a `Multiple` holding just one, is returned as is.
+    /// `errors` must not be empty; in release builds an empty `Multiple` is returned for it.
+    pub fn multiple(errors: Vec<Error>) -> Error {
+        debug_assert!(!errors.is_empty(), "Error::multiple called without any errors");
+        let mut errors = errors.into_iter();
+        let first = errors.next().unwrap_or_else(|| ErrorKind::Multiple(Vec::new()).into());
+        match errors.fold(first, combine_errors) {
+            Error(ErrorKind::Multiple(mut errs), _) if errs.len() == 1 => errs.remove(0),
+            combined => combined
+        }
+    }
+
+    /// The individual errors if this is an `ErrorKind::Multiple`, e.g. to classify each failure
+    /// separately. `Multiple` errors are always flat, so none of these are `Multiple` themselves.
+    pub fn iter_multiple(&self) -> Option<&[Error]> {
+        match *self.kind() {
+            ErrorKind::Multiple(ref errs) => Some(errs),
+            _ => None
+        }
+    }
+
+    /// The names of every missing env var this error reports, including within `Multiple`.
+    pub fn missing_env_vars(&self) -> Vec<&str> {
+        self.iter_multiple().unwrap_or(std::slice::from_ref(self)).iter()
+            .filter_map(|e| match *e.kind() {
+                ErrorKind::EnvVarMissing(ref key, _) => Some(key.as_str()),
+                _ => None
+            })
+            .collect()
+    }
+
+    /// Whether the config file couldn't be found, as opposed to found but invalid.
+    pub fn is_not_found(&self) -> bool {
+        matches!(*self.kind(), ErrorKind::ConfigNotFound(_) | ErrorKind::DefaultConfigNotFound(_))
+    }
+
+    /// Like the `Display` output, but lists at most `limit` errors of a `Multiple`, followed by
+    /// how many more there were, e.g. `Errors: a, b, and 37 more`. Other errors display as usual.
+    pub fn display_compact(&self, limit: usize) -> String {
+        let errs = match self.iter_multiple() {
+            Some(errs) if errs.len() > limit => errs,
+            _ => return self.to_string()
+        };
+        let mut shown = errs[..limit].iter().map(ToString::to_string).collect::<Vec<_>>();
+        shown.push(format!("and {} more", errs.len() - limit));
+        format!("Errors: {}", shown.join(", "))
+    }
+
+    /// Like the `Display` output, but with each error of a `Multiple` on its own line, for
+    /// readability when many env vars are missing. Other errors display as usual.
+    pub fn display_verbose(&self) -> String {
+        match self.iter_multiple() {
+            Some(errs) => errs.iter().fold(format!("{} errors:", errs.len()), |s, e| s + "\n  - " + &e.to_string()),
+            None => self.to_string()
         }
     }
 }
@@ -169,8 +775,19 @@ error_chain! {
 
 #[cfg(test)]
 mod tests {
-    use super::load_config_from_str;
+    use super::{combine_errors, Error, load_config_validated, load_config_dir, load_config, load_config_from_env_format, load_config_or, load_config_from_str_with_env, load_config_from_reader, load_config_from_str,
+                load_config_from_value, load_config_with_format, load_configs, load_layered_config,
+                config_path_from_args, find_upward, get_default_config_path, get_env_config_path, env_file_name, resolve_config_path_for_env, ErrorKind, Format, APP_ENV_VAR, CONFIG_PATH_ENV, DEFAULT_ENV_FILE_PATTERN};
+    #[cfg(feature = "substitution")]
+    use super::{unresolved_placeholders, load_config_with_report, LoadReport, expand_env, resolve_to_string, load_config_with_dotenv, load_config_from_str_coerced, load_config_debug, validate_config,
+                expand, ExpandOptions, SharedEnvSource};
+    #[cfg(feature = "substitution")]
+    use std::collections::BTreeMap;
+    #[cfg(feature = "substitution")]
+    use std::sync::Arc;
+    use std::collections::HashMap;
     use std::env;
+    use std::fs;
 
     #[derive(Debug, Deserialize)]
     struct SubConfig {
@@ -186,6 +803,11 @@ mod tests {
         more: SubConfig
     }
 
+    /// An env for `load_config_from_str_with_env`, so tests don't race on the process environment.
+    fn env_vars(vars: &[(&str, &str)]) -> HashMap<String, String> {
+        vars.iter().map(|&(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
 
     #[test]
     fn it_works_when_all_defined() {
@@ -225,6 +847,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "substitution")]
     fn it_works_when_env_var_required() {
         let config_str = r#"
             foo = "<<ENV:FOO1>>"
@@ -235,9 +858,9 @@ mod tests {
             thing2 = "thing2 value"
         "#;
 
-        env::set_var("FOO1", "env foo value");
+        let env = env_vars(&[("FOO1", "env foo value")]);
 
-        let config: Config = load_config_from_str(config_str).unwrap();
+        let config: Config = load_config_from_str_with_env(config_str, &env).unwrap();
         assert_eq!(&config.foo, "env foo value");
         assert_eq!(config.bar, 1234);
         assert_eq!(&config.baz, &Some("baz value".to_string()));
@@ -246,6 +869,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "substitution")]
     fn it_fails_when_required_env_var_missing() {
         let config_str = r#"
             foo = "<<ENV:FOO2>>"
@@ -259,8 +883,8 @@ mod tests {
         assert!(load_config_from_str::<Config>(config_str).is_err())
     }
 
-    //Tests combined here to avoid race condition between accessing env
     #[test]
+    #[cfg(feature = "substitution")]
     fn it_works_when_env_var_optional() {
         let config_str = r#"
             foo = "foo value"
@@ -271,22 +895,1412 @@ mod tests {
             thing2 = "thing2 value"
         "#;
 
-        env::set_var("BAZ", "env baz value");
-
-        let config1: Config = load_config_from_str(config_str).unwrap();
+        let config1: Config = load_config_from_str_with_env(config_str, &env_vars(&[("BAZ", "env baz value")])).unwrap();
         assert_eq!(&config1.foo, "foo value");
         assert_eq!(config1.bar, 1234);
         assert_eq!(&config1.baz, &Some("env baz value".to_string()));
         assert_eq!(&config1.more.thing1, "thing1 value");
         assert_eq!(&config1.more.thing2, "thing2 value");
 
-        env::remove_var("BAZ");
-
-        let config2: Config = load_config_from_str(config_str).unwrap();
+        let config2: Config = load_config_from_str_with_env(config_str, &env_vars(&[])).unwrap();
         assert_eq!(&config2.foo, "foo value");
         assert_eq!(config2.bar, 1234);
         assert_eq!(&config2.baz, &None);
         assert_eq!(&config2.more.thing1, "thing1 value");
         assert_eq!(&config2.more.thing2, "thing2 value");
     }
+
+    #[derive(Debug, Deserialize)]
+    #[cfg(feature = "substitution")]
+    struct Server {
+        host: String,
+        port: Option<String>
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[cfg(feature = "substitution")]
+    struct ArrayConfig {
+        hosts: Vec<String>,
+        servers: Vec<Server>,
+        groups: Vec<Vec<String>>
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_expands_env_vars_in_arrays() {
+        let config_str = r#"
+            hosts = ["<<ENV:ARRAY_HOST1>>", "literal", "<<ENV?:ARRAY_UNSET>>", "<<ENV:ARRAY_HOST2>>"]
+            servers = [
+                { host = "<<ENV:ARRAY_HOST1>>", port = "<<ENV?:ARRAY_UNSET>>" },
+                { host = "other", port = "<<ENV:ARRAY_PORT>>" }
+            ]
+            groups = [["<<ENV:ARRAY_HOST1>>"], ["<<ENV?:ARRAY_UNSET>>", "<<ENV:ARRAY_HOST2>>"]]
+        "#;
+
+        let env = env_vars(&[("ARRAY_HOST1", "host1"), ("ARRAY_HOST2", "host2"), ("ARRAY_PORT", "8080")]);
+
+        let config: ArrayConfig = load_config_from_str_with_env(config_str, &env).unwrap();
+        assert_eq!(config.hosts, vec!["host1", "literal", "host2"]);
+        assert_eq!(&config.servers[0].host, "host1");
+        assert_eq!(&config.servers[0].port, &None);
+        assert_eq!(&config.servers[1].host, "other");
+        assert_eq!(&config.servers[1].port, &Some("8080".to_string()));
+        assert_eq!(config.groups, vec![vec!["host1"], vec!["host2"]]);
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_fails_when_required_env_var_missing_in_array() {
+        let config_str = r#"
+            hosts = ["<<ENV:ARRAY_MISSING>>"]
+            servers = []
+            groups = []
+        "#;
+
+        assert!(load_config_from_str::<ArrayConfig>(config_str).is_err())
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_uses_default_when_required_env_var_missing() {
+        let config_str = r#"
+            foo = "<<ENV:DEFAULT_FOO:-http://localhost:8080/a b>>"
+            bar = 1234
+            baz = "<<ENV:DEFAULT_BAZ:->>"
+            [more]
+            thing1 = "<<ENV:DEFAULT_THING1:-unused default>>"
+            thing2 = "thing2 value"
+        "#;
+
+        let env = env_vars(&[("DEFAULT_THING1", "env thing1 value")]);
+
+        let config: Config = load_config_from_str_with_env(config_str, &env).unwrap();
+        assert_eq!(&config.foo, "http://localhost:8080/a b");
+        assert_eq!(&config.baz, &Some("".to_string()));
+        assert_eq!(&config.more.thing1, "env thing1 value");
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_expands_env_vars_within_strings() {
+        let config_str = r#"
+            foo = "https://<<ENV:INLINE_HOST>>:<<ENV:INLINE_PORT:-80>>/api<<ENV?:INLINE_UNSET>>"
+            bar = 1234
+            baz = "<<ENV:INLINE_HOST>>/<<ENV:INLINE_HOST>>"
+            [more]
+            thing1 = "thing1 value"
+            thing2 = "thing2 value"
+        "#;
+
+        let env = env_vars(&[("INLINE_HOST", "example.com")]);
+
+        let config: Config = load_config_from_str_with_env(config_str, &env).unwrap();
+        assert_eq!(&config.foo, "https://example.com:80/api");
+        assert_eq!(&config.baz, &Some("example.com/example.com".to_string()));
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_fails_when_inline_required_env_var_missing() {
+        let config_str = r#"
+            foo = "https://<<ENV:INLINE_MISSING1>>:<<ENV:INLINE_MISSING2>>/api"
+            bar = 1234
+            [more]
+            thing1 = "thing1 value"
+            thing2 = "thing2 value"
+        "#;
+
+        let err = load_config_from_str::<Config>(config_str).unwrap_err();
+        match *err.kind() {
+            ErrorKind::Multiple(ref errs) => assert_eq!(errs.len(), 2),
+            ref other => panic!("Expected multiple errors, got {:?}", other)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_reads_values_from_files() {
+        let secret_path = env::temp_dir().join("config_loader_file_directive_secret");
+        fs::write(&secret_path, "file secret\n").unwrap();
+
+        let config_str = format!(r#"
+            foo = "<<FILE:{}>>"
+            bar = 1234
+            baz = "<<FILE?:{}>>"
+            [more]
+            thing1 = "thing1 value"
+            thing2 = "thing2 value"
+        "#, secret_path.display(), secret_path.with_extension("missing").display());
+
+        let config: Config = load_config_from_str(&config_str).unwrap();
+        assert_eq!(&config.foo, "file secret");
+        assert_eq!(&config.baz, &None);
+
+        fs::remove_file(secret_path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_fails_when_required_file_missing() {
+        let missing_path = env::temp_dir().join("config_loader_file_directive_missing");
+
+        let config_str = format!(r#"
+            foo = "<<FILE:{}>>"
+            bar = 1234
+            [more]
+            thing1 = "thing1 value"
+            thing2 = "thing2 value"
+        "#, missing_path.display());
+
+        match *load_config_from_str::<Config>(&config_str).unwrap_err().kind() {
+            ErrorKind::Io(_) => (),
+            ref other => panic!("Expected io error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn it_loads_from_value() {
+        let value: toml::Value = toml::from_str(r#"
+            foo = "value foo"
+            bar = 1234
+            [more]
+            thing1 = "thing1 value"
+            thing2 = "thing2 value"
+        "#).unwrap();
+
+        let config: Config = load_config_from_value(value).unwrap();
+        assert_eq!(&config.foo, "value foo");
+        assert_eq!(config.bar, 1234);
+        assert_eq!(&config.baz, &None);
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[cfg(feature = "substitution")]
+    struct DatetimeConfig {
+        name: String,
+        created_at: toml::value::Datetime,
+        times: Vec<toml::value::Datetime>
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_preserves_datetimes_alongside_env_vars() {
+        let config_str = r#"
+            name = "<<ENV:DATETIME_NAME>>"
+            created_at = 1979-05-27T07:32:00Z
+            times = [1979-05-27, 07:32:00]
+        "#;
+
+        let env = env_vars(&[("DATETIME_NAME", "env name")]);
+
+        let config: DatetimeConfig = load_config_from_str_with_env(config_str, &env).unwrap();
+        assert_eq!(&config.name, "env name");
+        assert_eq!(config.created_at.to_string(), "1979-05-27T07:32:00Z");
+        assert_eq!(config.times[0].to_string(), "1979-05-27");
+        assert_eq!(config.times[1].to_string(), "07:32:00");
+    }
+
+    #[test]
+    fn it_rejects_unknown_config_extensions() {
+        match *load_config::<Config, _>(Some("Config.ini")).unwrap_err().kind() {
+            ErrorKind::UnknownFormat(ref ext) => assert_eq!(ext, "ini"),
+            ref other => panic!("Expected unknown format error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn it_loads_with_explicit_format() {
+        let config_path = env::temp_dir().join("config_loader_explicit_format.conf");
+        fs::write(&config_path, r#"
+            foo = "foo value"
+            bar = 1234
+            [more]
+            thing1 = "thing1 value"
+            thing2 = "thing2 value"
+        "#).unwrap();
+
+        let config: Config = load_config_with_format(Some(&config_path), Format::Toml).unwrap();
+        assert_eq!(&config.foo, "foo value");
+        assert_eq!(config.bar, 1234);
+
+        fs::remove_file(config_path).unwrap();
+    }
+
+    #[test]
+    fn it_merges_multiple_config_files() {
+        let defaults_path = env::temp_dir().join("config_loader_merge_defaults.toml");
+        let local_path = env::temp_dir().join("config_loader_merge_local.toml");
+        fs::write(&defaults_path, r#"
+            foo = "default foo"
+            bar = 1
+            [more]
+            thing1 = "default thing1"
+            thing2 = "default thing2"
+        "#).unwrap();
+        fs::write(&local_path, r#"
+            bar = 2
+            [more]
+            thing1 = "local thing1"
+        "#).unwrap();
+
+        let config: Config = load_configs(&[&defaults_path, &local_path]).unwrap();
+        assert_eq!(&config.foo, "default foo");
+        assert_eq!(config.bar, 2);
+        assert_eq!(&config.more.thing1, "local thing1");
+        assert_eq!(&config.more.thing2, "default thing2");
+
+        fs::remove_file(defaults_path).unwrap();
+        fs::remove_file(local_path).unwrap();
+    }
+
+    #[test]
+    fn it_loads_layered_configs() {
+        let base_path = env::temp_dir().join("config_loader_layered_base.toml");
+        let overlay_path = env::temp_dir().join("config_loader_layered_base.production.toml");
+        fs::write(&base_path, r#"
+            foo = "base foo"
+            bar = 1
+            [more]
+            thing1 = "base thing1"
+            thing2 = "base thing2"
+        "#).unwrap();
+        fs::write(&overlay_path, r#"
+            foo = "overlay foo"
+        "#).unwrap();
+
+        let base_only: Config = load_layered_config(&base_path, None).unwrap();
+        assert_eq!(&base_only.foo, "base foo");
+        assert_eq!(&base_only.more.thing1, "base thing1");
+
+        let layered: Config = load_layered_config(&base_path, Some(&overlay_path)).unwrap();
+        assert_eq!(&layered.foo, "overlay foo");
+        assert_eq!(&layered.more.thing1, "base thing1");
+        assert_eq!(&layered.more.thing2, "base thing2");
+
+        let missing_overlay = overlay_path.with_extension("missing.toml");
+        assert!(load_layered_config::<Config, _>(&base_path, Some(&missing_overlay)).is_err());
+
+        fs::remove_file(base_path).unwrap();
+        fs::remove_file(overlay_path).unwrap();
+    }
+
+    #[test]
+    fn it_reads_config_path_from_env() {
+        let config_path = env::temp_dir().join("config_loader_path_from_env.toml");
+        fs::write(&config_path, r#"
+            foo = "env path foo"
+            bar = 1234
+            [more]
+            thing1 = "thing1 value"
+            thing2 = "thing2 value"
+        "#).unwrap();
+
+        let env = env_vars(&[(CONFIG_PATH_ENV, config_path.to_str().unwrap())]);
+        let resolved = resolve_config_path_for_env(None::<&str>, "Missing.toml", APP_ENV_VAR, DEFAULT_ENV_FILE_PATTERN, &env).unwrap();
+        assert_eq!(resolved, config_path);
+
+        let config: Config = load_config(Some(resolved)).unwrap();
+        assert_eq!(&config.foo, "env path foo");
+
+        fs::remove_file(config_path).unwrap();
+    }
+
+    #[test]
+    fn it_finds_default_config_next_to_executable() {
+        let exe_dir = env::current_exe().unwrap().parent().unwrap().to_path_buf();
+        let config_path = exe_dir.join("config_loader_default_name.toml");
+        fs::write(&config_path, "").unwrap();
+
+        assert_eq!(get_default_config_path("config_loader_default_name.toml").ok(), Some(config_path.clone()));
+        assert_eq!(get_default_config_path("config_loader_default_name.missing").ok(), None);
+
+        fs::remove_file(config_path).unwrap();
+    }
+
+    #[test]
+    fn it_finds_default_config_in_parent_directories() {
+        let root = env::temp_dir().join("config_loader_upward_test");
+        let nested = root.join("a/b/c");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(root.join("a/upward.toml"), "").unwrap();
+
+        assert_eq!(find_upward(&nested, "upward.toml"), Some(root.join("a/upward.toml")));
+        assert_eq!(find_upward(&nested, "upward.missing"), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[cfg(feature = "substitution")]
+    struct TypedConfig {
+        port: i32,
+        debug: bool,
+        rate: f64,
+        name: String
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_coerces_env_values_when_requested() {
+        let config_str = r#"
+            port = "<<ENV:COERCE_PORT:-8080>>"
+            debug = "<<ENV:COERCE_DEBUG:-false>>"
+            rate = "<<ENV:COERCE_RATE:--1.5e3>>"
+            name = "<<ENV:COERCE_NAME:-nan>>"
+        "#;
+
+        assert!(load_config_from_str::<TypedConfig>(config_str).is_err());
+
+        let config: TypedConfig = load_config_from_str_coerced(config_str).unwrap();
+        assert_eq!(config.port, 8080);
+        assert!(!config.debug);
+        assert_eq!(config.rate, -1500.0);
+        assert_eq!(&config.name, "nan");
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_expands_env_vars_recursively() {
+        let config_str = r#"
+            foo = "<<ENV:RECURSIVE_FOO>>"
+            bar = 1234
+            baz = "prefix-<<ENV:RECURSIVE_FOO>>"
+            [more]
+            thing1 = "thing1 value"
+            thing2 = "thing2 value"
+        "#;
+
+        let env = env_vars(&[("RECURSIVE_FOO", "<<ENV:RECURSIVE_BAR>>"), ("RECURSIVE_BAR", "bar-<<ENV:RECURSIVE_BAZ>>"), ("RECURSIVE_BAZ", "baz value")]);
+
+        let config: Config = load_config_from_str_with_env(config_str, &env).unwrap();
+        assert_eq!(&config.foo, "bar-baz value");
+        assert_eq!(&config.baz, &Some("prefix-bar-baz value".to_string()));
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_fails_on_env_var_expansion_cycle() {
+        let config_str = r#"
+            foo = "<<ENV:CYCLE_A>>"
+            bar = 1234
+            [more]
+            thing1 = "thing1 value"
+            thing2 = "thing2 value"
+        "#;
+
+        let env = env_vars(&[("CYCLE_A", "<<ENV:CYCLE_B>>"), ("CYCLE_B", "<<ENV:CYCLE_A>>")]);
+
+        match *load_config_from_str_with_env::<Config>(config_str, &env).unwrap_err().kind() {
+            ErrorKind::ExpansionCycle(_) => (),
+            ref other => panic!("Expected expansion cycle error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_leaves_escaped_placeholders_literal() {
+        let config_str = r#"
+            foo = '<<\ENV:ESCAPED_FOO>>'
+            bar = 1234
+            baz = '<<\ENV:ESCAPED_FOO>> is <<ENV:ESCAPED_FOO>>, <<\\ENV:ESCAPED_FOO>>'
+            [more]
+            thing1 = '<<\FILE:/etc/passwd>>'
+            thing2 = "thing2 value"
+        "#;
+
+        let env = env_vars(&[("ESCAPED_FOO", "expanded")]);
+
+        let config: Config = load_config_from_str_with_env(config_str, &env).unwrap();
+        assert_eq!(&config.foo, "<<ENV:ESCAPED_FOO>>");
+        assert_eq!(&config.baz, &Some(r"<<ENV:ESCAPED_FOO>> is expanded, <<\ENV:ESCAPED_FOO>>".to_string()));
+        assert_eq!(&config.more.thing1, "<<FILE:/etc/passwd>>");
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_reports_every_missing_env_var_at_once() {
+        let config_str = r#"
+            foo = "<<ENV:AGGREGATE_MISSING1>>"
+            bar = 1234
+            [more]
+            thing1 = "https://<<ENV:AGGREGATE_MISSING2>>/<<ENV:AGGREGATE_MISSING3>>"
+            thing2 = "thing2 value"
+            [more.deeper]
+            list = [{ value = "<<ENV:AGGREGATE_MISSING4>>" }, "<<ENV:AGGREGATE_MISSING5>>"]
+        "#;
+
+        let err = load_config_from_str::<Config>(config_str).unwrap_err();
+        let mut missing = match *err.kind() {
+            ErrorKind::Multiple(ref errs) => errs.iter().map(|e| match *e.kind() {
+                ErrorKind::EnvVarMissing(ref key, _) => key.clone(),
+                ref other => panic!("Expected missing env var error, got {:?}", other)
+            }).collect::<Vec<_>>(),
+            ref other => panic!("Expected multiple errors, got {:?}", other)
+        };
+        missing.sort();
+        assert_eq!(missing, vec!["AGGREGATE_MISSING1", "AGGREGATE_MISSING2", "AGGREGATE_MISSING3",
+                                 "AGGREGATE_MISSING4", "AGGREGATE_MISSING5"]);
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_reports_key_path_of_missing_env_var() {
+        let config_str = r#"
+            foo = "foo value"
+            bar = 1234
+            [more]
+            thing1 = "thing1 value"
+            thing2 = "thing2 value"
+            [database.credentials]
+            password = "<<ENV:PATH_MISSING_PASSWORD>>"
+            [[database.replicas]]
+            host = "literal"
+            [[database.replicas]]
+            host = "<<ENV:PATH_MISSING_HOST>>"
+        "#;
+
+        let err = load_config_from_str::<Config>(config_str).unwrap_err();
+        let mut paths = match *err.kind() {
+            ErrorKind::Multiple(ref errs) => errs.iter().map(|e| match *e.kind() {
+                ErrorKind::EnvVarMissing(_, ref path) => path.clone(),
+                ref other => panic!("Expected missing env var error, got {:?}", other)
+            }).collect::<Vec<_>>(),
+            ref other => panic!("Expected multiple errors, got {:?}", other)
+        };
+        paths.sort();
+        assert_eq!(paths, vec!["database.credentials.password", "database.replicas[1].host"]);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn it_loads_config_async() {
+        let config_path = env::temp_dir().join("config_loader_async.toml");
+        fs::write(&config_path, r#"
+            foo = "<<ENV:ASYNC_FOO:-async foo>>"
+            bar = 1234
+            [more]
+            thing1 = "thing1 value"
+            thing2 = "thing2 value"
+        "#).unwrap();
+
+        let config: Config = super::load_config_async(Some(&config_path)).await.unwrap();
+        assert_eq!(&config.foo, "async foo");
+        assert_eq!(config.bar, 1234);
+
+        fs::remove_file(config_path).unwrap();
+    }
+
+    #[cfg(feature = "checksum")]
+    #[test]
+    fn it_verifies_config_checksum() {
+        let config_path = env::temp_dir().join("config_loader_verified.toml");
+        fs::write(&config_path, "name = \"verified\"\n").unwrap();
+        let sha256 = "ffb08be9baab60f42bfeba9e6dff9041536653f4ef8c0a8f549f5b15329a92ed";
+
+        let config: toml::Value = super::load_config_verified(&config_path, sha256).unwrap();
+        assert_eq!(config["name"].as_str(), Some("verified"));
+        assert!(super::load_config_verified::<toml::Value, _>(&config_path, &sha256.to_uppercase()).is_ok());
+
+        let err = super::load_config_verified::<toml::Value, _>(&config_path, &"0".repeat(64)).unwrap_err();
+        match *err.kind() {
+            ErrorKind::ChecksumMismatch(_, _, ref actual) => assert_eq!(actual, sha256),
+            ref other => panic!("Expected checksum mismatch, got {:?}", other)
+        }
+        assert!(err.to_string().starts_with(&format!("Config file {} has SHA-256", config_path.display())));
+
+        fs::remove_file(config_path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_resolves_env_vars_with_dots_and_hyphens() {
+        let config_str = r#"
+            foo = "<<ENV:MYAPP.DB-HOST>>"
+            bar = 1234
+            baz = "<<ENV?:MYAPP.DB-PORT>>"
+            [more]
+            thing1 = "<<ENV:MYAPP.DB-HOST>>:<<ENV:MYAPP.DB-MISSING:-5432>>"
+            thing2 = "<<ENV:MYAPP.DB-MISSING:-fallback>>"
+        "#;
+
+        let env = env_vars(&[("MYAPP.DB-HOST", "db.example.com"), ("MYAPP.DB-PORT", "5433")]);
+
+        let config: Config = load_config_from_str_with_env(config_str, &env).unwrap();
+        assert_eq!(&config.foo, "db.example.com");
+        assert_eq!(&config.baz, &Some("5433".to_string()));
+        assert_eq!(&config.more.thing1, "db.example.com:5432");
+        assert_eq!(&config.more.thing2, "fallback");
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_reads_env_keys_that_overlap_the_placeholder_syntax() {
+        let config_str = r#"
+            foo = "<<ENV:ENV>>"
+            bar = 1234
+            baz = "<<ENV?:ENV>>"
+            [more]
+            thing1 = "<<ENV:E>>"
+            thing2 = "<<ENV?:E>>"
+        "#;
+
+        let env = env_vars(&[("ENV", "env value"), ("E", "e value")]);
+
+        let config: Config = load_config_from_str_with_env(config_str, &env).unwrap();
+        assert_eq!(&config.foo, "env value");
+        assert_eq!(&config.baz, &Some("env value".to_string()));
+        assert_eq!(&config.more.thing1, "e value");
+        assert_eq!(&config.more.thing2, "e value");
+
+        // `?` isn't allowed in env names, so this isn't a valid placeholder.
+        let invalid_str = config_str.replace("<<ENV?:E>>", "<<ENV?:ENV?>>");
+        match *load_config_from_str_with_env::<Config>(&invalid_str, &env).unwrap_err().kind() {
+            ErrorKind::InvalidPlaceholder(ref placeholder, _) => assert_eq!(placeholder, "<<ENV?:ENV?>>"),
+            ref other => panic!("Expected invalid placeholder, got {:?}", other)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_validates_config_reporting_every_error() {
+        let valid_str = r#"
+            foo = "foo value"
+            bar = 1234
+            [more]
+            thing1 = "thing1 value"
+            thing2 = "thing2 value"
+        "#;
+        assert!(validate_config::<Config>(valid_str).is_ok());
+
+        let invalid_str = r#"
+            foo = "<<ENV:VALIDATE_MISSING1>>"
+            bar = "not a number"
+            [more]
+            thing1 = "<<ENV:VALIDATE_MISSING2>>"
+            thing2 = "thing2 value"
+        "#;
+        match *validate_config::<Config>(invalid_str).unwrap_err().kind() {
+            ErrorKind::Multiple(ref errs) => {
+                assert_eq!(errs.len(), 3);
+                assert_eq!(errs.iter().filter(|e| matches!(*e.kind(), ErrorKind::EnvVarMissing(..))).count(), 2);
+                assert!(errs.iter().any(|e| matches!(*e.kind(), ErrorKind::Deserialization(_))));
+            },
+            ref other => panic!("Expected multiple errors, got {:?}", other)
+        }
+
+        let unresolved_str = r#"
+            foo = "foo value"
+            bar = "<<ENV:VALIDATE_MISSING_BAR>>"
+            [more]
+            thing1 = "<<ENV:VALIDATE_MISSING1>>"
+            thing2 = "thing2 value"
+        "#;
+        match *validate_config::<Config>(unresolved_str).unwrap_err().kind() {
+            ErrorKind::Multiple(ref errs) => {
+                let missing = errs.iter().map(|e| match *e.kind() {
+                    ErrorKind::EnvVarMissing(ref key, ref path) => (key.as_str(), path.as_str()),
+                    ref other => panic!("Expected missing env var, got {:?}", other)
+                }).collect::<Vec<_>>();
+                assert_eq!(missing, vec![("VALIDATE_MISSING_BAR", "bar"), ("VALIDATE_MISSING1", "more.thing1")]);
+            },
+            ref other => panic!("Expected multiple errors, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn it_loads_from_reader() {
+        let config_bytes = br#"
+            foo = "reader foo"
+            bar = 1234
+            [more]
+            thing1 = "thing1 value"
+            thing2 = "thing2 value"
+        "#;
+
+        let config: Config = load_config_from_reader(&config_bytes[..]).unwrap();
+        assert_eq!(&config.foo, "reader foo");
+        assert_eq!(config.bar, 1234);
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_returns_expanded_value_for_debugging() {
+        let config_str = r#"
+            foo = "<<ENV:DEBUG_FOO:-debug foo>>"
+            bar = 1234
+            baz = "<<ENV?:DEBUG_UNSET>>"
+            [more]
+            thing1 = "thing1 value"
+            thing2 = "thing2 value"
+        "#;
+
+        let (config, expanded): (Config, _) = load_config_debug(config_str).unwrap();
+        assert_eq!(&config.foo, "debug foo");
+        assert_eq!(expanded.value()["foo"].as_str(), Some("debug foo"));
+        assert_eq!(expanded.value()["bar"].as_integer(), Some(1234));
+        assert!(expanded.value().get("baz").is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_redacts_secret_values() {
+        let config_str = r#"
+            foo = "<<SECRET_ENV:SECRET_FOO:-hunter2>>"
+            bar = 1234
+            baz = "<<SECRET_ENV?:SECRET_UNSET>>"
+            tokens = ["<<ENV?:SECRET_UNSET>>", "public", "<<SECRET_ENV:SECRET_TOKEN:-token>>"]
+            [more]
+            thing1 = "<<ENV:SECRET_FOO:-hunter2>>"
+            thing2 = "<<SECRET_ENV:SECRET_UNSET:-default secret>>"
+        "#;
+
+        let (config, expanded): (Config, _) = load_config_debug(config_str).unwrap();
+        assert_eq!(&config.foo, "hunter2");
+        assert_eq!(&config.baz, &None);
+        assert_eq!(&config.more.thing2, "default secret");
+
+        let secret_paths = expanded.secret_paths().iter().map(String::as_str).collect::<Vec<_>>();
+        assert_eq!(secret_paths, vec!["foo", "more.thing2", "tokens[1]"]);
+
+        let redacted = expanded.redacted_value();
+        assert_eq!(redacted["foo"].as_str(), Some("***"));
+        assert_eq!(redacted["tokens"][0].as_str(), Some("public"));
+        assert_eq!(redacted["tokens"][1].as_str(), Some("***"));
+        assert_eq!(redacted["more"]["thing1"].as_str(), Some("hunter2"));
+        assert_eq!(redacted["more"]["thing2"].as_str(), Some("***"));
+        assert_eq!(expanded.value()["foo"].as_str(), Some("hunter2"));
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_casts_typed_env_placeholders() {
+        let config_str = r#"
+            port = "<<ENV_INT:TYPED_PORT>>"
+            debug = "<<ENV_BOOL:TYPED_DEBUG>>"
+            rate = "<<ENV_FLOAT?:TYPED_UNSET:-0.5>>"
+            name = "<<ENV:TYPED_PORT>>"
+        "#;
+
+        let env = env_vars(&[("TYPED_PORT", "8080"), ("TYPED_DEBUG", "true")]);
+
+        let config: TypedConfig = load_config_from_str_with_env(config_str, &env).unwrap();
+        assert_eq!(config.port, 8080);
+        assert!(config.debug);
+        assert_eq!(config.rate, 0.5);
+        assert_eq!(&config.name, "8080");
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_errors_when_typed_env_value_does_not_parse() {
+        let config_str = r#"
+            port = "<<ENV_INT:TYPED_BAD_PORT>>"
+            debug = true
+            rate = 1.0
+            name = "x"
+        "#;
+
+        let env = env_vars(&[("TYPED_BAD_PORT", "eighty")]);
+
+        match load_config_from_str_with_env::<TypedConfig>(config_str, &env).unwrap_err().kind() {
+            ErrorKind::EnvVarParse(key, path, _, value) => {
+                assert_eq!(key, "TYPED_BAD_PORT");
+                assert_eq!(path, "port");
+                assert_eq!(value, "eighty");
+            },
+            e => panic!("unexpected error {:?}", e)
+        }
+    }
+
+    #[test]
+    fn it_reports_key_path_of_type_mismatches() {
+        let config_str = r#"
+            foo = "foo value"
+            bar = "<<ENV:MISMATCH_BAR>>"
+            [more]
+            thing1 = "thing1 value"
+            thing2 = "thing2 value"
+        "#;
+
+        let env = env_vars(&[("MISMATCH_BAR", "not a number")]);
+
+        let message = load_config_from_str_with_env::<Config>(config_str, &env).unwrap_err().to_string();
+        assert!(message.contains("`bar`"), "{}", message);
+        assert!(!message.contains("line"), "{}", message);
+    }
+
+    #[test]
+    fn it_reads_config_path_from_args() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.into()).collect::<Vec<std::ffi::OsString>>();
+
+        assert_eq!(config_path_from_args(args(&["-v", "serve"])).unwrap(), None);
+        assert_eq!(config_path_from_args(args(&["--config", "a.toml", "serve"])).unwrap(), Some("a.toml".into()));
+        assert_eq!(config_path_from_args(args(&["--config=b.toml"])).unwrap(), Some("b.toml".into()));
+        assert_eq!(config_path_from_args(args(&["-c", "a.toml", "-c", "c.yaml"])).unwrap(), Some("c.yaml".into()));
+
+        match *config_path_from_args(args(&["serve", "--config"])).unwrap_err().kind() {
+            ErrorKind::MissingArgValue(ref flag) => assert_eq!(flag, "--config"),
+            ref other => panic!("Expected missing arg value, got {:?}", other)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_expands_env_vars_in_keys() {
+        let config_str = r#"
+            ["<<ENV:KEY_TENANT>>"]
+            url = "https://<<ENV:KEY_TENANT>>.example.com"
+            ["static"]
+            url = "https://example.com"
+        "#;
+
+        let env = env_vars(&[("KEY_TENANT", "acme")]);
+
+        let config: BTreeMap<String, BTreeMap<String, String>> = load_config_from_str_with_env(config_str, &env).unwrap();
+        assert_eq!(config["acme"]["url"], "https://acme.example.com");
+        assert_eq!(config["static"]["url"], "https://example.com");
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_fails_when_expanded_keys_collide() {
+        let config_str = r#"
+            [servers]
+            "<<ENV:KEY_DUP_A>>" = 1
+            "<<ENV:KEY_DUP_B>>" = 2
+        "#;
+
+        let env = env_vars(&[("KEY_DUP_A", "primary"), ("KEY_DUP_B", "primary")]);
+
+        match *load_config_from_str_with_env::<BTreeMap<String, BTreeMap<String, i64>>>(config_str, &env).unwrap_err().kind() {
+            ErrorKind::DuplicateKey(ref path) => assert_eq!(path, "servers.primary"),
+            ref other => panic!("Expected duplicate key, got {:?}", other)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_falls_back_across_env_var_names() {
+        let config_str = r#"
+            foo = "<<ENV:FALLBACK_UNSET|FALLBACK_FOO>>"
+            bar = 1234
+            baz = "<<ENV?:FALLBACK_UNSET|FALLBACK_UNSET_TOO>>"
+            [more]
+            thing1 = "<<ENV:FALLBACK_FOO|FALLBACK_THING1>>"
+            thing2 = "db=<<ENV:FALLBACK_UNSET|FALLBACK_THING1>>"
+        "#;
+
+        let env = env_vars(&[("FALLBACK_FOO", "foo value"), ("FALLBACK_THING1", "thing1 value")]);
+
+        let config: Config = load_config_from_str_with_env(config_str, &env).unwrap();
+        assert_eq!(&config.foo, "foo value");
+        assert_eq!(&config.baz, &None);
+        assert_eq!(&config.more.thing1, "foo value");
+        assert_eq!(&config.more.thing2, "db=thing1 value");
+
+        let missing_str = config_str.replace("FALLBACK_UNSET|FALLBACK_FOO", "FALLBACK_UNSET|FALLBACK_UNSET_TOO");
+        match *load_config_from_str_with_env::<Config>(&missing_str, &env).unwrap_err().kind() {
+            ErrorKind::EnvVarMissing(ref key, _) => assert_eq!(key, "FALLBACK_UNSET|FALLBACK_UNSET_TOO"),
+            ref other => panic!("Expected missing env var, got {:?}", other)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_uses_env_var_default_for_env_var() {
+        let config_str = r#"
+            foo = "<<ENV:NESTED_DEFAULT_FOO:-<<ENV:NESTED_DEFAULT_FALLBACK>>>>"
+            bar = 1234
+            [more]
+            thing1 = "<<ENV:NESTED_DEFAULT_UNSET:-<<ENV:NESTED_DEFAULT_FALLBACK>>>>"
+            thing2 = "url=<<ENV:NESTED_DEFAULT_UNSET:-<<ENV:NESTED_DEFAULT_FALLBACK>>>>/path"
+        "#;
+
+        let env = env_vars(&[("NESTED_DEFAULT_FOO", "foo value"), ("NESTED_DEFAULT_FALLBACK", "fallback value")]);
+
+        let config: Config = load_config_from_str_with_env(config_str, &env).unwrap();
+        assert_eq!(&config.foo, "foo value");
+        assert_eq!(&config.more.thing1, "fallback value");
+        assert_eq!(&config.more.thing2, "url=fallback value/path");
+
+        let unset_str = config_str.replace("NESTED_DEFAULT_FALLBACK", "NESTED_DEFAULT_UNSET_TOO");
+        match *load_config_from_str_with_env::<Config>(&unset_str, &env).unwrap_err().kind() {
+            ErrorKind::Multiple(ref errs) => assert!(errs.iter().all(|e| match *e.kind() {
+                ErrorKind::EnvVarMissing(ref key, _) => key == "NESTED_DEFAULT_UNSET_TOO",
+                _ => false
+            })),
+            ref other => panic!("Expected missing env vars, got {:?}", other)
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[cfg(feature = "substitution")]
+    struct ServersConfig {
+        servers: Vec<Server>
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_expands_env_vars_in_arrays_of_tables() {
+        let config_str = r#"
+            [[servers]]
+            host = "<<ENV:AOT_HOST1>>"
+
+            [[servers]]
+            host = "<<ENV:AOT_HOST2>>"
+            port = "<<ENV?:AOT_PORT2>>"
+        "#;
+
+        let env = env_vars(&[("AOT_HOST1", "host1"), ("AOT_HOST2", "host2"), ("AOT_PORT2", "8080")]);
+
+        let config: ServersConfig = load_config_from_str_with_env(config_str, &env).unwrap();
+        assert_eq!(config.servers.len(), 2);
+        assert_eq!(&config.servers[0].host, "host1");
+        assert_eq!(&config.servers[0].port, &None);
+        assert_eq!(&config.servers[1].host, "host2");
+        assert_eq!(&config.servers[1].port, &Some("8080".to_string()));
+
+        let missing_str = config_str.replace("AOT_HOST", "AOT_MISSING_HOST");
+        match *load_config_from_str_with_env::<ServersConfig>(&missing_str, &env).unwrap_err().kind() {
+            ErrorKind::Multiple(ref errs) => {
+                let paths = errs.iter().map(|e| match *e.kind() {
+                    ErrorKind::EnvVarMissing(_, ref path) => path.clone(),
+                    ref other => panic!("Expected missing env var, got {:?}", other)
+                }).collect::<Vec<_>>();
+                assert_eq!(paths, vec!["servers[0].host", "servers[1].host"]);
+            },
+            ref other => panic!("Expected multiple errors, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn it_loads_fallback_when_config_file_missing() {
+        let fallback_str = r#"
+            foo = "fallback foo"
+            bar = 1
+            [more]
+            thing1 = "default thing1"
+            thing2 = "default thing2"
+        "#;
+
+        let missing_path = env::temp_dir().join("config_loader_fallback_missing.toml");
+        let config: Config = load_config_or(Some(&missing_path), fallback_str).unwrap();
+        assert_eq!(&config.foo, "fallback foo");
+        assert_eq!(config.bar, 1);
+
+        let config_path = env::temp_dir().join("config_loader_fallback_present.toml");
+        fs::write(&config_path, "foo = \"file foo\"\nbar = 2\n[more]\nthing1 = \"a\"\nthing2 = \"b\"\n").unwrap();
+        let config: Config = load_config_or(Some(&config_path), fallback_str).unwrap();
+        assert_eq!(&config.foo, "file foo");
+        assert_eq!(config.bar, 2);
+
+        fs::remove_file(config_path).unwrap();
+    }
+
+    #[test]
+    fn it_reports_missing_config_files_by_kind() {
+        let missing_path = env::temp_dir().join("config_loader_not_found.toml");
+        match *load_config::<Config, _>(Some(&missing_path)).unwrap_err().kind() {
+            ErrorKind::ConfigNotFound(ref path) => assert_eq!(path, &missing_path),
+            ref other => panic!("Expected config not found, got {:?}", other)
+        }
+
+        match *get_default_config_path("config_loader_default.missing").unwrap_err().kind() {
+            ErrorKind::DefaultConfigNotFound(ref name) => assert_eq!(name, "config_loader_default.missing"),
+            ref other => panic!("Expected default config not found, got {:?}", other)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_injects_unquoted_placeholders_as_typed_values() {
+        let config_str = r#"
+            port = <<ENV:UNQUOTED_PORT>>
+            debug = <<ENV?:UNQUOTED_DEBUG>>
+            rate = <<ENV:UNQUOTED_UNSET:-0.25>>
+            name = "<<ENV:UNQUOTED_PORT>>"
+        "#;
+
+        let env = env_vars(&[("UNQUOTED_PORT", "8080"), ("UNQUOTED_DEBUG", "true")]);
+
+        let config: TypedConfig = load_config_from_str_with_env(config_str, &env).unwrap();
+        assert_eq!(config.port, 8080);
+        assert!(config.debug);
+        assert_eq!(config.rate, 0.25);
+        assert_eq!(&config.name, "8080");
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_rejects_malformed_placeholders() {
+        let config_str = r#"
+            foo = "<<ENV FOO>>"
+            bar = 1234
+            baz = "<<ENV:>>"
+            [more]
+            thing1 = '<<\ENV FOO>>'
+            thing2 = "<<ENV:MALFORMED_SET>>"
+        "#;
+
+        let env = env_vars(&[("MALFORMED_SET", "set")]);
+
+        match *load_config_from_str_with_env::<Config>(config_str, &env).unwrap_err().kind() {
+            ErrorKind::Multiple(ref errs) => {
+                let invalid = errs.iter().map(|e| match *e.kind() {
+                    ErrorKind::InvalidPlaceholder(ref placeholder, ref path) => (placeholder.as_str(), path.as_str()),
+                    ref other => panic!("Expected invalid placeholder, got {:?}", other)
+                }).collect::<Vec<_>>();
+                assert_eq!(invalid, vec![("<<ENV:>>", "baz"), ("<<ENV FOO>>", "foo")]);
+            },
+            ref other => panic!("Expected multiple errors, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn it_combines_errors_in_order() {
+        let missing = |key: &str| super::Error::from(ErrorKind::EnvVarMissing(key.to_owned(), "path".to_owned()));
+        let combined = combine_errors(
+            missing("A"),
+            combine_errors(missing("B"), combine_errors(missing("C"), "custom validation failed".into())));
+
+        match *combined.kind() {
+            ErrorKind::Multiple(ref errs) => {
+                let messages = errs.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+                assert_eq!(messages.len(), 4);
+                assert!(messages[0].contains("'A'"));
+                assert!(messages[1].contains("'B'"));
+                assert!(messages[2].contains("'C'"));
+                assert_eq!(messages[3], "custom validation failed");
+            },
+            ref other => panic!("Expected multiple errors, got {:?}", other)
+        }
+        assert_eq!(combined.iter_multiple().map(|errs| errs.len()), Some(4));
+        assert!(missing("D").iter_multiple().is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_loads_env_vars_from_dotenv_file() {
+        let config_path = env::temp_dir().join("config_loader_dotenv.toml");
+        let dotenv_path = env::temp_dir().join("config_loader_dotenv.env");
+        fs::write(&config_path, r#"
+            foo = "<<ENV:DOTENV_FOO>>"
+            bar = 1
+            [more]
+            thing1 = "<<ENV:DOTENV_THING1>>"
+            thing2 = "thing2"
+        "#).unwrap();
+        fs::write(&dotenv_path, "DOTENV_FOO=\"from dotenv\"\nDOTENV_THING1=from dotenv\n").unwrap();
+
+        env::set_var("DOTENV_THING1", "from env");
+
+        let config: Config = load_config_with_dotenv(Some(&config_path), &dotenv_path).unwrap();
+        assert_eq!(&config.foo, "from dotenv");
+        assert_eq!(&config.more.thing1, "from env");
+
+        fs::remove_file(config_path).unwrap();
+        fs::remove_file(dotenv_path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_resolves_env_vars_from_explicit_map() {
+        let config_str = r#"
+            foo = "<<ENV:MAP_FOO>>"
+            bar = 1
+            baz = "<<ENV?:PATH>>"
+            [more]
+            thing1 = "<<ENV:MAP_THING1:-default>>"
+            thing2 = "<<ENV:MAP_FOO>>-2"
+        "#;
+
+        let mut env_vars = HashMap::new();
+        env_vars.insert("MAP_FOO".to_owned(), "foo from map".to_owned());
+
+        let config: Config = load_config_from_str_with_env(config_str, &env_vars).unwrap();
+        assert_eq!(&config.foo, "foo from map");
+        assert_eq!(&config.baz, &None);
+        assert_eq!(&config.more.thing1, "default");
+        assert_eq!(&config.more.thing2, "foo from map-2");
+    }
+
+    #[cfg(feature = "base64")]
+    #[test]
+    fn it_decodes_base64_env_values() {
+        let config_str = r#"
+            foo = "<<ENV_B64:B64_FOO>>"
+            bar = 1
+            [more]
+            thing1 = "<<ENV_B64:B64_INVALID>>"
+            thing2 = "thing2"
+        "#;
+
+        let env = env_vars(&[("B64_FOO", "aHVudGVyMg==\n"), ("B64_INVALID", "not base64!")]);
+
+        let config: Config = load_config_from_str_with_env(&config_str.replace("B64_INVALID", "B64_FOO"), &env).unwrap();
+        assert_eq!(&config.foo, "hunter2");
+
+        match *load_config_from_str_with_env::<Config>(config_str, &env).unwrap_err().kind() {
+            ErrorKind::Base64Decode(ref key, ref path, _) => {
+                assert_eq!(key, "B64_INVALID");
+                assert_eq!(path, "more.thing1");
+            },
+            ref other => panic!("Expected base64 error, got {:?}", other)
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+    struct EnvFormatConfig {
+        database_url: String,
+        port: i64,
+        debug: bool,
+        retry_rate: f64
+    }
+
+    #[test]
+    fn it_loads_env_format_config() {
+        let config_str = r#"
+            # app settings
+            DATABASE_URL="postgres://db.example.com/app"
+            PORT=5432
+            DEBUG=true
+            RETRY_RATE=0.5
+        "#;
+
+        let config: EnvFormatConfig = load_config_from_env_format(config_str).unwrap();
+        assert_eq!(&config.database_url, "postgres://db.example.com/app");
+        assert_eq!(config.port, 5432);
+        assert!(config.debug);
+        assert_eq!(config.retry_rate, 0.5);
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_coerces_placeholders_in_env_format_config() {
+        let config_str = r#"
+            DATABASE_URL="postgres://<<ENV:ENV_FORMAT_UNSET:-db.example.com>>/app"
+            PORT=<<ENV:ENV_FORMAT_UNSET:-5432>>
+            DEBUG=true
+            RETRY_RATE=0.5
+        "#;
+
+        let config: EnvFormatConfig = load_config_from_env_format(config_str).unwrap();
+        assert_eq!(&config.database_url, "postgres://db.example.com/app");
+        assert_eq!(config.port, 5432);
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_resolves_config_back_to_toml() {
+        let config_str = r#"
+            name = "<<ENV:RESOLVE_NAME:-resolved>>"
+            dropped = "<<ENV?:RESOLVE_UNSET>>"
+            hosts = ["<<ENV:RESOLVE_NAME:-resolved>>.example.com", "other"]
+            [db]
+            url = "postgres://<<ENV:RESOLVE_NAME:-resolved>>"
+            [[servers]]
+            port = 80
+        "#;
+
+        let resolved = resolve_to_string(config_str).unwrap();
+        let expected: toml::Value = toml::from_str(r#"
+            name = "resolved"
+            hosts = ["resolved.example.com", "other"]
+            [db]
+            url = "postgres://resolved"
+            [[servers]]
+            port = 80
+        "#).unwrap();
+        assert_eq!(toml::from_str::<toml::Value>(&resolved).unwrap(), expected);
+        assert!(!resolved.contains("<<"));
+    }
+
+    #[test]
+    fn it_names_the_value_that_failed_to_serialize() {
+        let invalid = toml::value::Datetime { date: None, time: None, offset: None };
+        let mut value: toml::Value = toml::from_str("name = \"app\"\n[[servers]]\nport = 80\n[[servers]]\nport = 81\n").unwrap();
+        value["servers"][1].as_table_mut().unwrap().insert("started".to_owned(), toml::Value::Datetime(invalid));
+
+        let err = super::to_toml_string(&value).unwrap_err();
+        match *err.kind() {
+            ErrorKind::Serialization(ref path) => assert_eq!(path, "servers[1].started"),
+            ref other => panic!("Expected serialization error, got {:?}", other)
+        }
+        let cause = std::error::Error::source(&err).unwrap();
+        assert!(cause.downcast_ref::<toml::ser::Error>().is_some());
+    }
+
+    #[test]
+    fn it_formats_multiple_errors_compactly_or_verbosely() {
+        let err = Error::multiple((1..=4).map(|i| Error::missing_env(&format!("VAR{}", i), "key")).collect());
+
+        let missing = |i: usize| format!("Required environment variable 'VAR{}' not set (referenced by 'key')", i);
+
+        assert_eq!(err.display_compact(2), format!("Errors: {}, {}, and 2 more", missing(1), missing(2)));
+        assert_eq!(err.display_compact(4), err.to_string());
+        assert_eq!(err.display_verbose(), format!("4 errors:\n  - {}\n  - {}\n  - {}\n  - {}", missing(1), missing(2), missing(3), missing(4)));
+
+        let single = Error::missing_env("VAR", "key");
+        assert_eq!(single.display_compact(0), single.to_string());
+        assert_eq!(single.display_verbose(), single.to_string());
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_falls_back_to_other_config_values() {
+        let config_str = r#"
+            name = "<<ENV:CONFIG_FALLBACK_UNSET||defaults.name>>"
+            port = "<<ENV:CONFIG_FALLBACK_UNSET||chained>>"
+            chained = "<<ENV:CONFIG_FALLBACK_UNSET||defaults.port>>"
+            db = "<<ENV:CONFIG_FALLBACK_UNSET||defaults.db>>"
+            [defaults]
+            name = "from config"
+            port = 8080
+            db = { host = "<<ENV:CONFIG_FALLBACK_UNSET||defaults.name>>", password = "<<SECRET_ENV:CONFIG_FALLBACK_UNSET:-secret>>" }
+        "#;
+
+        let (value, expanded) = load_config_debug::<toml::Value>(config_str).unwrap();
+        assert_eq!(value["name"].as_str(), Some("from config"));
+        assert_eq!(value["port"].as_integer(), Some(8080));
+        assert_eq!(value["db"]["host"].as_str(), Some("from config"));
+        assert!(expanded.secret_paths().contains("db.password"));
+
+        let env = env_vars(&[("CONFIG_FALLBACK_SET", "from env")]);
+        let value: toml::Value = load_config_from_str_with_env("set = \"<<ENV:CONFIG_FALLBACK_SET||name>>\"\nname = \"from config\"\n", &env).unwrap();
+        assert_eq!(value["set"].as_str(), Some("from env"));
+
+        match *load_config_from_str::<toml::Value>("a = \"<<ENV:CONFIG_FALLBACK_UNSET||b>>\"\nb = \"<<ENV:CONFIG_FALLBACK_UNSET||a>>\"\n").unwrap_err().kind() {
+            ErrorKind::ReferenceCycle(ref path) => assert_eq!(path, "a"),
+            ref other => panic!("Expected reference cycle, got {:?}", other)
+        }
+        match *load_config_from_str::<toml::Value>("a = \"<<ENV:CONFIG_FALLBACK_UNSET||missing>>\"\n").unwrap_err().kind() {
+            ErrorKind::InvalidReference(ref reference, ref path) => assert_eq!((reference.as_str(), path.as_str()), ("missing", "a")),
+            ref other => panic!("Expected invalid reference, got {:?}", other)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_lists_unresolved_placeholders() {
+        let config_str = r#"
+            name = "<<ENV:UNRESOLVED_SET:-set>>"
+            url = "http://<<ENV:UNRESOLVED_HOST>>:<<ENV:UNRESOLVED_SET:-set>>"
+            optional = "<<ENV?:UNRESOLVED_OPTIONAL>>"
+            [tenants]
+            "<<ENV:UNRESOLVED_TENANT>>" = { key = "<<SECRET_ENV:UNRESOLVED_KEY>>" }
+        "#;
+
+        assert_eq!(unresolved_placeholders(config_str).unwrap(), vec![
+            ("tenants.<<ENV:UNRESOLVED_TENANT>>".to_owned(), "<<ENV:UNRESOLVED_TENANT>>".to_owned()),
+            ("tenants.<<ENV:UNRESOLVED_TENANT>>.key".to_owned(), "<<SECRET_ENV:UNRESOLVED_KEY>>".to_owned()),
+            ("url".to_owned(), "http://<<ENV:UNRESOLVED_HOST>>:<<ENV:UNRESOLVED_SET:-set>>".to_owned())
+        ]);
+        assert!(unresolved_placeholders("name = ").is_err());
+    }
+
+    #[test]
+    fn it_loads_a_config_directory() {
+        let dir = env::temp_dir().join("config_loader_conf.d");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("nested.toml")).unwrap();
+        fs::write(dir.join("10-base.toml"), r#"
+            foo = "base foo"
+            bar = 1
+            [more]
+            thing1 = "base thing1"
+            thing2 = "base thing2"
+        "#).unwrap();
+        fs::write(dir.join("20-local.toml"), "bar = 2\n[more]\nthing1 = \"local thing1\"\n").unwrap();
+        fs::write(dir.join("30-ignored.toml.bak"), "bar = 3\n").unwrap();
+        fs::write(dir.join("README"), "not toml").unwrap();
+
+        let config: Config = load_config_dir(&dir).unwrap();
+        assert_eq!(&config.foo, "base foo");
+        assert_eq!(config.bar, 2);
+        assert_eq!(&config.more.thing1, "local thing1");
+        assert_eq!(&config.more.thing2, "base thing2");
+
+        fs::remove_dir_all(&dir).unwrap();
+        match *load_config_dir::<Config, _>(&dir).unwrap_err().kind() {
+            ErrorKind::ConfigNotFound(ref path) => assert_eq!(path, &dir),
+            ref other => panic!("Expected config not found, got {:?}", other)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_expands_env_without_deserializing() {
+        let value: toml::Value = toml::from_str(r#"
+            host = "<<ENV:EXPAND_ENV_HOST:-db.internal>>"
+            port = 5432
+            dropped = "<<ENV?:EXPAND_ENV_UNSET>>"
+            tags = ["<<ENV:EXPAND_ENV_HOST:-db.internal>>", "<<ENV?:EXPAND_ENV_UNSET>>"]
+        "#).unwrap();
+
+        let expected: toml::Value = toml::from_str(r#"
+            host = "db.internal"
+            port = 5432
+            tags = ["db.internal"]
+        "#).unwrap();
+        assert_eq!(expand_env(value).unwrap(), expected);
+
+        let missing: toml::Value = toml::from_str("host = \"<<ENV:EXPAND_ENV_UNSET>>\"").unwrap();
+        assert!(expand_env(missing).is_err());
+    }
+
+    #[test]
+    fn it_prefers_environment_specific_default_config() {
+        assert_eq!(env_file_name("Config.toml", "production", DEFAULT_ENV_FILE_PATTERN), "Config.production.toml");
+        assert_eq!(env_file_name("conf/app", "dev", DEFAULT_ENV_FILE_PATTERN), "conf/app.dev");
+        assert_eq!(env_file_name("app.yaml", "dev", "{env}/{stem}{ext}"), "dev/app.yaml");
+
+        let exe_dir = env::current_exe().unwrap().parent().unwrap().to_path_buf();
+        let base_path = exe_dir.join("config_loader_app_env.toml");
+        let staging_path = exe_dir.join("config_loader_app_env.staging.toml");
+        fs::write(&base_path, "").unwrap();
+        fs::write(&staging_path, "").unwrap();
+
+        let find = |app_env| get_env_config_path("config_loader_app_env.toml", app_env, DEFAULT_ENV_FILE_PATTERN).ok();
+        assert_eq!(find(Some("staging")), Some(staging_path.clone()));
+        assert_eq!(find(Some("production")), Some(base_path.clone()));
+        assert_eq!(find(None), Some(base_path.clone()));
+
+        fs::remove_file(base_path).unwrap();
+        fs::remove_file(staging_path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_reports_which_env_vars_were_used() {
+        let config_str = r#"
+            foo = "<<ENV:REPORT_UNSET|REPORT_FOO>>"
+            bar = 1
+            baz = "<<ENV?:REPORT_UNSET>>"
+            [more]
+            thing1 = "<<ENV:REPORT_UNSET:-default>>"
+            thing2 = "<<ENV?:REPORT_OPTIONAL>>-<<ENV:REPORT_FOO>>"
+        "#;
+
+        let env = env_vars(&[("REPORT_FOO", "foo"), ("REPORT_OPTIONAL", "optional")]);
+        let options = ExpandOptions { env: Some(SharedEnvSource(Arc::new(env))), ..ExpandOptions::default() };
+        let expanded = expand::expand(&options, Format::Toml.parse(config_str).unwrap()).unwrap();
+        assert_eq!(expanded.value()["more"]["thing2"].as_str(), Some("optional-foo"));
+
+        let vars = |entries: &[(&str, &str)]| entries.iter()
+            .map(|&(path, key)| (path.to_owned(), vec![key.to_owned()].into_iter().collect()))
+            .collect::<BTreeMap<_, _>>();
+        assert_eq!(expanded.report(), &LoadReport {
+            required: vars(&[("foo", "REPORT_FOO"), ("more.thing2", "REPORT_FOO")]),
+            optional: vars(&[("more.thing2", "REPORT_OPTIONAL")]),
+            skipped: vars(&[("baz", "REPORT_UNSET"), ("more.thing1", "REPORT_UNSET")])
+        });
+
+        let config_path = env::temp_dir().join("config_loader_report.toml");
+        fs::write(&config_path, "foo = \"<<ENV:REPORT_UNSET:-foo>>\"\nbar = 1\n[more]\nthing1 = \"a\"\nthing2 = \"b\"\n").unwrap();
+
+        let (config, report): (Config, LoadReport) = load_config_with_report(Some(&config_path)).unwrap();
+        assert_eq!(&config.foo, "foo");
+        assert_eq!(report, LoadReport { skipped: vars(&[("foo", "REPORT_UNSET")]), ..LoadReport::default() });
+
+        fs::remove_file(config_path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_expands_placeholders_in_inline_tables() {
+        #[derive(Debug, Deserialize)]
+        struct Server {
+            host: String,
+            port: i64,
+            name: Option<String>
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct InlineConfig {
+            server: Server,
+            replicas: Vec<Server>
+        }
+
+        let config_str = r#"
+            server = { host = "<<ENV:INLINE_TABLE_HOST>>", port = 8080, name = "<<ENV?:INLINE_TABLE_UNSET>>" }
+            replicas = [{ host = "replica-<<ENV:INLINE_TABLE_HOST>>", port = "<<ENV_INT:INLINE_TABLE_PORT>>", name = "<<ENV?:INLINE_TABLE_NAME>>" }]
+        "#;
+
+        let env = env_vars(&[("INLINE_TABLE_HOST", "db"), ("INLINE_TABLE_PORT", "5432"), ("INLINE_TABLE_NAME", "primary")]);
+
+        let config: InlineConfig = load_config_from_str_with_env(config_str, &env).unwrap();
+        assert_eq!(&config.server.host, "db");
+        assert_eq!(config.server.port, 8080);
+        assert_eq!(config.server.name, None);
+        assert_eq!(&config.replicas[0].host, "replica-db");
+        assert_eq!(config.replicas[0].port, 5432);
+        assert_eq!(config.replicas[0].name.as_deref(), Some("primary"));
+    }
+
+    #[test]
+    fn it_runs_validation_after_loading() {
+        let config_str = r#"
+            foo = "foo"
+            bar = 0
+            [more]
+            thing1 = "thing1"
+            thing2 = "thing2"
+        "#;
+        let positive_bar = |config: &Config| if config.bar > 0 { Ok(()) } else { Err("bar must be > 0".to_owned()) };
+
+        let err = load_config_validated(config_str, positive_bar).unwrap_err();
+        match *err.kind() {
+            ErrorKind::Validation(ref reason) => assert_eq!(reason, "bar must be > 0"),
+            ref other => panic!("Expected validation error, got {:?}", other)
+        }
+        assert_eq!(err.to_string(), "Invalid config: bar must be > 0");
+
+        let config = load_config_validated(&config_str.replace("bar = 0", "bar = 1"), positive_bar).unwrap();
+        assert_eq!(config.bar, 1);
+    }
+
+    #[cfg(feature = "keyring")]
+    #[test]
+    fn it_reports_missing_keyring_entries() {
+        keyring::set_default_credential_builder(keyring::mock::default_credential_builder());
+
+        let err = load_config_from_str::<Config>(r#"
+            foo = "<<KEYRING:config_loader_test/nobody>>"
+            bar = 1
+            [more]
+            thing1 = "thing1"
+            thing2 = "thing2"
+        "#).unwrap_err();
+        match *err.kind() {
+            ErrorKind::KeyringError(ref entry, _) => assert_eq!(entry, "config_loader_test/nobody"),
+            ref other => panic!("Expected keyring error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn it_builds_and_inspects_errors() {
+        let err = Error::multiple(vec![
+            Error::missing_env("A", "a"),
+            Error::multiple(vec![Error::missing_env("B", "b"), ErrorKind::UnknownKey("c".to_owned()).into()])
+        ]);
+        assert_eq!(err.iter_multiple().unwrap().len(), 3);
+        assert_eq!(err.missing_env_vars(), vec!["A", "B"]);
+        assert!(!err.is_not_found());
+
+        let single = Error::multiple(vec![Error::missing_env("A", "a")]);
+        assert!(single.iter_multiple().is_none());
+        assert_eq!(single.missing_env_vars(), vec!["A"]);
+        let nested = Error::multiple(vec![ErrorKind::Multiple(vec![Error::missing_env("A", "a")]).into()]);
+        assert!(nested.iter_multiple().is_none());
+        assert!(matches!(*nested.kind(), ErrorKind::EnvVarMissing(..)));
+        assert!(Error::from(ErrorKind::ConfigNotFound("missing.toml".into())).is_not_found());
+    }
+
+    #[test]
+    #[should_panic(expected = "without any errors")]
+    #[cfg(debug_assertions)]
+    fn it_rejects_combining_no_errors() {
+        Error::multiple(Vec::new());
+    }
 }
@@ -0,0 +1,1043 @@
+use serde::de::DeserializeOwned;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use crate::env_source::{EnvSource, ProcessEnv, SharedEnvSource};
+use crate::expand::{self, ExpandOptions};
+use crate::format::parse_documents;
+use crate::interpolate::interpolate;
+use crate::nullable::NullableValue;
+use crate::strict::deserialize_strict;
+use crate::merge::{self, MergeStrategy};
+use crate::{config_dir_paths, empty_table, read_config_value, resolve_config_path_for_env, Error, ErrorKind, Format, APP_ENV_VAR, CONFIG_PATH_ENV, DEFAULT_CONFIG_NAME, DEFAULT_ENV_FILE_PATTERN};
+#[cfg(feature = "substitution")]
+use crate::requirements::check_consistent_env_usage;
+#[cfg(feature = "substitution")]
+use crate::syntax::Syntax;
+
+/// Builder for loading config with non-default settings.
+///
+/// ```no_run
+/// # use config_loader::ConfigLoader;
+/// # use std::collections::BTreeMap;
+/// let config: BTreeMap<String, String> = ConfigLoader::new()
+///     .default_name("app.toml")
+///     .overlay("app.local.toml")
+///     .coerce(true)
+///     .load()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConfigLoader {
+    path: Option<PathBuf>,
+    default_name: String,
+    app_env_var: String,
+    env_file_pattern: String,
+    overlay: Option<PathBuf>,
+    #[cfg(feature = "substitution")]
+    delimiters: Option<(String, String)>,
+    #[cfg(feature = "substitution")]
+    check_env_usage: bool,
+    strict: bool,
+    expand_paths: bool,
+    interpolate: bool,
+    defaults_table: bool,
+    max_file_size: Option<u64>,
+    document_separator: Option<String>,
+    merge_strategy: MergeStrategy,
+    options: ExpandOptions
+}
+
+impl ConfigLoader {
+    pub fn new() -> ConfigLoader {
+        ConfigLoader {
+            path: None,
+            default_name: DEFAULT_CONFIG_NAME.to_owned(),
+            app_env_var: APP_ENV_VAR.to_owned(),
+            env_file_pattern: DEFAULT_ENV_FILE_PATTERN.to_owned(),
+            overlay: None,
+            #[cfg(feature = "substitution")]
+            delimiters: None,
+            #[cfg(feature = "substitution")]
+            check_env_usage: false,
+            strict: false,
+            expand_paths: false,
+            interpolate: false,
+            defaults_table: false,
+            max_file_size: None,
+            document_separator: None,
+            merge_strategy: MergeStrategy::Replace,
+            options: ExpandOptions::default()
+        }
+    }
+
+    /// Loads this file instead of looking up `CONFIG_PATH` or the default file.
+    pub fn path<P: AsRef<Path>>(mut self, path: P) -> ConfigLoader {
+        self.path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// File name to look for when no path is given. Defaults to `Config.toml`.
+    pub fn default_name(mut self, default_name: &str) -> ConfigLoader {
+        self.default_name = default_name.to_owned();
+        self
+    }
+
+    /// Env var naming the deployment environment, used to prefer an environment-specific default
+    /// file. Defaults to `APP_ENV`.
+    pub fn app_env_var(mut self, var: &str) -> ConfigLoader {
+        self.app_env_var = var.to_owned();
+        self
+    }
+
+    /// How the environment-specific default file is named, from the default name's `{stem}` and
+    /// `{ext}` and the environment's `{env}`. Defaults to `{stem}.{env}{ext}`, e.g.
+    /// `Config.production.toml`.
+    pub fn env_file_pattern(mut self, pattern: &str) -> ConfigLoader {
+        self.env_file_pattern = pattern.to_owned();
+        self
+    }
+
+    /// A second file deep-merged over the main one, as in `load_layered_config`. The overlay
+    /// must exist.
+    pub fn overlay<P: AsRef<Path>>(mut self, overlay: P) -> ConfigLoader {
+        self.overlay = Some(overlay.as_ref().to_path_buf());
+        self
+    }
+
+    /// How arrays in both the main file and the overlay are combined. Defaults to replacing the
+    /// main file's array with the overlay's.
+    pub fn merge_strategy(mut self, strategy: MergeStrategy) -> ConfigLoader {
+        self.merge_strategy = strategy;
+        self
+    }
+
+    /// Whether to insert env values that look like booleans or numbers as those types, as in
+    /// `load_config_from_str_coerced`. Off by default.
+    pub fn coerce(mut self, coerce: bool) -> ConfigLoader {
+        self.options.coerce = coerce;
+        self
+    }
+
+    /// Whether to trim leading and trailing whitespace from resolved env and file values. By
+    /// default only `<<FILE:...>>` contents are trimmed, since secrets files often end in a
+    /// newline; `true` also trims env values and `false` leaves file contents untouched.
+    pub fn trim_values(mut self, trim: bool) -> ConfigLoader {
+        self.options.trim = Some(trim);
+        self
+    }
+
+    /// Replaces the `<<` and `>>` placeholder delimiters, e.g. `("${", "}")` for `${ENV:KEY}`.
+    /// The delimiters are matched literally and must be non-empty; invalid delimiters are
+    /// reported when loading. Unquoted placeholders like `debug = <<ENV:DEBUG>>` are only
+    /// recognized with the default delimiters.
+    #[cfg(feature = "substitution")]
+    pub fn delimiters(mut self, prefix: &str, suffix: &str) -> ConfigLoader {
+        self.delimiters = Some((prefix.to_owned(), suffix.to_owned()));
+        self
+    }
+
+    /// Retries a failed `<<CMD:...>>`, `<<KEYRING:...>>`, or custom directive up to `retries`
+    /// times, e.g. so a flaky secret store doesn't fail startup. The first retry waits `backoff`,
+    /// doubling after each attempt. Env and file placeholders are never retried. If every attempt
+    /// fails, the last error is chained with `ErrorKind::DirectiveRetriesExhausted`, which records
+    /// the attempt count. No retries by default.
+    pub fn directive_retries(mut self, retries: u32, backoff: Duration) -> ConfigLoader {
+        self.options.directive_retries = retries;
+        self.options.directive_backoff = backoff;
+        self
+    }
+
+    /// How long a `<<CMD:...>>` command may run before loading fails. Defaults to 10 seconds.
+    #[cfg(feature = "command-substitution")]
+    pub fn command_timeout(mut self, timeout: Duration) -> ConfigLoader {
+        self.options.command_limits.timeout = timeout;
+        self
+    }
+
+    /// The most bytes of output a `<<CMD:...>>` command may print before loading fails. Defaults
+    /// to 64 KiB.
+    #[cfg(feature = "command-substitution")]
+    pub fn command_output_limit(mut self, bytes: usize) -> ConfigLoader {
+        self.options.command_limits.max_output = bytes;
+        self
+    }
+
+    /// Whether unset optional vars like `<<ENV?:KEY>>` are an error, as if they were required. Off
+    /// by default; turning it on in development catches a misconfigured environment while the
+    /// same config keeps them optional in production.
+    ///
+    /// Otherwise an unset optional var removes its key, or its array element, since TOML has no
+    /// null to stand in for it. `Option<T>` fields, including those of tables inside arrays,
+    /// deserialize a removed key as `None`, but a removed element shortens its array; give the
+    /// placeholder a default such as `<<ENV:KEY:->>` to keep every element in place, or use
+    /// `optional_as_null`.
+    pub fn treat_optional_as_required(mut self, require: bool) -> ConfigLoader {
+        self.options.require_optional = require;
+        self
+    }
+
+    /// Whether an unset optional var like `<<ENV?:KEY>>` yields null instead of removing its key,
+    /// so an array element holding one stays in place. Null deserializes as `None` into an
+    /// `Option<T>` field, the same as a removed key does, but it's a type error for any other
+    /// field, where a removed key would fall back to a `#[serde(default)]`. Tables holding nulls
+    /// aren't empty, so `prune_empty_tables` keeps them. Off by default.
+    pub fn optional_as_null(mut self, null: bool) -> ConfigLoader {
+        self.options.optional_as_null = null;
+        self
+    }
+
+    /// Whether to fail with `ErrorKind::EnvVarEmpty` when a required var like `<<ENV:KEY>>` is
+    /// set to an empty string, for fields where that's a misconfiguration. Optional and
+    /// defaulted vars still resolve to the empty string, and with `trim` a var holding only
+    /// whitespace counts as empty. Off by default, so empty values are substituted as is.
+    pub fn reject_empty_required(mut self, reject: bool) -> ConfigLoader {
+        self.options.reject_empty_required = reject;
+        self
+    }
+
+    /// Whether to remove tables whose every key was an unset optional var, so that e.g. a `[tls]`
+    /// section of `<<ENV?:...>>` values deserializes as an `Option<TlsConfig>` of `None` rather
+    /// than failing on its missing fields. Removal cascades to parent tables, and a table inside
+    /// an array is dropped like any other element. Tables written empty are kept. Off by default.
+    pub fn prune_empty_tables(mut self, prune: bool) -> ConfigLoader {
+        self.options.prune_empty_tables = prune;
+        self
+    }
+
+    /// Whether placeholders like `<<ENV:PORT:%05d>>` or `<<ENV?:CODE:%-8s>>` format their value
+    /// printf-style, e.g. for legacy systems that expect fixed-width fields. A spec is
+    /// `%[flags][width][.precision]conversion`, with flags `-`, `0`, `+`, and ` `, and one of
+    /// the conversions `d`, `x`, `X`, `o`, `f`, or `s`; precision applies to `f` and `s` only. An
+    /// invalid spec fails with `ErrorKind::InvalidFormatSpec`, and a value that doesn't parse as
+    /// the conversion's type with `ErrorKind::EnvVarParse`. Formatted values are always strings.
+    /// Off by default, in which case such placeholders are invalid.
+    #[cfg(feature = "substitution")]
+    pub fn printf_format(mut self, enabled: bool) -> ConfigLoader {
+        self.options.printf_format = enabled;
+        self
+    }
+
+    /// Resolves env placeholders from `source` instead of the process environment, e.g. a map in
+    /// tests, or `(overrides, ProcessEnv)` to layer overrides over the real environment.
+    /// `CONFIG_PATH`, the `app_env_var`, and vars in paths expanded with `expand_paths` are read
+    /// from it too.
+    pub fn env_source<E: EnvSource + 'static>(mut self, source: E) -> ConfigLoader {
+        self.options.env = Some(SharedEnvSource(Arc::new(source)));
+        self
+    }
+
+    /// Whether placeholder names may match env vars that differ only in case, e.g. `<<ENV:DB_URL>>`
+    /// reading `db_url`, for platforms with inconsistent casing. An exact match is always
+    /// preferred; among several case variants the first in sorted order wins, which may not be
+    /// the one intended, so avoid setting more than one. Sources that can't list their vars
+    /// only match exactly. Off by default.
+    pub fn case_insensitive_env(mut self, case_insensitive: bool) -> ConfigLoader {
+        self.options.case_insensitive_env = case_insensitive;
+        self
+    }
+
+    /// Whether to fail with `ErrorKind::InconsistentEnvUsage` when a var is required in one place
+    /// and optional in another, e.g. `<<ENV:DB>>` and `<<ENV?:DB>>`, whether or not it's set.
+    /// Catches authoring mistakes in large configs. Off by default.
+    #[cfg(feature = "substitution")]
+    pub fn check_env_usage(mut self, check: bool) -> ConfigLoader {
+        self.check_env_usage = check;
+        self
+    }
+
+    /// Whether to fail with `ErrorKind::UnknownKey` for keys the config type has no field for,
+    /// catching typos that would otherwise be ignored. Off by default.
+    pub fn strict(mut self, strict: bool) -> ConfigLoader {
+        self.strict = strict;
+        self
+    }
+
+    /// Whether to expand a leading `~` and `$VAR` or `${VAR}` references in the config and
+    /// overlay paths, as a shell would, e.g. `~/.config/app.toml`. Off by default. Vars are read
+    /// from the `env_source`, if set, and an unset one fails with `ErrorKind::EnvVarMissing`.
+    ///
+    /// This also applies to a path read from `CONFIG_PATH`, and placeholders like `<<ENV:STAGE>>`
+    /// in any of these paths are expanded first, as in config values (from the `env_source`, if
+    /// set). Shell-style references are expanded in a single pass, so a var whose value contains
+    /// `$VAR` can't recurse.
+    pub fn expand_paths(mut self, expand: bool) -> ConfigLoader {
+        self.expand_paths = expand;
+        self
+    }
+
+    /// Whether to replace `${path.to.key}` references in string values with the config values
+    /// at those key paths once placeholders are expanded, e.g. `url = "${host}:${port}"`.
+    /// `$${` keeps a literal `${`. Fails with `ErrorKind::ReferenceCycle` for values that refer
+    /// back to themselves and `ErrorKind::InvalidReference` for missing keys. Off by default.
+    pub fn interpolate(mut self, interpolate: bool) -> ConfigLoader {
+        self.interpolate = interpolate;
+        self
+    }
+
+    /// Whether a top-level `[defaults]` table supplies values for keys missing from the rest of
+    /// the document, e.g. `[defaults.db] port = 5432`, without `#[serde(default)]` on every
+    /// field. The table is merged underneath the document and removed before placeholders are
+    /// expanded, so placeholders in defaults that the document overrides are never resolved.
+    /// Off by default, leaving `defaults` an ordinary key.
+    pub fn defaults_table(mut self, enabled: bool) -> ConfigLoader {
+        self.defaults_table = enabled;
+        self
+    }
+
+    /// Fails with `ErrorKind::FileTooLarge` rather than reading any config file, overlay, or
+    /// include over `bytes` long, e.g. when the path comes from the environment. Unlimited by
+    /// default.
+    pub fn max_file_size(mut self, bytes: u64) -> ConfigLoader {
+        self.max_file_size = Some(bytes);
+        self
+    }
+
+    /// Splits each file into several documents on lines consisting of just `separator`, e.g.
+    /// `---` for files built by appending fragments, and deep-merges them in order so later
+    /// documents win. Placeholders are expanded after merging. Off by default.
+    pub fn document_separator(mut self, separator: &str) -> ConfigLoader {
+        self.document_separator = Some(separator.to_owned());
+        self
+    }
+
+    /// Loads the configured file, merging in the overlay if one was set.
+    pub fn load<C: DeserializeOwned>(&self) -> Result<C, Error> {
+        let path = self.config_path().map(|path| self.shell_path(&path)).transpose()?;
+        let config_path = resolve_config_path_for_env(path, &self.default_name, &self.app_env_var, &self.env_file_pattern, self.env())?;
+        let mut value = read_config_value(&config_path, self.max_file_size, self.document_separator.as_deref())?;
+        if let Some(ref overlay) = self.overlay {
+            let overlay = self.shell_path(overlay)?;
+            value = merge::merge_with(&self.merge_strategy, value, read_config_value(&overlay, self.max_file_size, self.document_separator.as_deref())?);
+        }
+
+        self.load_value(value)
+    }
+
+    /// Loads every `*.toml` file directly inside `dir` like `load_config_dir`, merging them in
+    /// file name order with the `merge_strategy`. With `MergeStrategy::Concat`, `[[array]]`
+    /// entries from several fragments end up in that order, e.g. every entry of `10-a.toml`
+    /// before those of `20-b.toml`. The path, default name, and overlay are ignored.
+    pub fn load_dir<C: DeserializeOwned, P: AsRef<Path>>(&self, dir: P) -> Result<C, Error> {
+        let mut value = empty_table();
+        for path in config_dir_paths(&self.shell_path(dir.as_ref())?)? {
+            value = merge::merge_with(&self.merge_strategy, value, read_config_value(&path, self.max_file_size, self.document_separator.as_deref())?);
+        }
+
+        self.load_value(value)
+    }
+
+    /// Loads config from a TOML string using these settings. The path, default name, and
+    /// overlay are ignored.
+    pub fn load_str<C: DeserializeOwned>(&self, config_str: &str) -> Result<C, Error> {
+        let value = match self.document_separator {
+            Some(ref separator) => parse_documents(Format::Toml, config_str, separator)?,
+            None => Format::Toml.parse(config_str)?
+        };
+        self.load_value(value)
+    }
+
+    fn load_value<C: DeserializeOwned>(&self, mut value: toml::Value) -> Result<C, Error> {
+        if self.defaults_table {
+            value = merge::apply_defaults(value);
+        }
+        let options = self.expand_options()?;
+        #[cfg(feature = "substitution")]
+        if self.check_env_usage {
+            check_consistent_env_usage(&options.syntax, &value)?;
+        }
+        let expanded = expand::expand(&options, value)?;
+        let nulls = expanded.null_paths().clone();
+        let mut expanded = expanded.into_value();
+        if self.interpolate {
+            expanded = interpolate(expanded)?;
+        }
+        match (self.strict, nulls.is_empty()) {
+            (true, true) => deserialize_strict(expanded),
+            (true, false) => deserialize_strict(NullableValue::new(expanded, &nulls)),
+            (false, true) => Ok(expanded.try_into()?),
+            (false, false) => Ok(C::deserialize(NullableValue::new(expanded, &nulls))?)
+        }
+    }
+
+    /// The configured path, falling back to `CONFIG_PATH` so it's expanded the same way.
+    fn config_path(&self) -> Option<PathBuf> {
+        self.path.clone().or_else(|| self.env().get(CONFIG_PATH_ENV).filter(|path| !path.is_empty()).map(PathBuf::from))
+    }
+
+    /// The `env_source`, or the process environment if none was set.
+    fn env(&self) -> &dyn EnvSource {
+        match self.options.env {
+            Some(SharedEnvSource(ref env)) => env.as_ref(),
+            None => &ProcessEnv
+        }
+    }
+
+    fn shell_path(&self, path: &Path) -> Result<PathBuf, Error> {
+        if !self.expand_paths {
+            return Ok(path.to_path_buf());
+        }
+        #[cfg(feature = "substitution")]
+        let path = &self.expand_path_placeholders(path)?;
+        expand_path(path, self.env())
+    }
+
+    /// Expands placeholders in `path` as if it were a config value at that key path, so errors
+    /// name the path.
+    #[cfg(feature = "substitution")]
+    fn expand_path_placeholders(&self, path: &Path) -> Result<PathBuf, Error> {
+        let s = match path.to_str() {
+            Some(s) => s,
+            None => return Ok(path.to_path_buf())
+        };
+        Ok(PathBuf::from(match expand::expand_at(&self.expand_options()?, s, toml::Value::String(s.to_owned()))? {
+            Some(toml::Value::String(expanded)) => expanded,
+            Some(other) => other.to_string(),
+            None => String::new()
+        }))
+    }
+
+    fn expand_options(&self) -> Result<ExpandOptions, Error> {
+        #[cfg(feature = "substitution")]
+        if let Some((ref prefix, ref suffix)) = self.delimiters {
+            return Ok(ExpandOptions { syntax: Syntax::new(prefix, suffix)?, ..self.options.clone() });
+        }
+        Ok(self.options.clone())
+    }
+}
+
+impl Default for ConfigLoader {
+    fn default() -> ConfigLoader {
+        ConfigLoader::new()
+    }
+}
+
+/// Expands a leading `~` to the home directory and `$VAR` or `${VAR}` to the var's value in
+/// `env`. Paths that aren't valid unicode are left alone.
+fn expand_path(path: &Path, env: &dyn EnvSource) -> Result<PathBuf, Error> {
+    let s = match path.to_str() {
+        Some(s) => s,
+        None => return Ok(path.to_path_buf())
+    };
+    let var = |key: &str| env.get(key).ok_or_else(|| Error::from(ErrorKind::EnvVarMissing(key.to_owned(), s.to_owned())));
+
+    let mut expanded = String::new();
+    let mut rest = s;
+    if rest == "~" || rest.starts_with("~/") || rest.starts_with("~\\") {
+        expanded.push_str(&var(if cfg!(windows) { "USERPROFILE" } else { "HOME" })?);
+        rest = &rest[1..];
+    }
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        let (key, after) = match rest.strip_prefix('{').and_then(|braced| braced.split_once('}')) {
+            Some((key, after)) => (key, after),
+            None => {
+                let end = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+                (&rest[..end], &rest[end..])
+            }
+        };
+        if key.is_empty() {
+            expanded.push('$');
+        } else {
+            expanded.push_str(&var(key)?);
+            rest = after;
+        }
+    }
+    expanded.push_str(rest);
+    Ok(PathBuf::from(expanded))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{expand_path, ConfigLoader};
+    use crate::{ErrorKind, MergeStrategy};
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
+    #[derive(Debug, Deserialize)]
+    struct Config {
+        name: String,
+        port: i64,
+        debug: Option<bool>
+    }
+
+    fn env_vars(vars: &[(&str, &str)]) -> HashMap<String, String> {
+        vars.iter().map(|&(key, value)| (key.to_owned(), value.to_owned())).collect()
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_loads_with_overlay_and_coercion() {
+        let base_path = env::temp_dir().join("config_loader_builder_base.toml");
+        let overlay_path = env::temp_dir().join("config_loader_builder_overlay.toml");
+        fs::write(&base_path, r#"
+            name = "base"
+            port = "<<ENV:BUILDER_PORT>>"
+        "#).unwrap();
+        fs::write(&overlay_path, r#"
+            debug = "<<ENV:BUILDER_DEBUG>>"
+        "#).unwrap();
+
+        let vars = env_vars(&[("BUILDER_PORT", "8080"), ("BUILDER_DEBUG", "true")]);
+
+        let config: Config = ConfigLoader::new()
+            .env_source(vars.clone())
+            .path(&base_path)
+            .overlay(&overlay_path)
+            .coerce(true)
+            .load()
+            .unwrap();
+        assert_eq!(&config.name, "base");
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.debug, Some(true));
+
+        assert!(ConfigLoader::new().env_source(vars).path(&base_path).load::<Config>().is_err());
+
+        fs::remove_file(base_path).unwrap();
+        fs::remove_file(overlay_path).unwrap();
+    }
+
+    #[cfg(feature = "substitution")]
+    #[test]
+    fn it_loads_with_custom_delimiters() {
+        let config_str = r#"
+            name = "${ENV:DELIMITER_NAME}-<<ENV:DELIMITER_NAME>>"
+            port = "${ENV:DELIMITER_PORT}"
+            debug = "${ENV?:DELIMITER_UNSET}"
+        "#;
+
+        let vars = env_vars(&[("DELIMITER_NAME", "name"), ("DELIMITER_PORT", "80")]);
+
+        let config: Config = ConfigLoader::new()
+            .env_source(vars.clone())
+            .delimiters("${", "}")
+            .coerce(true)
+            .load_str(config_str)
+            .unwrap();
+        assert_eq!(&config.name, "name-<<ENV:DELIMITER_NAME>>");
+        assert_eq!(config.port, 80);
+        assert_eq!(config.debug, None);
+
+        assert!(ConfigLoader::new().env_source(vars).delimiters("", "}").load_str::<Config>(config_str).is_err());
+    }
+
+    #[cfg(feature = "substitution")]
+    #[test]
+    fn it_does_not_strip_delimiter_chars_from_env_names() {
+        let config_str = r#"
+            name = "{{ENV:TRIM.NAME--}"
+            port = 1
+            debug = "{{ENV?:TRIM.DEBUG---}"
+        "#;
+
+        let vars = env_vars(&[("TRIM.NAME-", "exact name"), ("TRIM.NAME", "stripped name"), ("TRIM.DEBUG--", "true")]);
+
+        let config: Config = ConfigLoader::new()
+            .env_source(vars)
+            .delimiters("{{", "-}")
+            .coerce(true)
+            .load_str(config_str)
+            .unwrap();
+        assert_eq!(&config.name, "exact name");
+        assert_eq!(config.debug, Some(true));
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_trims_values_when_requested() {
+        let secret_path = env::temp_dir().join("config_loader_builder_trim_secret");
+        fs::write(&secret_path, "secret\n").unwrap();
+        let config_str = format!(r#"
+            name = "<<FILE:{}>>"
+            port = "<<ENV:TRIM_VALUES_PORT>>"
+        "#, secret_path.display());
+
+        let vars = env_vars(&[("TRIM_VALUES_PORT", " 8080\n")]);
+
+        let config: Config = ConfigLoader::new()
+            .env_source(vars.clone())
+            .trim_values(true)
+            .coerce(true)
+            .load_str(&config_str)
+            .unwrap();
+        assert_eq!(&config.name, "secret");
+        assert_eq!(config.port, 8080);
+
+        // By default only file contents are trimmed.
+        assert!(ConfigLoader::new().env_source(vars).coerce(true).load_str::<Config>(&config_str).is_err());
+
+        let file_only_str = config_str.replace("\"<<ENV:TRIM_VALUES_PORT>>\"", "1");
+        let config: Config = ConfigLoader::new().load_str(&file_only_str).unwrap();
+        assert_eq!(&config.name, "secret");
+
+        let config: Config = ConfigLoader::new().trim_values(false).load_str(&file_only_str).unwrap();
+        assert_eq!(&config.name, "secret\n");
+
+        fs::remove_file(secret_path).unwrap();
+    }
+
+    #[test]
+    fn it_rejects_unknown_keys_when_strict() {
+        let config_str = r#"
+            name = "strict"
+            prot = 80
+            port = 80
+        "#;
+
+        let config: Config = ConfigLoader::new().load_str(config_str).unwrap();
+        assert_eq!(config.port, 80);
+
+        match *ConfigLoader::new().strict(true).load_str::<Config>(config_str).unwrap_err().kind() {
+            ErrorKind::UnknownKey(ref path) => assert_eq!(path, "prot"),
+            ref other => panic!("Expected unknown key, got {:?}", other)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_resolves_from_custom_env_source() {
+        let config_str = r#"
+            name = "<<ENV:SOURCE_NAME>>"
+            port = "<<ENV:SOURCE_PORT>>"
+        "#;
+
+        let base = env_vars(&[("SOURCE_NAME", "from base"), ("SOURCE_PORT", "80")]);
+        let overrides = env_vars(&[("SOURCE_PORT", "8080")]);
+
+        let config: Config = ConfigLoader::new()
+            .env_source((overrides.clone(), base))
+            .coerce(true)
+            .load_str(config_str)
+            .unwrap();
+        assert_eq!(&config.name, "from base");
+        assert_eq!(config.port, 8080);
+
+        assert!(ConfigLoader::new().env_source(overrides).load_str::<Config>(config_str).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_treats_optional_as_required_when_requested() {
+        let config_str = r#"
+            name = "name"
+            port = 1
+            debug = "<<ENV?:PROMOTED_UNSET>>"
+        "#;
+
+        let config: Config = ConfigLoader::new().load_str(config_str).unwrap();
+        assert_eq!(config.debug, None);
+
+        match *ConfigLoader::new().treat_optional_as_required(true).load_str::<Config>(config_str).unwrap_err().kind() {
+            ErrorKind::EnvVarMissing(ref key, ref path) => {
+                assert_eq!(key, "PROMOTED_UNSET");
+                assert_eq!(path, "debug");
+            },
+            ref other => panic!("Expected missing env var, got {:?}", other)
+        }
+    }
+
+    #[cfg(all(feature = "command-substitution", unix))]
+    #[test]
+    fn it_substitutes_command_output() {
+        let config_str = r#"
+            name = "<<CMD:echo from command>>"
+            port = 1
+        "#;
+
+        let config: Config = ConfigLoader::new().load_str(config_str).unwrap();
+        assert_eq!(&config.name, "from command");
+
+        let err = ConfigLoader::new()
+            .command_timeout(std::time::Duration::from_millis(50))
+            .load_str::<Config>(&config_str.replace("echo from command", "sleep 5"))
+            .unwrap_err();
+        match *err.kind() {
+            ErrorKind::CommandFailed(ref command, _) => assert_eq!(command, "sleep 5"),
+            ref other => panic!("Expected command failure, got {:?}", other)
+        }
+
+        let err = ConfigLoader::new().command_output_limit(4).load_str::<Config>(config_str).unwrap_err();
+        assert!(err.to_string().contains("exceeded 4 bytes"));
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_drops_unset_optional_values_in_arrays() {
+        #[derive(Debug, Deserialize)]
+        struct Server {
+            host: String,
+            port: Option<i64>
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct Servers {
+            servers: Vec<Server>,
+            tags: Vec<String>
+        }
+
+        let config_str = r#"
+            tags = ["a", "<<ENV?:DROPPED_UNSET>>", "<<ENV:DROPPED_UNSET:->>"]
+            [[servers]]
+            host = "a"
+            port = "<<ENV_INT?:DROPPED_UNSET>>"
+        "#;
+
+        let config: Servers = ConfigLoader::new().load_str(config_str).unwrap();
+        assert_eq!(&config.servers[0].host, "a");
+        assert_eq!(config.servers[0].port, None);
+        assert_eq!(config.tags, vec!["a", ""]);
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_keeps_unset_optional_values_as_null_when_requested() {
+        #[derive(Debug, Deserialize)]
+        struct Servers {
+            servers: Vec<Option<String>>,
+            port: Option<i64>,
+            name: String
+        }
+
+        let config_str = r#"
+            servers = ["<<ENV?:PRIMARY>>", "<<ENV?:SECONDARY>>"]
+            port = "<<ENV_INT?:PORT>>"
+            name = "<<ENV?:NAME>>"
+        "#;
+        let loader = ConfigLoader::new().env_source(env_vars(&[("SECONDARY", "b")])).optional_as_null(true);
+
+        assert!(loader.clone().load_str::<Servers>(config_str).is_err());
+        let config: Servers = loader.clone().load_str(&config_str.replace("<<ENV?:NAME>>", "app")).unwrap();
+        assert_eq!(config.servers, vec![None, Some("b".to_owned())]);
+        assert_eq!(config.port, None);
+        assert_eq!(&config.name, "app");
+
+        let config: Servers = loader.strict(true).load_str(&config_str.replace("<<ENV?:NAME>>", "app")).unwrap();
+        assert_eq!(config.servers, vec![None, Some("b".to_owned())]);
+    }
+
+    #[test]
+    fn it_limits_config_file_size() {
+        let config_path = env::temp_dir().join("config_loader_max_size.toml");
+        fs::write(&config_path, "name = \"name\"\nport = 1\n").unwrap();
+
+        let config: Config = ConfigLoader::new().path(&config_path).max_file_size(64).load().unwrap();
+        assert_eq!(config.port, 1);
+        assert_eq!(config.debug, None);
+
+        match *ConfigLoader::new().path(&config_path).max_file_size(8).load::<Config>().unwrap_err().kind() {
+            ErrorKind::FileTooLarge(ref path, limit) => {
+                assert_eq!(path, &config_path);
+                assert_eq!(limit, 8);
+            },
+            ref other => panic!("Expected file too large, got {:?}", other)
+        }
+
+        fs::remove_file(config_path).unwrap();
+    }
+
+    #[test]
+    fn it_expands_home_and_env_vars_in_paths() {
+        let home_var = if cfg!(windows) { "USERPROFILE" } else { "HOME" };
+        let vars = env_vars(&[(home_var, "/home/app"), ("SHELL_PATH_DIR", "configs")]);
+
+        assert_eq!(expand_path(Path::new("~/.config/app.toml"), &vars).unwrap(), Path::new("/home/app/.config/app.toml"));
+        assert_eq!(expand_path(Path::new("/etc/${SHELL_PATH_DIR}/$SHELL_PATH_DIR.toml"), &vars).unwrap(), Path::new("/etc/configs/configs.toml"));
+        assert_eq!(expand_path(Path::new("/etc/~app/$/a${b"), &vars).unwrap(), Path::new("/etc/~app/$/a${b"));
+        match *expand_path(Path::new("$SHELL_PATH_UNSET/app.toml"), &vars).unwrap_err().kind() {
+            ErrorKind::EnvVarMissing(ref key, _) => assert_eq!(key, "SHELL_PATH_UNSET"),
+            ref other => panic!("Expected missing env var, got {:?}", other)
+        }
+
+        let config_path = env::temp_dir().join("config_loader_shell_path.toml");
+        fs::write(&config_path, "name = \"name\"\nport = 1\n").unwrap();
+        let tmp = env::temp_dir();
+        let vars = env_vars(&[("SHELL_PATH_TMP", tmp.to_str().unwrap())]);
+
+        let loader = ConfigLoader::new().env_source(vars).path("$SHELL_PATH_TMP/config_loader_shell_path.toml");
+        assert!(loader.clone().load::<Config>().is_err());
+        assert_eq!(loader.expand_paths(true).load::<Config>().unwrap().port, 1);
+
+        fs::remove_file(config_path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_matches_env_names_case_insensitively_when_requested() {
+        let config_str = r#"
+            name = "<<ENV:CASE_NAME>>"
+            port = "<<ENV_INT:case_port>>"
+        "#;
+
+        let mut vars = HashMap::new();
+        vars.insert("case_name".to_owned(), "lower".to_owned());
+        vars.insert("Case_Name".to_owned(), "mixed".to_owned());
+        vars.insert("case_port".to_owned(), "1".to_owned());
+        vars.insert("CASE_PORT".to_owned(), "2".to_owned());
+
+        assert!(ConfigLoader::new().env_source(vars.clone()).load_str::<Config>(config_str).is_err());
+
+        let config: Config = ConfigLoader::new().env_source(vars).case_insensitive_env(true).load_str(config_str).unwrap();
+        assert_eq!(&config.name, "mixed");
+        assert_eq!(config.port, 1);
+    }
+
+    #[cfg(feature = "substitution")]
+    #[test]
+    fn it_retries_failed_directives() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        crate::register_directive("TEST_FLAKY", Box::new(|arg| {
+            if CALLS.fetch_add(1, Ordering::SeqCst) % 3 == 2 {
+                Ok(arg.to_owned())
+            } else {
+                Err(ErrorKind::Validation("unavailable".to_owned()).into())
+            }
+        }));
+        let config_str = "name = \"<<TEST_FLAKY:flaky>>\"\nport = 1\n";
+
+        let config: Config = ConfigLoader::new().directive_retries(2, Duration::from_millis(1)).load_str(config_str).unwrap();
+        assert_eq!(&config.name, "flaky");
+
+        let err = ConfigLoader::new().directive_retries(1, Duration::from_millis(1)).load_str::<Config>(config_str).unwrap_err();
+        match *err.kind() {
+            ErrorKind::DirectiveRetriesExhausted(ref placeholder, attempts) => {
+                assert_eq!(placeholder, "<<TEST_FLAKY:flaky>>");
+                assert_eq!(attempts, 2);
+            },
+            ref other => panic!("Expected exhausted retries, got {:?}", other)
+        }
+        assert_eq!(err.iter().nth(1).unwrap().to_string(), "Invalid config: unavailable");
+    }
+
+    #[test]
+    fn it_interpolates_config_references_when_requested() {
+        let config_str = "name = \"${prefix}-app\"\nprefix = \"my\"\nport = 1\n";
+        let config: Config = ConfigLoader::new().interpolate(true).load_str(config_str).unwrap();
+        assert_eq!(&config.name, "my-app");
+
+        let config: Config = ConfigLoader::new().load_str(config_str).unwrap();
+        assert_eq!(&config.name, "${prefix}-app");
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_merges_documents_split_on_a_separator() {
+        let config_str = "name = \"first\"\nport = 1\n---\n\n---\nname = \"<<ENV:SEPARATED_NAME>>\"\n";
+        let vars = env_vars(&[("SEPARATED_NAME", "second")]);
+
+        let config: Config = ConfigLoader::new().env_source(vars.clone()).document_separator("---").load_str(config_str).unwrap();
+        assert_eq!(&config.name, "second");
+        assert_eq!(config.port, 1);
+
+        assert!(ConfigLoader::new().env_source(vars).load_str::<Config>(config_str).is_err());
+    }
+
+    #[cfg(feature = "substitution")]
+    #[test]
+    fn it_checks_env_usage_when_requested() {
+        let config_str = "name = \"<<ENV:USAGE_NAME:-app>>\"\nport = 1\nalias = \"<<ENV:USAGE_NAME>>\"\n";
+        let vars = env_vars(&[("USAGE_NAME", "set")]);
+
+        assert!(ConfigLoader::new().env_source(vars.clone()).load_str::<Config>(config_str).is_ok());
+        match *ConfigLoader::new().env_source(vars).check_env_usage(true).load_str::<Config>(config_str).unwrap_err().kind() {
+            ErrorKind::InconsistentEnvUsage(ref key, _, _) => assert_eq!(key, "USAGE_NAME"),
+            ref other => panic!("Expected inconsistent env usage, got {:?}", other)
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[cfg(feature = "substitution")]
+    struct Tls {
+        cert: String,
+        key: String
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[cfg(feature = "substitution")]
+    struct TlsConfig {
+        name: String,
+        tls: Option<Tls>,
+        empty: Option<HashMap<String, String>>
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_prunes_tables_emptied_by_unset_vars_when_requested() {
+        let config_str = r#"
+            name = "app"
+            [empty]
+            [tls]
+            cert = "<<ENV?:PRUNE_CERT>>"
+            [tls.files]
+            key = "<<ENV?:PRUNE_KEY>>"
+        "#;
+
+        assert!(ConfigLoader::new().env_source(env_vars(&[])).load_str::<TlsConfig>(config_str).is_err());
+
+        let config: TlsConfig = ConfigLoader::new().env_source(env_vars(&[])).prune_empty_tables(true).load_str(config_str).unwrap();
+        assert_eq!(&config.name, "app");
+        assert!(config.tls.is_none());
+        assert!(config.empty.unwrap().is_empty());
+
+        let config_str = "name = \"app\"\n[tls]\ncert = \"<<ENV?:PRUNE_CERT>>\"\nkey = \"<<ENV:PRUNE_KEY:-key.pem>>\"\n";
+        let vars = env_vars(&[("PRUNE_CERT", "cert.pem")]);
+        let tls = ConfigLoader::new().env_source(vars).prune_empty_tables(true).load_str::<TlsConfig>(config_str).unwrap().tls.unwrap();
+        assert_eq!((tls.cert.as_str(), tls.key.as_str()), ("cert.pem", "key.pem"));
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[cfg(feature = "substitution")]
+    struct LegacyConfig {
+        port: String,
+        code: Option<String>,
+        rate: String
+    }
+
+    #[cfg(feature = "substitution")]
+    #[test]
+    fn it_formats_env_values_printf_style_when_requested() {
+        let config_str = "port = <<ENV:PRINTF_PORT:%05d>>\ncode = \"<<ENV?:PRINTF_CODE:%-4s>>\"\nrate = \"<<ENV:PRINTF_RATE:%.2f>>\"\n";
+        let vars = env_vars(&[("PRINTF_PORT", "80"), ("PRINTF_RATE", "0.125")]);
+
+        assert!(ConfigLoader::new().env_source(vars.clone()).load_str::<LegacyConfig>(config_str).is_err());
+
+        let config: LegacyConfig = ConfigLoader::new().env_source(vars.clone()).printf_format(true).coerce(true).load_str(config_str).unwrap();
+        assert_eq!(&config.port, "00080");
+        assert_eq!(config.code, None);
+        assert_eq!(&config.rate, "0.12");
+
+        let invalid_str = config_str.replace("%05d", "%05q");
+        match *ConfigLoader::new().env_source(vars.clone()).printf_format(true).load_str::<LegacyConfig>(&invalid_str).unwrap_err().kind() {
+            ErrorKind::InvalidFormatSpec(ref spec, ref path, _) => assert_eq!((spec.as_str(), path.as_str()), ("%05q", "port")),
+            ref other => panic!("Expected invalid format spec, got {:?}", other)
+        }
+
+        let vars = env_vars(&[("PRINTF_PORT", "eighty"), ("PRINTF_RATE", "0.125")]);
+        match *ConfigLoader::new().env_source(vars).printf_format(true).load_str::<LegacyConfig>(config_str).unwrap_err().kind() {
+            ErrorKind::EnvVarParse(ref key, _, ref expected, _) => assert_eq!((key.as_str(), expected.as_str()), ("PRINTF_PORT", "an integer")),
+            ref other => panic!("Expected env var parse error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "substitution")]
+    fn it_rejects_empty_required_vars_when_requested() {
+        let vars = env_vars(&[("EMPTY_NAME", ""), ("EMPTY_DEBUG", "")]);
+        let config_str = "name = \"<<ENV:EMPTY_NAME>>\"\nport = 1\nnote = \"<<ENV?:EMPTY_DEBUG>>\"\n";
+
+        let config: Config = ConfigLoader::new().env_source(vars.clone()).load_str(config_str).unwrap();
+        assert_eq!(&config.name, "");
+
+        let err = ConfigLoader::new().env_source(vars.clone()).reject_empty_required(true).load_str::<Config>(config_str).unwrap_err();
+        match *err.kind() {
+            ErrorKind::EnvVarEmpty(ref key, ref path) => assert_eq!((key.as_str(), path.as_str()), ("EMPTY_NAME", "name")),
+            ref other => panic!("Expected empty env var, got {:?}", other)
+        }
+
+        let config_str = "name = \"<<ENV:EMPTY_NAME:-default>>\"\nport = 1\nnote = \"<<ENV?:EMPTY_DEBUG>>\"\n";
+        let config: Config = ConfigLoader::new().env_source(vars).reject_empty_required(true).load_str(config_str).unwrap();
+        assert_eq!(&config.name, "");
+    }
+
+    #[test]
+    fn it_resolves_the_config_path_from_the_env_source() {
+        let dir = env::temp_dir().join("config_loader_env_source_path");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("app.toml"), "name = \"default\"\nport = 1\n").unwrap();
+        fs::write(dir.join("app.staging.toml"), "name = \"staging\"\nport = 2\n").unwrap();
+        let config_path = dir.join("app.toml");
+
+        let vars = env_vars(&[("CONFIG_PATH", config_path.to_str().unwrap())]);
+        let config: Config = ConfigLoader::new().env_source(vars).load().unwrap();
+        assert_eq!(&config.name, "default");
+
+        let vars = env_vars(&[("ENV_SOURCE_PATH_DIR", dir.to_str().unwrap())]);
+        let config: Config = ConfigLoader::new()
+            .env_source(vars)
+            .path("$ENV_SOURCE_PATH_DIR/app.toml")
+            .expand_paths(true)
+            .load()
+            .unwrap();
+        assert_eq!(&config.name, "default");
+
+        let config: Config = ConfigLoader::new()
+            .env_source(env_vars(&[("ENV_SOURCE_STAGE", "staging")]))
+            .default_name(config_path.to_str().unwrap())
+            .app_env_var("ENV_SOURCE_STAGE")
+            .load()
+            .unwrap();
+        assert_eq!(&config.name, "staging");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "substitution")]
+    #[test]
+    fn it_expands_placeholders_in_paths() {
+        let config_path = env::temp_dir().join("config_loader_staged.toml");
+        fs::write(&config_path, "name = \"staged\"\nport = 1\n").unwrap();
+        let tmp = env::temp_dir();
+        let vars = env_vars(&[("PATH_PLACEHOLDER_TMP", tmp.to_str().unwrap()), ("PATH_PLACEHOLDER_STAGE", "staged")]);
+
+        let loader = ConfigLoader::new().env_source(vars.clone()).path("${PATH_PLACEHOLDER_TMP}/config_loader_<<ENV:PATH_PLACEHOLDER_STAGE>>.toml");
+        assert!(loader.clone().load::<Config>().is_err());
+        assert_eq!(&loader.expand_paths(true).load::<Config>().unwrap().name, "staged");
+
+        let err = ConfigLoader::new().env_source(vars).path("/etc/<<ENV:PATH_PLACEHOLDER_UNSET>>.toml").expand_paths(true).load::<Config>().unwrap_err();
+        match *err.kind() {
+            ErrorKind::EnvVarMissing(ref key, ref path) => assert_eq!((key.as_str(), path.as_str()), ("PATH_PLACEHOLDER_UNSET", "/etc/<<ENV:PATH_PLACEHOLDER_UNSET>>.toml")),
+            ref other => panic!("Expected missing env var, got {:?}", other)
+        }
+
+        fs::remove_file(config_path).unwrap();
+    }
+
+    #[test]
+    fn it_merges_directory_fragments_in_file_name_order() {
+        #[derive(Debug, Deserialize)]
+        struct Server {
+            name: String,
+            port: i64
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct Servers {
+            servers: Vec<Server>
+        }
+
+        let dir = env::temp_dir().join("config_loader_fragments.d");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("20-b.toml"), "[[servers]]\nname = \"b\"\nport = 2\n[[servers]]\nname = \"a\"\nport = 20\n").unwrap();
+        fs::write(dir.join("10-a.toml"), "[[servers]]\nname = \"a\"\nport = 1\n").unwrap();
+        fs::write(dir.join("30-c.toml"), "[[servers]]\nname = \"c\"\nport = 3\n").unwrap();
+        let servers = |strategy: MergeStrategy| {
+            let config: Servers = ConfigLoader::new().merge_strategy(strategy).load_dir(&dir).unwrap();
+            config.servers.into_iter().map(|server| (server.name, server.port)).collect::<Vec<_>>()
+        };
+        let pairs = |pairs: &[(&str, i64)]| pairs.iter().map(|&(name, port)| (name.to_owned(), port)).collect::<Vec<_>>();
+
+        assert_eq!(servers(MergeStrategy::Replace), pairs(&[("c", 3)]));
+        assert_eq!(servers(MergeStrategy::Concat), pairs(&[("a", 1), ("b", 2), ("a", 20), ("c", 3)]));
+        assert_eq!(servers(MergeStrategy::MergeByKey("name".to_owned())), pairs(&[("a", 20), ("b", 2), ("c", 3)]));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_fills_missing_keys_from_defaults_table_when_requested() {
+        let config_str = "name = \"app\"\n[defaults]\nport = 8080\nname = \"<<ENV:DEFAULTS_TABLE_UNSET>>\"\n";
+
+        let config: Config = ConfigLoader::new().defaults_table(true).load_str(config_str).unwrap();
+        assert_eq!(&config.name, "app");
+        assert_eq!(config.port, 8080);
+
+        assert!(ConfigLoader::new().load_str::<Config>(config_str).is_err());
+    }
+}
@@ -0,0 +1,218 @@
+
+/// How arrays present in both configs are combined when merging. Tables are always merged key
+/// by key, and other values are always replaced by the overlay's. Every strategy is
+/// deterministic: merging the same configs in the same order always gives the same arrays.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum MergeStrategy {
+    /// The overlay's array replaces the base array wholesale.
+    #[default]
+    Replace,
+    /// The overlay's elements are appended to the base array, so each config's elements stay
+    /// together, in the order the configs were merged.
+    Concat,
+    /// Arrays of tables are matched up by the value of the given key, e.g. `name` for
+    /// `[[servers]]` entries. Matching elements are deep-merged, with the overlay taking
+    /// precedence, and keep the base element's position; overlay elements without a match (or
+    /// without the key) are appended in order.
+    MergeByKey(String)
+}
+
+/// Top-level table whose values fill in keys missing from the rest of the document, when
+/// enabled with `ConfigLoader::defaults_table`.
+pub const DEFAULTS_KEY: &str = "defaults";
+
+/// Removes the top-level `[defaults]` table from `value` and deep-merges the rest of the
+/// document over it, so every key the document sets wins. A `defaults` key that isn't a table
+/// is left in place.
+pub fn apply_defaults(value: toml::Value) -> toml::Value {
+    let mut table = match value {
+        toml::Value::Table(table) => table,
+        other => return other
+    };
+    match table.remove(DEFAULTS_KEY) {
+        Some(defaults @ toml::Value::Table(_)) => merge(defaults, toml::Value::Table(table)),
+        Some(other) => {
+            table.insert(DEFAULTS_KEY.to_owned(), other);
+            toml::Value::Table(table)
+        },
+        None => toml::Value::Table(table)
+    }
+}
+
+/// Deep-merges `overlay` into `base`, with `overlay` taking precedence.
+///
+/// Tables are merged key by key, recursing into tables present on both sides. Everything else,
+/// including arrays, is replaced wholesale by the overlay's value: arrays are never concatenated.
+pub fn merge(base: toml::Value, overlay: toml::Value) -> toml::Value {
+    merge_with(&MergeStrategy::Replace, base, overlay)
+}
+
+/// Like `merge`, but combines arrays according to `strategy`.
+pub fn merge_with(strategy: &MergeStrategy, base: toml::Value, overlay: toml::Value) -> toml::Value {
+    match (base, overlay) {
+        (toml::Value::Table(mut base_table), toml::Value::Table(overlay_table)) => {
+            for (k, overlay_v) in overlay_table {
+                let merged = match base_table.remove(&k) {
+                    Some(base_v) => merge_with(strategy, base_v, overlay_v),
+                    None => overlay_v
+                };
+                base_table.insert(k, merged);
+            }
+            toml::Value::Table(base_table)
+        },
+        (toml::Value::Array(base_values), toml::Value::Array(overlay_values)) =>
+            toml::Value::Array(merge_arrays(strategy, base_values, overlay_values)),
+        (_, overlay) =>
+            overlay
+    }
+}
+
+fn merge_arrays(strategy: &MergeStrategy, mut base: Vec<toml::Value>, overlay: Vec<toml::Value>) -> Vec<toml::Value> {
+    let key = match *strategy {
+        MergeStrategy::Replace => return overlay,
+        MergeStrategy::Concat => {
+            base.extend(overlay);
+            return base;
+        },
+        MergeStrategy::MergeByKey(ref key) => key
+    };
+
+    for overlay_v in overlay {
+        let matching = overlay_v.get(key).and_then(|id| {
+            base.iter().position(|base_v| base_v.is_table() && base_v.get(key) == Some(id))
+        });
+        match matching {
+            Some(i) => {
+                let base_v = base.remove(i);
+                base.insert(i, merge_with(strategy, base_v, overlay_v));
+            },
+            None => base.push(overlay_v)
+        }
+    }
+    base
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_defaults, merge, merge_with, MergeStrategy};
+
+    #[test]
+    fn it_merges_tables_recursively() {
+        let base: toml::Value = toml::from_str(r#"
+            name = "base"
+            hosts = ["a", "b"]
+            [db]
+            user = "base user"
+            pass = "base pass"
+            [db.pool]
+            size = 5
+        "#).unwrap();
+        let overlay: toml::Value = toml::from_str(r#"
+            hosts = ["c"]
+            [db]
+            pass = "overlay pass"
+            [db.pool]
+            timeout = 30
+            [extra]
+            enabled = true
+        "#).unwrap();
+        let expected: toml::Value = toml::from_str(r#"
+            name = "base"
+            hosts = ["c"]
+            [db]
+            user = "base user"
+            pass = "overlay pass"
+            [db.pool]
+            size = 5
+            timeout = 30
+            [extra]
+            enabled = true
+        "#).unwrap();
+
+        assert_eq!(merge(base, overlay), expected);
+    }
+
+    #[test]
+    fn it_replaces_mismatched_types() {
+        let base: toml::Value = toml::from_str(r#"
+            db = "a string"
+            port = { number = 80 }
+        "#).unwrap();
+        let overlay: toml::Value = toml::from_str(r#"
+            db = { url = "a table" }
+            port = 8080
+        "#).unwrap();
+
+        let merged = merge(base, overlay);
+        assert_eq!(merged["db"]["url"].as_str(), Some("a table"));
+        assert_eq!(merged["port"].as_integer(), Some(8080));
+    }
+
+    #[test]
+    fn it_merges_arrays_by_strategy() {
+        let base: toml::Value = toml::from_str(r#"
+            hosts = ["a", "b"]
+            [[servers]]
+            name = "primary"
+            host = "base primary"
+            port = 80
+            [[servers]]
+            name = "secondary"
+            host = "base secondary"
+        "#).unwrap();
+        let overlay: toml::Value = toml::from_str(r#"
+            hosts = ["c"]
+            [[servers]]
+            name = "primary"
+            host = "overlay primary"
+            [[servers]]
+            name = "tertiary"
+            host = "overlay tertiary"
+        "#).unwrap();
+
+        let replaced = merge_with(&MergeStrategy::Replace, base.clone(), overlay.clone());
+        assert_eq!(replaced, merge(base.clone(), overlay.clone()));
+        assert_eq!(replaced["servers"].as_array().unwrap().len(), 2);
+        assert_eq!(replaced["hosts"], toml::Value::Array(vec!["c".into()]));
+
+        let concatenated = merge_with(&MergeStrategy::Concat, base.clone(), overlay.clone());
+        assert_eq!(concatenated["hosts"], toml::Value::Array(vec!["a".into(), "b".into(), "c".into()]));
+        assert_eq!(concatenated["servers"].as_array().unwrap().len(), 4);
+
+        let by_key = merge_with(&MergeStrategy::MergeByKey("name".to_owned()), base, overlay);
+        let servers = by_key["servers"].as_array().unwrap();
+        assert_eq!(servers.len(), 3);
+        assert_eq!(servers[0]["host"].as_str(), Some("overlay primary"));
+        assert_eq!(servers[0]["port"].as_integer(), Some(80));
+        assert_eq!(servers[1]["host"].as_str(), Some("base secondary"));
+        assert_eq!(servers[2]["name"].as_str(), Some("tertiary"));
+        assert_eq!(by_key["hosts"], toml::Value::Array(vec!["a".into(), "b".into(), "c".into()]));
+    }
+
+    #[test]
+    fn it_fills_missing_keys_from_defaults() {
+        let value: toml::Value = toml::from_str(r#"
+            name = "app"
+            [db]
+            host = "db"
+            [defaults]
+            name = "default"
+            timeout = 30
+            [defaults.db]
+            host = "localhost"
+            port = 5432
+        "#).unwrap();
+        let expected: toml::Value = toml::from_str(r#"
+            name = "app"
+            timeout = 30
+            [db]
+            host = "db"
+            port = 5432
+        "#).unwrap();
+
+        assert_eq!(apply_defaults(value), expected);
+
+        let not_a_table: toml::Value = toml::from_str("defaults = true").unwrap();
+        assert_eq!(apply_defaults(not_a_table.clone()), not_a_table);
+    }
+}
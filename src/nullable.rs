@@ -0,0 +1,198 @@
+use std::collections::BTreeSet;
+use serde::de::{self, DeserializeSeed, Deserializer, IntoDeserializer, MapAccess, SeqAccess, Visitor};
+use crate::expand::key_path;
+
+/// Deserializes a `toml::Value` like its own deserializer, except that the values at the key
+/// paths in `nulls`, such as `servers[1].port`, deserialize as null: `None` for an `Option`
+/// field, and unit for anything else, so e.g. a `String` field fails with a type error.
+pub struct NullableValue<'n> {
+    value: toml::Value,
+    path: String,
+    nulls: &'n BTreeSet<String>,
+    unresolved: bool
+}
+
+/// The message of the error `NullableValue::unresolved` fails with for a value that doesn't fit.
+pub const UNRESOLVED: &str = "value is an unresolved placeholder";
+
+impl<'n> NullableValue<'n> {
+    pub fn new(value: toml::Value, nulls: &'n BTreeSet<String>) -> NullableValue<'n> {
+        NullableValue { value, path: String::new(), nulls, unresolved: false }
+    }
+
+    /// Like `new`, except that the values at the key paths in `unresolved`, such as placeholders
+    /// left as written because they failed to resolve, deserialize as they are, failing with
+    /// `UNRESOLVED` rather than a type error if they don't fit the field.
+    pub fn unresolved(value: toml::Value, unresolved: &'n BTreeSet<String>) -> NullableValue<'n> {
+        NullableValue { value, path: String::new(), nulls: unresolved, unresolved: true }
+    }
+
+    fn is_null(&self) -> bool {
+        !self.unresolved && self.nulls.contains(&self.path)
+    }
+
+    fn is_unresolved(&self) -> bool {
+        self.unresolved && self.nulls.contains(&self.path)
+    }
+}
+
+fn unresolved_error(_: toml::de::Error) -> toml::de::Error {
+    de::Error::custom(UNRESOLVED)
+}
+
+impl<'de, 'n> Deserializer<'de> for NullableValue<'n> {
+    type Error = toml::de::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        if self.is_unresolved() {
+            return self.value.deserialize_any(visitor).map_err(unresolved_error);
+        }
+        if self.is_null() {
+            return visitor.visit_unit();
+        }
+
+        match self.value {
+            toml::Value::Table(table) =>
+                visitor.visit_map(NullableMap { entries: table.into_iter(), value: None, path: self.path, nulls: self.nulls, unresolved: self.unresolved }),
+            toml::Value::Array(values) =>
+                visitor.visit_seq(NullableSeq { values: values.into_iter().enumerate(), path: self.path, nulls: self.nulls, unresolved: self.unresolved }),
+            other_value =>
+                other_value.deserialize_any(visitor)
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        if self.is_unresolved() {
+            self.value.deserialize_option(visitor).map_err(unresolved_error)
+        } else if self.is_null() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    // Datetimes and enums are scalars in TOML, so they're left to its own deserializer.
+    fn deserialize_struct<V: Visitor<'de>>(self, name: &'static str, fields: &'static [&'static str], visitor: V)
+                                           -> Result<V::Value, Self::Error> {
+        if self.is_unresolved() {
+            return self.value.deserialize_struct(name, fields, visitor).map_err(unresolved_error);
+        }
+        match self.value {
+            toml::Value::Table(_) | toml::Value::Array(_) => self.deserialize_any(visitor),
+            _ if self.is_null() => visitor.visit_unit(),
+            other_value => other_value.deserialize_struct(name, fields, visitor)
+        }
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, name: &'static str, variants: &'static [&'static str], visitor: V)
+                                         -> Result<V::Value, Self::Error> {
+        if self.is_unresolved() {
+            self.value.deserialize_enum(name, variants, visitor).map_err(unresolved_error)
+        } else if self.is_null() {
+            visitor.visit_unit()
+        } else {
+            self.value.deserialize_enum(name, variants, visitor)
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf unit
+        unit_struct seq tuple tuple_struct map identifier ignored_any
+    }
+}
+
+struct NullableMap<'n> {
+    entries: toml::map::IntoIter<String, toml::Value>,
+    value: Option<(String, toml::Value)>,
+    path: String,
+    nulls: &'n BTreeSet<String>,
+    unresolved: bool
+}
+
+impl<'de, 'n> MapAccess<'de> for NullableMap<'n> {
+    type Error = toml::de::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error> {
+        match self.entries.next() {
+            Some((k, v)) => {
+                self.value = Some((key_path(&self.path, &k), v));
+                seed.deserialize(k.into_deserializer()).map(Some)
+            },
+            None => Ok(None)
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Self::Error> {
+        let (path, value) = self.value.take().ok_or_else(|| de::Error::custom("value is missing"))?;
+        seed.deserialize(NullableValue { value, path, nulls: self.nulls, unresolved: self.unresolved })
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+struct NullableSeq<'n> {
+    values: std::iter::Enumerate<std::vec::IntoIter<toml::Value>>,
+    path: String,
+    nulls: &'n BTreeSet<String>,
+    unresolved: bool
+}
+
+impl<'de, 'n> SeqAccess<'de> for NullableSeq<'n> {
+    type Error = toml::de::Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Self::Error> {
+        match self.values.next() {
+            Some((i, value)) => seed.deserialize(NullableValue { value, path: format!("{}[{}]", self.path, i), nulls: self.nulls, unresolved: self.unresolved }).map(Some),
+            None => Ok(None)
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.values.len())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use serde::Deserialize;
+    use super::NullableValue;
+
+    #[derive(Debug, Deserialize)]
+    struct Server {
+        host: String,
+        port: Option<i64>
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Config {
+        name: Option<String>,
+        servers: Vec<Server>
+    }
+
+    #[test]
+    fn it_deserializes_null_paths_as_none() {
+        let value: toml::Value = toml::from_str(r#"
+            name = ""
+            servers = [{ host = "a", port = 1 }, { host = "b", port = "" }]
+        "#).unwrap();
+        let nulls = vec!["name".to_owned(), "servers[1].port".to_owned()].into_iter().collect::<BTreeSet<_>>();
+
+        let config = Config::deserialize(NullableValue::new(value.clone(), &nulls)).unwrap();
+        assert_eq!(config.name, None);
+        assert_eq!(config.servers.len(), 2);
+        assert_eq!(config.servers[0].port, Some(1));
+        assert_eq!(&config.servers[1].host, "b");
+        assert_eq!(config.servers[1].port, None);
+
+        let nulls = vec!["servers[0].host".to_owned()].into_iter().collect::<BTreeSet<_>>();
+        assert!(Config::deserialize(NullableValue::new(value, &nulls)).is_err());
+    }
+}
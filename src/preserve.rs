@@ -0,0 +1,144 @@
+use toml_edit::{DocumentMut, InlineTable, Item, Table, Value};
+use crate::expand::{expand_at, key_path, ExpandOptions};
+use crate::Error;
+#[cfg(feature = "substitution")]
+use crate::{syntax::Syntax, unquoted};
+
+/// Like `resolve_to_string`, but replaces placeholders in the TOML as written, keeping comments,
+/// key order, and formatting, e.g. for generated files ops edit by hand afterwards. Keys holding
+/// unset optional placeholders are removed. Placeholders in keys are left as written.
+pub fn resolve_to_string_preserving(config_str: &str) -> Result<String, Error> {
+    let config_str = config_str.strip_prefix('\u{feff}').unwrap_or(config_str);
+    let mut document = parse_document(config_str)?;
+
+    let mut errors = Vec::new();
+    resolve_table(&ExpandOptions::default(), "", document.as_table_mut(), &mut errors);
+    if errors.is_empty() {
+        Ok(document.to_string())
+    } else {
+        Err(Error::multiple(errors))
+    }
+}
+
+fn parse_document(config_str: &str) -> Result<DocumentMut, Error> {
+    let err = match config_str.parse() {
+        Ok(document) => return Ok(document),
+        Err(err) => err
+    };
+    #[cfg(feature = "substitution")]
+    if let Some(quoted) = unquoted::quote_placeholders(&Syntax::default(), config_str) {
+        return Ok(quoted.parse()?);
+    }
+    Err(err.into())
+}
+
+fn resolve_table(options: &ExpandOptions, path: &str, table: &mut Table, errors: &mut Vec<Error>) {
+    let keys = table.iter().map(|(k, _)| k.to_owned()).collect::<Vec<_>>();
+    for key in keys {
+        let item_path = key_path(path, &key);
+        let keep = match table.get_mut(&key) {
+            Some(Item::Value(value)) => resolve_value(options, &item_path, value, errors),
+            Some(Item::Table(table)) => { resolve_table(options, &item_path, table, errors); true },
+            Some(Item::ArrayOfTables(tables)) => {
+                for (i, table) in tables.iter_mut().enumerate() {
+                    resolve_table(options, &format!("{}[{}]", item_path, i), table, errors);
+                }
+                true
+            },
+            _ => true
+        };
+        if !keep {
+            table.remove(&key);
+        }
+    }
+}
+
+fn resolve_inline_table(options: &ExpandOptions, path: &str, table: &mut InlineTable, errors: &mut Vec<Error>) {
+    let keys = table.iter().map(|(k, _)| k.to_owned()).collect::<Vec<_>>();
+    for key in keys {
+        let keep = match table.get_mut(&key) {
+            Some(value) => resolve_value(options, &key_path(path, &key), value, errors),
+            None => true
+        };
+        if !keep {
+            table.remove(&key);
+        }
+    }
+}
+
+/// Expands a string `value` in place, keeping its surrounding whitespace and comments. Returns
+/// whether to keep it, i.e. `false` for an unset optional placeholder.
+fn resolve_value(options: &ExpandOptions, path: &str, value: &mut Value, errors: &mut Vec<Error>) -> bool {
+    let s = match *value {
+        Value::String(ref s) => s.value().clone(),
+        Value::Array(ref mut values) => {
+            let mut i = 0;
+            for index in 0..values.len() {
+                if resolve_value(options, &format!("{}[{}]", path, index), values.get_mut(i).expect("index in bounds"), errors) {
+                    i += 1;
+                } else {
+                    values.remove(i);
+                }
+            }
+            return true;
+        },
+        Value::InlineTable(ref mut table) => {
+            resolve_inline_table(options, path, table, errors);
+            return true;
+        },
+        _ => return true
+    };
+
+    let expanded = match expand_at(options, path, toml::Value::String(s.clone())) {
+        Ok(Some(expanded)) => expanded,
+        Ok(None) => return false,
+        Err(e) => { errors.push(e); return true; }
+    };
+    if expanded.as_str() == Some(s.as_str()) {
+        return true;
+    }
+    match expanded.to_string().parse::<Value>() {
+        Ok(mut resolved) => {
+            *resolved.decor_mut() = value.decor().clone();
+            *value = resolved;
+        },
+        Err(e) => errors.push(e.into())
+    }
+    true
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::resolve_to_string_preserving;
+
+    #[test]
+    fn it_resolves_placeholders_keeping_comments_and_formatting() {
+        let config_str = r#"# Generated config
+name = "<<ENV:PRESERVE_NAME:-app>>"   # the app name
+port = <<ENV:PRESERVE_PORT:-8080>>
+literal = 'unchanged'
+dropped = "<<ENV?:PRESERVE_UNSET>>"
+
+[servers]
+hosts = [ "a", "<<ENV:PRESERVE_NAME:-app>>", "<<ENV?:PRESERVE_UNSET>>" ]
+db = { url = "postgres://<<ENV:PRESERVE_NAME:-app>>" }
+"#;
+
+        assert_eq!(resolve_to_string_preserving(config_str).unwrap(), r#"# Generated config
+name = "app"   # the app name
+port = 8080
+literal = 'unchanged'
+
+[servers]
+hosts = [ "a", "app"]
+db = { url = "postgres://app" }
+"#);
+    }
+
+    #[test]
+    fn it_reports_every_unresolved_placeholder() {
+        let err = resolve_to_string_preserving("a = \"<<ENV:PRESERVE_MISSING_A>>\"\nb = \"<<ENV:PRESERVE_MISSING_B>>\"\n").unwrap_err();
+        assert_eq!(err.missing_env_vars(), vec!["PRESERVE_MISSING_A", "PRESERVE_MISSING_B"]);
+    }
+}
@@ -0,0 +1,164 @@
+/// A printf-style conversion such as `%05d` or `%-8s`, applied to the value of a placeholder like
+/// `<<ENV:PORT:%05d>>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatSpec {
+    left_align: bool,
+    zero_pad: bool,
+    plus_sign: bool,
+    space_sign: bool,
+    width: usize,
+    precision: Option<usize>,
+    conversion: char
+}
+
+impl FormatSpec {
+    /// Parses `%[flags][width][.precision]conversion`. The flags are any of `-` (left align), `0`
+    /// (zero pad), `+` (always sign), and ` ` (space for a positive sign). The conversion is `d`
+    /// (integer), `x` or `X` (hex), `o` (octal), `f` (float, precision defaulting to 6), or `s`
+    /// (string, precision truncating it). Nothing may follow the conversion.
+    pub fn parse(spec: &str) -> Result<FormatSpec, String> {
+        let mut rest = spec.strip_prefix('%').ok_or("expected it to start with '%'")?;
+        let mut format = FormatSpec {
+            left_align: false,
+            zero_pad: false,
+            plus_sign: false,
+            space_sign: false,
+            width: 0,
+            precision: None,
+            conversion: 's'
+        };
+
+        loop {
+            match rest.chars().next() {
+                Some('-') => format.left_align = true,
+                Some('0') => format.zero_pad = true,
+                Some('+') => format.plus_sign = true,
+                Some(' ') => format.space_sign = true,
+                _ => break
+            }
+            rest = &rest[1..];
+        }
+
+        let (width, after) = split_digits(rest);
+        format.width = parse_count(width, "width")?;
+        rest = after;
+        if let Some(after) = rest.strip_prefix('.') {
+            let (precision, after) = split_digits(after);
+            format.precision = Some(parse_count(precision, "precision")?);
+            rest = after;
+        }
+
+        let mut chars = rest.chars();
+        format.conversion = match chars.next() {
+            Some(c @ ('d' | 'x' | 'X' | 'o' | 'f' | 's')) => c,
+            Some(c) => return Err(format!("unknown conversion '{}'", c)),
+            None => return Err("missing conversion".to_owned())
+        };
+        if !chars.as_str().is_empty() {
+            return Err(format!("unexpected '{}' after the conversion", chars.as_str()));
+        }
+        if format.precision.is_some() && !matches!(format.conversion, 'f' | 's') {
+            return Err(format!("precision isn't supported for '%{}'", format.conversion));
+        }
+        Ok(format)
+    }
+
+    /// What the value must parse as, for errors.
+    pub fn expected(&self) -> &'static str {
+        match self.conversion {
+            'f' => "a float",
+            's' => "a string",
+            _ => "an integer"
+        }
+    }
+
+    /// Formats `value`, or `None` if it doesn't parse as the conversion requires. Surrounding
+    /// whitespace is ignored for numeric conversions.
+    pub fn apply(&self, value: &str) -> Option<String> {
+        // Whether zero padding applies: strings, `inf`, and `nan` are padded with spaces.
+        let (negative, body, numeric) = match self.conversion {
+            'd' => {
+                let i = value.trim().parse::<i64>().ok()?;
+                (i < 0, i.unsigned_abs().to_string(), true)
+            },
+            'x' => (false, format!("{:x}", value.trim().parse::<i64>().ok()?), true),
+            'X' => (false, format!("{:X}", value.trim().parse::<i64>().ok()?), true),
+            'o' => (false, format!("{:o}", value.trim().parse::<i64>().ok()?), true),
+            'f' => {
+                let f = value.trim().parse::<f64>().ok()?;
+                (f.is_sign_negative() && !f.is_nan(), format!("{:.*}", self.precision.unwrap_or(6), f.abs()), f.is_finite())
+            },
+            _ => (false, match self.precision {
+                Some(precision) => value.chars().take(precision).collect(),
+                None => value.to_owned()
+            }, false)
+        };
+
+        let sign = if negative {
+            "-"
+        } else if self.plus_sign && matches!(self.conversion, 'd' | 'f') {
+            "+"
+        } else if self.space_sign && matches!(self.conversion, 'd' | 'f') {
+            " "
+        } else {
+            ""
+        };
+        let padding = self.width.saturating_sub(sign.len() + body.chars().count());
+        Some(if self.left_align {
+            format!("{}{}{}", sign, body, " ".repeat(padding))
+        } else if self.zero_pad && numeric {
+            format!("{}{}{}", sign, "0".repeat(padding), body)
+        } else {
+            format!("{}{}{}", " ".repeat(padding), sign, body)
+        })
+    }
+}
+
+fn split_digits(s: &str) -> (&str, &str) {
+    s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()))
+}
+
+fn parse_count(digits: &str, what: &str) -> Result<usize, String> {
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    digits.parse().map_err(|_| format!("{} '{}' is too large", what, digits))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::FormatSpec;
+
+    fn format(spec: &str, value: &str) -> Option<String> {
+        FormatSpec::parse(spec).unwrap().apply(value)
+    }
+
+    #[test]
+    fn it_formats_values() {
+        assert_eq!(format("%05d", "42").unwrap(), "00042");
+        assert_eq!(format("%05d", "-42").unwrap(), "-0042");
+        assert_eq!(format("%+d", " 42\n").unwrap(), "+42");
+        assert_eq!(format("%-5d", "42").unwrap(), "42   ");
+        assert_eq!(format("%5d", "42").unwrap(), "   42");
+        assert_eq!(format("%04x", "255").unwrap(), "00ff");
+        assert_eq!(format("%X", "255").unwrap(), "FF");
+        assert_eq!(format("%o", "8").unwrap(), "10");
+        assert_eq!(format("%08.3f", "-3.14159").unwrap(), "-003.142");
+        assert_eq!(format("%f", "1").unwrap(), "1.000000");
+        assert_eq!(format("%06f", "-inf").unwrap(), "  -inf");
+        assert_eq!(format("%08s", "abc").unwrap(), "     abc");
+        assert_eq!(format("%-6.2s", "abc").unwrap(), "ab    ");
+        assert_eq!(format("%d", "4.2"), None);
+    }
+
+    #[test]
+    fn it_rejects_invalid_specs() {
+        assert!(FormatSpec::parse("05d").is_err());
+        assert!(FormatSpec::parse("%").is_err());
+        assert!(FormatSpec::parse("%5q").is_err());
+        assert!(FormatSpec::parse("%5dd").is_err());
+        assert!(FormatSpec::parse("%.2d").is_err());
+        assert!(FormatSpec::parse("%99999999999999999999999d").is_err());
+    }
+}
@@ -0,0 +1,126 @@
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::Path;
+use std::sync::Arc;
+use crate::env_source::{ProcessEnv, SharedEnvSource};
+use crate::expand::ExpandOptions;
+use crate::syntax::Syntax;
+use crate::{load_config_from_value_with, read_config_value, resolve_config_path, Error, ErrorKind, DEFAULT_CONFIG_NAME};
+
+/// Like `load_config`, but when stdin is a terminal asks for the value of each missing required
+/// env var instead of failing, e.g. to guide first-time setup of a developer CLI. Input for vars
+/// used by `<<SECRET_ENV:...>>` isn't echoed. The answers are only used for this load, not set
+/// in the environment. Without a terminal, missing vars fail as usual.
+pub fn load_config_interactive<C: DeserializeOwned, P: AsRef<Path>>(config_path: Option<P>) -> Result<C, Error> {
+    let config_path = resolve_config_path(config_path, DEFAULT_CONFIG_NAME)?;
+    let value = read_config_value(&config_path, None, None)?;
+    load_prompting(value, io::stdin().is_terminal(), prompt_terminal)
+}
+
+/// Loads `value`, calling `prompt` with the name, key path, and secrecy of each missing var if
+/// `interactive`, then retrying with the answers. Vars referenced only from answers are asked
+/// for in a later round; a var that's still missing after being asked for fails the load.
+fn load_prompting<C, F>(value: toml::Value, interactive: bool, mut prompt: F) -> Result<C, Error>
+    where C: DeserializeOwned, F: FnMut(&str, &str, bool) -> io::Result<String>
+{
+    let secrets = secret_vars(&Syntax::default(), &value);
+    let mut answers = HashMap::new();
+    loop {
+        let env = SharedEnvSource(Arc::new((answers.clone(), ProcessEnv)));
+        let options = ExpandOptions { env: Some(env), ..ExpandOptions::default() };
+        let err = match load_config_from_value_with(&options, value.clone()) {
+            Ok(config) => return Ok(config),
+            Err(err) => err
+        };
+
+        let missing = missing_vars(&err);
+        if !interactive || missing.is_empty() || missing.iter().any(|&(key, _)| answers.contains_key(key)) {
+            return Err(err);
+        }
+        for (key, path) in missing {
+            let answer = prompt(key, path, secrets.contains(key))?;
+            answers.insert(key.to_owned(), answer);
+        }
+    }
+}
+
+/// The first var of each missing fallback chain, with the key path that needs it.
+fn missing_vars(err: &Error) -> Vec<(&str, &str)> {
+    let mut seen = BTreeSet::new();
+    err.iter_multiple().unwrap_or(std::slice::from_ref(err)).iter()
+        .filter_map(|e| match *e.kind() {
+            ErrorKind::EnvVarMissing(ref key, ref path) => Some((key.split('|').next().unwrap_or(key), path.as_str())),
+            _ => None
+        })
+        .filter(|&(key, _)| seen.insert(key))
+        .collect()
+}
+
+/// The vars read by `<<SECRET_ENV:...>>` placeholders anywhere in `value`.
+fn secret_vars(syntax: &Syntax, value: &toml::Value) -> BTreeSet<String> {
+    match *value {
+        toml::Value::String(ref s) =>
+            syntax.whole(&syntax.secret_env, s)
+                .map(|captures| captures[2].split('|').map(str::to_owned).collect())
+                .unwrap_or_default(),
+        toml::Value::Table(ref table) =>
+            table.values().flat_map(|v| secret_vars(syntax, v)).collect(),
+        toml::Value::Array(ref values) =>
+            values.iter().flat_map(|v| secret_vars(syntax, v)).collect(),
+        _ => BTreeSet::new()
+    }
+}
+
+fn prompt_terminal(key: &str, path: &str, secret: bool) -> io::Result<String> {
+    let question = format!("{} (needed by '{}'): ", key, path);
+    if secret {
+        return rpassword::prompt_password(question);
+    }
+
+    let mut stderr = io::stderr();
+    stderr.write_all(question.as_bytes())?;
+    stderr.flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(answer.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::load_prompting;
+    use crate::ErrorKind;
+
+    #[derive(Debug, Deserialize)]
+    struct Config {
+        name: String,
+        password: String
+    }
+
+    #[test]
+    fn it_prompts_for_missing_vars() {
+        let value: toml::Value = toml::from_str(r#"
+            name = "<<ENV:PROMPT_NAME>>"
+            password = "<<SECRET_ENV:PROMPT_PASSWORD>>"
+        "#).unwrap();
+
+        let mut asked = Vec::new();
+        let config: Config = load_prompting(value.clone(), true, |key, path, secret| {
+            asked.push((key.to_owned(), path.to_owned(), secret));
+            Ok(if key == "PROMPT_NAME" { "<<ENV:PROMPT_NESTED>>".to_owned() } else { format!("{} answer", key) })
+        }).unwrap();
+
+        assert_eq!(&config.name, "PROMPT_NESTED answer");
+        assert_eq!(&config.password, "PROMPT_PASSWORD answer");
+        assert_eq!(asked, vec![
+            ("PROMPT_NAME".to_owned(), "name".to_owned(), false),
+            ("PROMPT_PASSWORD".to_owned(), "password".to_owned(), true),
+            ("PROMPT_NESTED".to_owned(), "name".to_owned(), false)
+        ]);
+
+        let err = load_prompting::<Config, _>(value, false, |_, _, _| panic!("Prompted without a terminal")).unwrap_err();
+        assert_eq!(err.missing_env_vars(), vec!["PROMPT_NAME", "PROMPT_PASSWORD"]);
+        assert!(matches!(*err.kind(), ErrorKind::Multiple(_)));
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::{BTreeMap, BTreeSet};
+use crate::syntax::Syntax;
+use crate::expand::key_path;
+use crate::{Error, ErrorKind, Format};
+
+/// The env vars a config references, each mapped to the key paths that reference it. Fallback
+/// chains like `DATABASE_URL|DB_URL` are listed as written, since only one of them must be set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnvRequirements {
+    /// Vars referenced by `<<ENV:KEY>>` or `<<SECRET_ENV:KEY>>` without a default, which must be set to load the config.
+    pub required: BTreeMap<String, BTreeSet<String>>,
+    /// Vars referenced by `<<ENV?:KEY>>` or `<<ENV:KEY:-default>>`, which may be left unset.
+    pub optional: BTreeMap<String, BTreeSet<String>>
+}
+
+impl EnvRequirements {
+    /// Vars that are required at some key paths but optional at others, e.g. `<<ENV:DB>>` in one
+    /// place and `<<ENV?:DB>>` in another. Leaving such a var unset drops some keys and fails
+    /// others, which is usually a mistake.
+    pub fn inconsistent(&self) -> Vec<&str> {
+        self.required.keys().filter(|key| self.optional.contains_key(*key)).map(String::as_str).collect()
+    }
+}
+
+/// Lists the env vars referenced by a TOML config without reading the environment, e.g. to
+/// generate a `.env.example` or check in CI that every var is documented. Vars referenced from
+/// within a default value are included as optional.
+pub fn required_env_vars(config_str: &str) -> Result<EnvRequirements, Error> {
+    Ok(collect_requirements(&Syntax::default(), &Format::Toml.parse(config_str)?))
+}
+
+/// Fails with an `ErrorKind::InconsistentEnvUsage` for each var `value` references as both
+/// required and optional; see `EnvRequirements::inconsistent`.
+pub fn check_consistent_env_usage(syntax: &Syntax, value: &toml::Value) -> Result<(), Error> {
+    let requirements = collect_requirements(syntax, value);
+    let errors = requirements.inconsistent().into_iter()
+        .map(|key| {
+            let first_path = |vars: &BTreeMap<String, BTreeSet<String>>| vars[key].iter().next().cloned().unwrap_or_default();
+            ErrorKind::InconsistentEnvUsage(key.to_owned(), first_path(&requirements.required), first_path(&requirements.optional)).into()
+        })
+        .collect::<Vec<Error>>();
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::multiple(errors))
+    }
+}
+
+fn collect_requirements(syntax: &Syntax, value: &toml::Value) -> EnvRequirements {
+    let mut requirements = EnvRequirements::default();
+    collect_value(syntax, "", value, &mut requirements);
+    requirements
+}
+
+fn collect_value(syntax: &Syntax, path: &str, value: &toml::Value, requirements: &mut EnvRequirements) {
+    match *value {
+        toml::Value::String(ref s) =>
+            collect_string(syntax, path, s, false, requirements),
+        toml::Value::Table(ref table) =>
+            for (k, v) in table {
+                collect_string(syntax, &key_path(path, k), k, false, requirements);
+                collect_value(syntax, &key_path(path, k), v, requirements);
+            },
+        toml::Value::Array(ref values) =>
+            for (i, v) in values.iter().enumerate() {
+                collect_value(syntax, &format!("{}[{}]", path, i), v, requirements);
+            },
+        _ => ()
+    }
+}
+
+/// Records the placeholders in `s`. Everything found inside a default is optional, since the
+/// default is only used when the outer var is unset.
+fn collect_string(syntax: &Syntax, path: &str, s: &str, in_default: bool, requirements: &mut EnvRequirements) {
+    if let Some(captures) = syntax.whole(&syntax.env_req, s) {
+        let default = captures.get(2).map(|m| m.as_str());
+        record(path, &captures[1], in_default || default.is_some(), requirements);
+        if let Some(default) = default {
+            collect_string(syntax, path, default, true, requirements);
+        }
+    } else if let Some(captures) = syntax.whole(&syntax.env_opt, s).or_else(|| syntax.whole(&syntax.env_ref, s)) {
+        record(path, &captures[1], true, requirements);
+    } else if let Some(captures) = syntax.whole(&syntax.env_format, s) {
+        record(path, &captures[2], in_default || !captures[1].is_empty(), requirements);
+    } else if let Some(captures) = syntax.whole(&syntax.secret_env, s) {
+        let default = captures.get(3).map(|m| m.as_str());
+        let optional = in_default || !captures[1].is_empty() || default.is_some();
+        record(path, &captures[2], optional, requirements);
+        if let Some(default) = default {
+            collect_string(syntax, path, default, true, requirements);
+        }
+    } else if let Some(captures) = syntax.whole(&syntax.env_typed, s) {
+        let default = captures.get(4).map(|m| m.as_str());
+        let optional = in_default || !captures[2].is_empty() || default.is_some();
+        record(path, &captures[3], optional, requirements);
+        if let Some(default) = default {
+            collect_string(syntax, path, default, true, requirements);
+        }
+    } else {
+        for placeholder in syntax.inline_placeholders(s) {
+            let optional = in_default || placeholder.optional || placeholder.default.is_some();
+            record(path, placeholder.key, optional, requirements);
+            if let Some(default) = placeholder.default {
+                collect_string(syntax, path, default, true, requirements);
+            }
+        }
+    }
+}
+
+fn record(path: &str, env_key: &str, optional: bool, requirements: &mut EnvRequirements) {
+    let vars = if optional { &mut requirements.optional } else { &mut requirements.required };
+    vars.entry(env_key.to_owned()).or_default().insert(path.to_owned());
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{check_consistent_env_usage, required_env_vars};
+    use crate::syntax::Syntax;
+    use crate::ErrorKind;
+
+    #[test]
+    fn it_lists_referenced_env_vars_with_paths() {
+        let config_str = r#"
+            name = "<<ENV:NAME>>"
+            greeting = "hello <<ENV:NAME>>, <<ENV?:TITLE>>"
+            escaped = '<<\ENV:NOT_A_VAR>>'
+            [db]
+            url = "postgres://<<ENV:DB_HOST>>:<<ENV:DB_PORT:-5432>>"
+            password = "<<ENV?:DB_PASSWORD>>"
+            port = "<<ENV:PORT:-<<ENV:DEFAULT_PORT>>>>"
+            [[servers]]
+            "<<ENV:REGION>>" = "primary"
+            host = "<<ENV:DB_HOST>>"
+            token = "<<SECRET_ENV:API_TOKEN>>"
+            weight = "<<ENV_FLOAT?:WEIGHT>>"
+        "#;
+
+        let requirements = required_env_vars(config_str).unwrap();
+        let required = requirements.required.iter()
+            .map(|(k, paths)| (k.as_str(), paths.iter().map(String::as_str).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        assert_eq!(required, vec![
+            ("API_TOKEN", vec!["servers[0].token"]),
+            ("DB_HOST", vec!["db.url", "servers[0].host"]),
+            ("NAME", vec!["greeting", "name"]),
+            ("REGION", vec!["servers[0].<<ENV:REGION>>"])
+        ]);
+        assert_eq!(requirements.optional.keys().map(String::as_str).collect::<Vec<_>>(),
+                   vec!["DB_PASSWORD", "DB_PORT", "DEFAULT_PORT", "PORT", "TITLE", "WEIGHT"]);
+    }
+
+    #[test]
+    fn it_finds_vars_used_as_both_required_and_optional() {
+        let config_str = r#"
+            url = "<<ENV:DB>>"
+            name = "<<ENV:NAME>>"
+            [replica]
+            url = "<<ENV?:DB>>"
+            port = "<<ENV:PORT:-5432>>"
+        "#;
+
+        assert_eq!(required_env_vars(config_str).unwrap().inconsistent(), vec!["DB"]);
+
+        let err = check_consistent_env_usage(&Syntax::default(), &toml::from_str(config_str).unwrap()).unwrap_err();
+        match *err.kind() {
+            ErrorKind::InconsistentEnvUsage(ref key, ref required_path, ref optional_path) =>
+                assert_eq!((key.as_str(), required_path.as_str(), optional_path.as_str()), ("DB", "url", "replica.url")),
+            ref other => panic!("Expected inconsistent env usage, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn it_reads_strings_starting_with_a_directive_as_inline() {
+        let config_str = r#"
+            env = "<<ENV:WHOLE_A:-x>> and <<ENV:WHOLE_B>>"
+            secret = "<<SECRET_ENV:WHOLE_C:-x>> and <<ENV:WHOLE_D>>"
+            typed = "<<ENV_INT:WHOLE_E:-1>>-<<ENV:WHOLE_F>>"
+            formatted = "<<ENV?:WHOLE_G:%05d>>-<<ENV:WHOLE_H>>"
+        "#;
+
+        let requirements = required_env_vars(config_str).unwrap();
+        assert_eq!(requirements.required.keys().map(String::as_str).collect::<Vec<_>>(),
+                   vec!["WHOLE_B", "WHOLE_D", "WHOLE_F", "WHOLE_H"]);
+        assert_eq!(requirements.optional.keys().map(String::as_str).collect::<Vec<_>>(), vec!["WHOLE_A"]);
+    }
+}
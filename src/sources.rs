@@ -0,0 +1,159 @@
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use std::env;
+use std::path::PathBuf;
+use crate::expand::{coerce_value, key_path};
+use crate::{empty_table, load_config_from_value, merge, read_config_value, Error, Format};
+
+/// Where each key of a config loaded by `load_sources` came from: the dotted key path of every
+/// value (tables excepted) mapped to the label of the source that won it.
+pub type ProvenanceMap = BTreeMap<String, String>;
+
+/// A labeled place to load config from, for `load_sources`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// A config file, in the format given by its extension, labeled with its path.
+    File(PathBuf),
+    /// Inline TOML, e.g. compiled-in defaults, labeled with `label`.
+    Str { label: String, config: String },
+    /// Process env vars starting with `prefix`, with the rest of the name lowercased and split
+    /// into keys on `__`, e.g. `APP_DB__HOST` with prefix `APP_` sets `db.host`. Values that look
+    /// like booleans or numbers are inserted as those types. Labeled `env {prefix}*`.
+    Env { prefix: String }
+}
+
+impl Source {
+    /// The label recorded in the `ProvenanceMap` for keys this source sets.
+    pub fn label(&self) -> String {
+        match *self {
+            Source::File(ref path) => path.display().to_string(),
+            Source::Str { ref label, .. } => label.clone(),
+            Source::Env { ref prefix } => format!("env {}*", prefix)
+        }
+    }
+
+    fn read(&self) -> Result<toml::Value, Error> {
+        match *self {
+            Source::File(ref path) => read_config_value(path, None, None),
+            Source::Str { ref config, .. } => Format::Toml.parse(config),
+            Source::Env { ref prefix } => Ok(env_overlay(prefix))
+        }
+    }
+}
+
+/// Loads config from `sources` deep-merged in order, as by `load_configs`, so later sources take
+/// precedence, and reports which source each key's value came from, e.g. to debug layered
+/// config. Placeholders are expanded once, after merging, so the provenance of a key is the
+/// source that wrote its placeholder.
+pub fn load_sources<C: DeserializeOwned>(sources: Vec<Source>) -> Result<(C, ProvenanceMap), Error> {
+    let mut merged = empty_table();
+    let mut provenance = ProvenanceMap::new();
+    for source in sources {
+        let value = source.read()?;
+        record(&mut provenance, "", &value, &source.label());
+        merged = merge::merge(merged, value);
+    }
+
+    Ok((load_config_from_value(merged)?, provenance))
+}
+
+/// Records `label` for every value in `value`, replacing whatever set the same keys before, as
+/// merging `value` would.
+fn record(provenance: &mut ProvenanceMap, path: &str, value: &toml::Value, label: &str) {
+    match *value {
+        toml::Value::Table(ref table) => {
+            provenance.remove(path);
+            for (k, v) in table {
+                record(provenance, &key_path(path, k), v, label);
+            }
+        },
+        _ => {
+            provenance.retain(|p, _| !p.strip_prefix(path).is_some_and(|rest| rest.starts_with(['.', '['])));
+            provenance.insert(path.to_owned(), label.to_owned());
+        }
+    }
+}
+
+fn env_overlay(prefix: &str) -> toml::Value {
+    let mut vars = env::vars_os()
+        .filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)))
+        .filter_map(|(k, v)| Some((k.strip_prefix(prefix)?.to_lowercase(), v)))
+        .filter(|(k, _)| !k.is_empty())
+        .collect::<Vec<_>>();
+    vars.sort();
+
+    let mut overlay = empty_table();
+    for (key, value) in vars {
+        let mut keys = key.split("__").collect::<Vec<_>>();
+        let last = keys.pop().unwrap_or_default();
+        let mut table = &mut overlay;
+        for k in keys {
+            table = table.as_table_mut().expect("table")
+                .entry(k.to_owned()).or_insert_with(empty_table);
+            if !table.is_table() {
+                *table = empty_table();
+            }
+        }
+        table.as_table_mut().expect("table").insert(last.to_owned(), coerce_value(value));
+    }
+    overlay
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{load_sources, Source};
+    use std::env;
+    use std::fs;
+
+    #[derive(Debug, Deserialize)]
+    struct Db {
+        host: String,
+        port: i64
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Config {
+        name: String,
+        debug: bool,
+        db: Db
+    }
+
+    #[test]
+    fn it_tracks_which_source_each_key_came_from() {
+        let config_path = env::temp_dir().join("config_loader_sources.toml");
+        fs::write(&config_path, "name = \"file\"\n[db]\nhost = \"db.internal\"\n").unwrap();
+        env::set_var("SOURCES_APP_DB__PORT", "6543");
+
+        let (config, provenance): (Config, _) = load_sources(vec![
+            Source::Str { label: "defaults".to_owned(), config: "name = \"default\"\ndebug = false\n[db]\nport = 5432\n".to_owned() },
+            Source::File(config_path.clone()),
+            Source::Env { prefix: "SOURCES_APP_".to_owned() }
+        ]).unwrap();
+
+        assert_eq!(&config.name, "file");
+        assert!(!config.debug);
+        assert_eq!(&config.db.host, "db.internal");
+        assert_eq!(config.db.port, 6543);
+
+        let file_label = config_path.display().to_string();
+        assert_eq!(provenance.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect::<Vec<_>>(), vec![
+            ("db.host", file_label.as_str()),
+            ("db.port", "env SOURCES_APP_*"),
+            ("debug", "defaults"),
+            ("name", file_label.as_str())
+        ]);
+
+        fs::remove_file(config_path).unwrap();
+    }
+
+    #[test]
+    fn it_drops_provenance_of_replaced_tables() {
+        let (_, provenance): (toml::Value, _) = load_sources(vec![
+            Source::Str { label: "a".to_owned(), config: "[db]\nhost = \"a\"\n".to_owned() },
+            Source::Str { label: "b".to_owned(), config: "db = \"b\"\n".to_owned() }
+        ]).unwrap();
+
+        assert_eq!(provenance.into_iter().collect::<Vec<_>>(), vec![("db".to_owned(), "b".to_owned())]);
+    }
+}
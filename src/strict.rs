@@ -0,0 +1,79 @@
+use serde::de::{Deserialize, Deserializer};
+use serde_ignored::Path;
+use crate::expand::{collect_results, key_path};
+use crate::{combine_errors, Error, ErrorKind};
+
+/// Deserializes from `deserializer`, usually a `toml::Value`, failing with `ErrorKind::UnknownKey` for every key that `C` doesn't
+/// have a field for instead of silently ignoring it, e.g. a `prot` typo for `port`. Any
+/// deserialization error is reported alongside the unknown keys.
+pub fn deserialize_strict<'de, C, D>(deserializer: D) -> Result<C, Error>
+    where C: Deserialize<'de>, D: Deserializer<'de, Error = toml::de::Error> {
+    let mut unknown = Vec::new();
+    let result = serde_ignored::deserialize(deserializer, |path| unknown.push(Err(unknown_key(&path))));
+
+    match (result, collect_results::<(), _>(unknown)) {
+        (Ok(config), Ok(_)) => Ok(config),
+        (Ok(_), Err(e)) => Err(e),
+        (Err(e), Ok(_)) => Err(e.into()),
+        (Err(e), Err(unknown)) => Err(combine_errors(unknown, e.into()))
+    }
+}
+
+fn unknown_key(path: &Path) -> Error {
+    ErrorKind::UnknownKey(dotted_path(path)).into()
+}
+
+/// Formats `path` the same way as the key paths in other errors, e.g. `servers[0].host`.
+pub fn dotted_path(path: &Path) -> String {
+    match *path {
+        Path::Root => String::new(),
+        Path::Seq { parent, index } => format!("{}[{}]", dotted_path(parent), index),
+        Path::Map { parent, ref key } => key_path(&dotted_path(parent), key),
+        Path::Some { parent } | Path::NewtypeStruct { parent } | Path::NewtypeVariant { parent } =>
+            dotted_path(parent)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::deserialize_strict;
+    use crate::ErrorKind;
+
+    #[derive(Debug, Deserialize)]
+    struct Server {
+        host: String
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Config {
+        port: i64,
+        servers: Vec<Server>,
+        backup: Option<Server>
+    }
+
+    #[test]
+    fn it_rejects_unknown_keys() {
+        let value: toml::Value = toml::from_str(r#"
+            port = 80
+            servers = [{ host = "a" }, { host = "b", prot = 81 }]
+            [backup]
+            host = "c"
+            hots = "typo"
+        "#).unwrap();
+
+        let unknown = deserialize_strict::<Config, _>(value).unwrap_err().iter_multiple().unwrap().iter()
+            .map(|e| match *e.kind() {
+                ErrorKind::UnknownKey(ref path) => path.clone(),
+                ref other => panic!("Expected unknown key, got {:?}", other)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(unknown, vec!["backup.hots", "servers[1].prot"]);
+
+        let value: toml::Value = toml::from_str("port = 80\nservers = [{ host = \"a\" }]\n").unwrap();
+        let config = deserialize_strict::<Config, _>(value).unwrap();
+        assert_eq!(config.port, 80);
+        assert_eq!(&config.servers[0].host, "a");
+        assert!(config.backup.is_none());
+    }
+}
@@ -0,0 +1,225 @@
+use regex::{self, Regex};
+use crate::{Error, ErrorKind};
+
+/// An env var name within a placeholder, or several separated by `|` to try in order, as in
+/// `<<ENV:DATABASE_URL|DB_URL>>`.
+const ENV_NAME: &str = "[a-zA-Z0-9_.-]+(?:\\|[a-zA-Z0-9_.-]+)*";
+
+/// The type names accepted by typed placeholders like `<<ENV_INT:PORT>>`.
+const TYPED_KINDS: &str = if cfg!(feature = "base64") { "INT|BOOL|FLOAT|AUTO|B64" } else { "INT|BOOL|FLOAT|AUTO" };
+
+lazy_static! {
+    static ref DEFAULT_SYNTAX: Syntax = Syntax::new("<<", ">>").unwrap();
+}
+
+/// The placeholder patterns for a given pair of delimiters, e.g. `<<` and `>>` for
+/// `<<ENV:KEY>>`.
+#[derive(Debug, Clone)]
+pub struct Syntax {
+    pub env_req: Regex,
+    pub env_opt: Regex,
+    /// `<<ENV:KEY||path.to.key>>`, falling back to another config value when `KEY` is unset.
+    pub env_ref: Regex,
+    /// `<<ENV:KEY:%05d>>`, formatting the value printf-style when that's enabled.
+    pub env_format: Regex,
+    pub secret_env: Regex,
+    pub env_typed: Regex,
+    pub file_req: Regex,
+    pub file_opt: Regex,
+    pub env_inline: Regex,
+    /// Any `<<NAME:arg>>`, for custom directives.
+    pub custom: Regex,
+    #[cfg(feature = "command-substitution")]
+    pub cmd: Regex,
+    #[cfg(feature = "keyring")]
+    pub keyring: Regex,
+    /// The prefix followed by a backslash, which escapes a placeholder.
+    escape: String,
+    prefix: String,
+    suffix: String
+}
+
+/// A placeholder such as `<<ENV?:KEY:-default>>` found within a longer string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InlinePlaceholder<'s> {
+    /// Byte range of the whole placeholder.
+    pub start: usize,
+    pub end: usize,
+    pub optional: bool,
+    pub key: &'s str,
+    pub default: Option<&'s str>
+}
+
+impl Syntax {
+    /// Builds the patterns for placeholders of the form `{prefix}ENV:KEY{suffix}`. The delimiters
+    /// are matched literally, so they may contain regex metacharacters, but neither may be empty.
+    pub fn new(prefix: &str, suffix: &str) -> Result<Syntax, Error> {
+        if prefix.is_empty() || suffix.is_empty() {
+            return Err(ErrorKind::InvalidDelimiter(format!("'{}' and '{}' must both be non-empty", prefix, suffix)).into());
+        }
+
+        let p = regex::escape(prefix);
+        let s = regex::escape(suffix);
+        let build = |pattern: String| Regex::new(&pattern)
+            .map_err(|e| Error::from(ErrorKind::InvalidDelimiter(e.to_string())));
+
+        Ok(Syntax {
+            env_req: build(format!("^{}ENV:({})(?::-(.*))?{}$", p, ENV_NAME, s))?,
+            env_opt: build(format!("^{}ENV\\?:({}){}$", p, ENV_NAME, s))?,
+            env_ref: build(format!("^{}ENV:({})\\|\\|(.+){}$", p, ENV_NAME, s))?,
+            env_format: build(format!("^{}ENV(\\??):({}):(%.*){}$", p, ENV_NAME, s))?,
+            secret_env: build(format!("^{}SECRET_ENV(\\??):({})(?::-(.*))?{}$", p, ENV_NAME, s))?,
+            env_typed: build(format!("^{}ENV_({})(\\??):({})(?::-(.*))?{}$", p, TYPED_KINDS, ENV_NAME, s))?,
+            file_req: build(format!("^{}FILE:(.+){}$", p, s))?,
+            file_opt: build(format!("^{}FILE\\?:(.+){}$", p, s))?,
+            env_inline: build(format!("{}ENV(\\??):({})(?::-(.*?))?{}", p, ENV_NAME, s))?,
+            custom: build(format!("^{}([a-zA-Z][a-zA-Z0-9_]*):(.+){}$", p, s))?,
+            #[cfg(feature = "command-substitution")]
+            cmd: build(format!("^{}CMD:(.+){}$", p, s))?,
+            #[cfg(feature = "keyring")]
+            keyring: build(format!("^{}KEYRING:([^/]+)/(.+){}$", p, s))?,
+            escape: format!("{}\\", prefix),
+            prefix: prefix.to_owned(),
+            suffix: suffix.to_owned()
+        })
+    }
+
+    /// Collapses the escape `<<\` to `<<`, so `<<\ENV:FOO>>` is left as the literal text
+    /// `<<ENV:FOO>>` instead of being expanded. Only applied to the literal parts of a string,
+    /// never to resolved values.
+    pub fn unescape(&self, s: &str) -> String {
+        s.replace(&self.escape, &self.prefix)
+    }
+
+    /// Whether `s` is wrapped in the delimiters like a placeholder, e.g. the malformed
+    /// `<<ENV FOO>>`. Escaped placeholders don't count.
+    pub fn looks_like_placeholder(&self, s: &str) -> bool {
+        s.len() >= self.prefix.len() + self.suffix.len()
+            && s.starts_with(&self.prefix)
+            && s.ends_with(&self.suffix)
+            && !s.starts_with(&self.escape)
+    }
+
+    /// The name of the directive `s` consists of, like `FILE` for `<<FILE:path>>`, `FILE?` for
+    /// `<<FILE?:path>>`, or `AWS_SSM` for `<<AWS_SSM:/db/pass>>`. Env placeholders, even
+    /// malformed ones like `<<ENV:bad name>>`, aren't directives.
+    pub fn directive_name(&self, s: &str) -> Option<String> {
+        if self.whole(&self.file_opt, s).is_some() {
+            return Some("FILE?".to_owned());
+        }
+        let captures = self.whole(&self.custom, s)?;
+        let name = &captures[1];
+        let is_env = name == "ENV" || name == "SECRET_ENV"
+            || name.strip_prefix("ENV_").is_some_and(|kind| TYPED_KINDS.split('|').any(|k| k == kind));
+        if is_env { None } else { Some(name.to_owned()) }
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn suffix(&self) -> &str {
+        &self.suffix
+    }
+
+    /// Finds the env placeholders within `s`, in order. A default may itself contain
+    /// placeholders, as in `<<ENV:PORT:-<<ENV:DEFAULT_PORT>>>>`, so it runs to the suffix that
+    /// balances the placeholder rather than the first one.
+    pub fn inline_placeholders<'s>(&self, s: &'s str) -> Vec<InlinePlaceholder<'s>> {
+        let mut placeholders = Vec::new();
+        let mut pos = 0;
+        while let Some(captures) = self.env_inline.captures_at(s, pos) {
+            let whole = captures.get(0).unwrap();
+            let (default, end) = match captures.get(3) {
+                Some(default) => {
+                    let default_end = self.balanced_end(s, default.start()).unwrap_or_else(|| default.end());
+                    (Some(&s[default.start()..default_end]), default_end + self.suffix.len())
+                },
+                None => (None, whole.end())
+            };
+
+            placeholders.push(InlinePlaceholder {
+                start: whole.start(),
+                end,
+                optional: !captures[1].is_empty(),
+                key: captures.get(2).unwrap().as_str(),
+                default
+            });
+            pos = end;
+        }
+        placeholders
+    }
+
+    /// Captures `s` with one of the whole-string patterns, like `env_req`, only if `s` is a single
+    /// placeholder: the suffix balancing its prefix is at the very end. `<<ENV:A:-x>> and
+    /// <<ENV:B>>` is two placeholders within a string, not one with a long default.
+    pub fn whole<'s>(&self, pattern: &Regex, s: &'s str) -> Option<regex::Captures<'s>> {
+        let single = s.starts_with(&self.prefix)
+            && s.len() >= self.prefix.len() + self.suffix.len()
+            && self.balanced_end(s, self.prefix.len()) == Some(s.len() - self.suffix.len());
+        if single { pattern.captures(s) } else { None }
+    }
+
+    /// The index of the suffix closing a placeholder whose contents start at `start`, skipping
+    /// over nested placeholders, including escaped ones.
+    pub fn balanced_end(&self, s: &str, start: usize) -> Option<usize> {
+        let mut depth = 0;
+        let mut i = start;
+        while i < s.len() {
+            let rest = &s[i..];
+            if rest.starts_with(&self.prefix) {
+                depth += 1;
+                i += self.prefix.len();
+            } else if rest.starts_with(&self.suffix) {
+                if depth == 0 {
+                    return Some(i);
+                }
+                depth -= 1;
+                i += self.suffix.len();
+            } else {
+                i += rest.chars().next().map_or(1, char::len_utf8);
+            }
+        }
+        None
+    }
+}
+
+impl Default for Syntax {
+    fn default() -> Syntax {
+        DEFAULT_SYNTAX.clone()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::Syntax;
+
+    #[test]
+    fn it_escapes_custom_delimiters() {
+        let syntax = Syntax::new("${", "}").unwrap();
+        assert_eq!(&syntax.env_req.captures("${ENV:FOO:-a}").unwrap()[1], "FOO");
+        assert!(syntax.env_opt.is_match("${ENV?:FOO}"));
+        assert!(!syntax.env_req.is_match("<<ENV:FOO>>"));
+        assert_eq!(syntax.unescape("${\\ENV:FOO}"), "${ENV:FOO}");
+    }
+
+    #[test]
+    fn it_finds_inline_placeholders_with_nested_defaults() {
+        let syntax = Syntax::default();
+        let s = "a <<ENV:A:-<<ENV:B:-<<\\x>>>>>> b <<ENV?:C>>";
+        let placeholders = syntax.inline_placeholders(s);
+        assert_eq!(placeholders.len(), 2);
+        assert_eq!(placeholders[0].key, "A");
+        assert_eq!(placeholders[0].default, Some("<<ENV:B:-<<\\x>>>>"));
+        assert_eq!(&s[placeholders[0].end..], " b <<ENV?:C>>");
+        assert!(placeholders[1].optional);
+        assert_eq!(placeholders[1].default, None);
+    }
+
+    #[test]
+    fn it_rejects_empty_delimiters() {
+        assert!(Syntax::new("", ">>").is_err());
+        assert!(Syntax::new("<<", "").is_err());
+    }
+}
@@ -0,0 +1,163 @@
+use crate::syntax::Syntax;
+
+/// Rewrites placeholders written without quotes in value position, e.g. `debug = <<ENV:DEBUG>>`,
+/// into quoted strings so the TOML parses. Unquoted `<<ENV:...>>` and `<<ENV?:...>>` become
+/// `<<ENV_AUTO:...>>`, whose value is inserted as a boolean or number if it looks like one;
+/// other unquoted placeholders are quoted as written. Returns `None` if there was nothing to
+/// rewrite. Placeholders inside strings, comments, and keys are left alone.
+pub fn quote_placeholders(syntax: &Syntax, s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut quoted = String::with_capacity(s.len());
+    let mut copied = 0;
+    // The last byte outside of whitespace, strings, and comments, to tell values from keys.
+    let mut prev = b'\n';
+    // How many arrays deep the current value is, so the newlines of a multi-line array don't
+    // end it, and whether we're in a table header like `[table]`, whose brackets don't count.
+    let mut depth = 0usize;
+    let mut in_header = false;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let rest = &s[i..];
+        if rest.starts_with("\"\"\"") {
+            i = string_end(s, i + 3, "\"\"\"", true);
+            prev = b'"';
+        } else if rest.starts_with("'''") {
+            i = string_end(s, i + 3, "'''", false);
+            prev = b'\'';
+        } else if rest.starts_with('"') {
+            i = string_end(s, i + 1, "\"", true);
+            prev = b'"';
+        } else if rest.starts_with('\'') {
+            i = string_end(s, i + 1, "'", false);
+            prev = b'\'';
+        } else if rest.starts_with('#') {
+            i += rest.find('\n').unwrap_or(rest.len());
+        } else if rest.starts_with(syntax.prefix()) && matches!(prev, b'=' | b',' | b'[') {
+            let end = match syntax.balanced_end(s, i + syntax.prefix().len()) {
+                Some(end) => end + syntax.suffix().len(),
+                None => return None
+            };
+            quoted.push_str(&s[copied..i]);
+            quoted.push_str(&quote(&auto_typed(syntax, &s[i..end])));
+            copied = end;
+            i = end;
+            prev = b'"';
+        } else {
+            let c = rest.chars().next().unwrap();
+            if c == '\n' {
+                in_header = false;
+                if depth == 0 {
+                    prev = b'\n';
+                }
+            } else if c == '[' && prev == b'\n' {
+                // A table header rather than an array.
+                in_header = true;
+            } else if !c.is_whitespace() {
+                match c {
+                    '[' if !in_header => depth += 1,
+                    ']' if !in_header => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+                prev = bytes[i];
+            }
+            i += c.len_utf8();
+        }
+    }
+
+    if copied == 0 {
+        return None;
+    }
+    quoted.push_str(&s[copied..]);
+    Some(quoted)
+}
+
+/// The index just past the string closing `delimiter`, or the end of `s` if it's unterminated.
+fn string_end(s: &str, mut i: usize, delimiter: &str, escapes: bool) -> usize {
+    while i < s.len() {
+        let rest = &s[i..];
+        if escapes && rest.starts_with('\\') {
+            i += 1 + rest[1..].chars().next().map_or(0, char::len_utf8);
+        } else if rest.starts_with(delimiter) {
+            return i + delimiter.len();
+        } else {
+            i += rest.chars().next().unwrap().len_utf8();
+        }
+    }
+    i
+}
+
+fn auto_typed(syntax: &Syntax, placeholder: &str) -> String {
+    let inner = &placeholder[syntax.prefix().len()..];
+    if syntax.env_ref.is_match(placeholder) || syntax.env_format.is_match(placeholder) {
+        // The fallback keeps its type, and a formatted value is always a string, so neither is
+        // auto typed.
+        placeholder.to_owned()
+    } else if inner.starts_with("ENV:") || inner.starts_with("ENV?:") {
+        format!("{}ENV_AUTO{}", syntax.prefix(), &inner[3..])
+    } else {
+        placeholder.to_owned()
+    }
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::quote_placeholders;
+    use crate::syntax::Syntax;
+
+    #[test]
+    fn it_quotes_placeholders_in_value_position() {
+        let syntax = Syntax::default();
+        let config_str = r#"
+            debug = <<ENV:DEBUG>>
+            ports = [<<ENV?:PORT>>, 80, <<ENV_INT:OTHER_PORT>>]
+            inline = { rate = <<ENV:RATE:-0.5>> }
+            quoted = "<<ENV:QUOTED>> = <<ENV:NOT_A_VALUE>>"
+            # comment = <<ENV:COMMENTED>>
+            [table]
+            key = <<FILE:path>>
+        "#;
+
+        assert_eq!(quote_placeholders(&syntax, config_str).unwrap(), r#"
+            debug = "<<ENV_AUTO:DEBUG>>"
+            ports = ["<<ENV_AUTO?:PORT>>", 80, "<<ENV_INT:OTHER_PORT>>"]
+            inline = { rate = "<<ENV_AUTO:RATE:-0.5>>" }
+            quoted = "<<ENV:QUOTED>> = <<ENV:NOT_A_VALUE>>"
+            # comment = <<ENV:COMMENTED>>
+            [table]
+            key = "<<FILE:path>>"
+        "#);
+        assert_eq!(quote_placeholders(&syntax, "debug = \"<<ENV:DEBUG>>\""), None);
+    }
+
+    #[test]
+    fn it_quotes_placeholders_in_multi_line_arrays() {
+        let syntax = Syntax::default();
+        let config_str = r#"
+            ports = [
+              <<ENV:P>>, # first
+              [<<ENV:Q>>,
+               <<ENV:R>>],
+              <<ENV:P>>
+            ]
+            [[servers]]
+            host = <<ENV:HOST>>
+        "#;
+
+        assert_eq!(quote_placeholders(&syntax, config_str).unwrap(), r#"
+            ports = [
+              "<<ENV_AUTO:P>>", # first
+              ["<<ENV_AUTO:Q>>",
+               "<<ENV_AUTO:R>>"],
+              "<<ENV_AUTO:P>>"
+            ]
+            [[servers]]
+            host = "<<ENV_AUTO:HOST>>"
+        "#);
+    }
+}
@@ -0,0 +1,69 @@
+use serde::de::DeserializeOwned;
+use crate::expand::{self, ExpandOptions};
+use crate::strict::dotted_path;
+use crate::{Error, Format};
+
+/// A problem with a config that doesn't stop it loading, but may be worth logging.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// An optional or defaulted var wasn't set, so the key at `path` was dropped or its default
+    /// used.
+    EnvUnset { key: String, path: String },
+    /// The config type has no field for the key at `path`, so it was ignored.
+    UnknownKey { path: String }
+}
+
+/// Loads a TOML config like `load_config_from_str`, also returning the non-fatal problems found
+/// along the way, e.g. to log them at startup. Warnings found before a fatal error are still
+/// returned alongside it.
+pub fn load_config_collecting<C: DeserializeOwned>(config_str: &str) -> (Result<C, Error>, Vec<Warning>) {
+    let mut warnings = Vec::new();
+    let result = load(config_str, &mut warnings);
+    (result, warnings)
+}
+
+fn load<C: DeserializeOwned>(config_str: &str, warnings: &mut Vec<Warning>) -> Result<C, Error> {
+    let expanded = expand::expand(&ExpandOptions::default(), Format::Toml.parse(config_str)?)?;
+    for (path, keys) in &expanded.report().skipped {
+        warnings.extend(keys.iter().map(|key| Warning::EnvUnset { key: key.clone(), path: path.clone() }));
+    }
+
+    Ok(serde_ignored::deserialize(expanded.into_value(), |path| {
+        warnings.push(Warning::UnknownKey { path: dotted_path(&path) });
+    })?)
+}
+
+
+#[cfg(all(test, feature = "substitution"))]
+mod tests {
+    use super::{load_config_collecting, Warning};
+
+    #[derive(Debug, Deserialize)]
+    struct Config {
+        name: String,
+        port: Option<i64>
+    }
+
+    #[test]
+    fn it_collects_warnings() {
+        let config_str = r#"
+            name = "<<ENV:COLLECTING_UNSET:-default>>"
+            port = "<<ENV_INT?:COLLECTING_UNSET>>"
+            prot = 80
+        "#;
+
+        let (config, warnings) = load_config_collecting::<Config>(config_str);
+        let config = config.unwrap();
+        assert_eq!(&config.name, "default");
+        assert_eq!(config.port, None);
+        assert_eq!(warnings, vec![
+            Warning::EnvUnset { key: "COLLECTING_UNSET".to_owned(), path: "name".to_owned() },
+            Warning::EnvUnset { key: "COLLECTING_UNSET".to_owned(), path: "port".to_owned() },
+            Warning::UnknownKey { path: "prot".to_owned() }
+        ]);
+
+        let (config, warnings) = load_config_collecting::<Config>("prot = 80\n");
+        assert!(config.is_err());
+        assert_eq!(warnings, vec![Warning::UnknownKey { path: "prot".to_owned() }]);
+    }
+}
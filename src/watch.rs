@@ -0,0 +1,172 @@
+use notify::{EventKind, RecursiveMode, Watcher};
+use serde::de::DeserializeOwned;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+use crate::{load_config, Error, ErrorKind};
+
+/// How long the file must go unchanged before it's reloaded, so a burst of writes from an editor
+/// only triggers one reload.
+pub const DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// A running `watch_config`. Dropping it stops watching.
+pub struct ConfigWatcher {
+    _watcher: notify::RecommendedWatcher
+}
+
+/// Reloads the config at `path` with `load_config` whenever the file changes, passing each result
+/// to `callback` on a background thread. Failed reloads, e.g. from a half-written edit or a newly
+/// missing env var, are passed to `callback` as errors rather than stopping the watcher. The
+/// file's directory is watched, so editors that replace the file on save are handled. Requires
+/// the `watch` feature.
+pub fn watch_config<C, P, F>(path: P, mut callback: F) -> Result<ConfigWatcher, Error>
+    where C: DeserializeOwned + 'static,
+          P: AsRef<Path>,
+          F: FnMut(Result<C, Error>) + Send + 'static
+{
+    let path = path.as_ref().to_path_buf();
+    let file_name = path.file_name()
+        .map(|name| name.to_owned())
+        .ok_or_else(|| Error::from(ErrorKind::WatchPathInvalid(path.clone())))?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from(".")
+    };
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    })?;
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+
+    // The sender lives in the watcher, so dropping the `ConfigWatcher` disconnects the channel and
+    // ends this thread.
+    thread::spawn(move || {
+        while let Ok(event) = rx.recv() {
+            match event {
+                Ok(ref event) if !affects(event, &file_name) => continue,
+                Ok(_) => (),
+                Err(e) => {
+                    callback(Err(e.into()));
+                    continue;
+                }
+            }
+
+            loop {
+                match rx.recv_timeout(DEBOUNCE) {
+                    Ok(_) => continue,
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => return
+                }
+            }
+            callback(load_config(Some(&path)));
+        }
+    });
+
+    Ok(ConfigWatcher { _watcher: watcher })
+}
+
+/// A config that's reloaded whenever its file changes, keeping the last good value if a reload
+/// fails. Dropping it stops watching. Requires the `watch` feature.
+pub struct ReloadableConfig<C> {
+    current: Arc<RwLock<Arc<C>>>,
+    _watcher: ConfigWatcher
+}
+
+impl<C: DeserializeOwned + Send + Sync + 'static> ReloadableConfig<C> {
+    /// Loads the config at `path`, failing if the initial load does, then watches it as in
+    /// `watch_config`. A reload only replaces the current value if it succeeds; otherwise the
+    /// error is passed to `on_error` and the previous value is kept.
+    pub fn new<P, F>(path: P, mut on_error: F) -> Result<ReloadableConfig<C>, Error>
+        where P: AsRef<Path>,
+              F: FnMut(Error) + Send + 'static
+    {
+        let current = Arc::new(RwLock::new(Arc::new(load_config(Some(path.as_ref()))?)));
+
+        let reloaded = current.clone();
+        let watcher = watch_config(path, move |config: Result<C, Error>| match config {
+            Ok(config) => *reloaded.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(config),
+            Err(e) => on_error(e)
+        })?;
+
+        Ok(ReloadableConfig { current, _watcher: watcher })
+    }
+
+    /// The most recently loaded valid config.
+    pub fn current(&self) -> Arc<C> {
+        self.current.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+fn affects(event: &notify::Event, file_name: &OsString) -> bool {
+    let changed = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_));
+    changed && event.paths.iter().any(|path| path.file_name() == Some(file_name.as_os_str()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{watch_config, ReloadableConfig};
+    use crate::Error;
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn it_reloads_config_on_change() {
+        let dir = env::temp_dir().join("config_loader_watch_test");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("watched.toml");
+        fs::write(&path, "name = \"first\"\n").unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let watcher = watch_config(&path, move |config: Result<BTreeMap<String, String>, Error>| {
+            let _ = tx.send(config);
+        }).unwrap();
+
+        fs::write(&path, "name = \"second\"\n").unwrap();
+        let config = rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!(config["name"], "second");
+
+        fs::write(&path, "name = ").unwrap();
+        assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap().is_err());
+
+        drop(watcher);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_keeps_last_good_config_when_reload_fails() {
+        let dir = env::temp_dir().join("config_loader_reloadable_test");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("reloadable.toml");
+        fs::write(&path, "name = \"first\"\n").unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let config = ReloadableConfig::<BTreeMap<String, String>>::new(&path, move |e| {
+            let _ = tx.send(e);
+        }).unwrap();
+        assert_eq!(config.current()["name"], "first");
+
+        fs::write(&path, "name = ").unwrap();
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(config.current()["name"], "first");
+
+        fs::write(&path, "name = \"second\"\n").unwrap();
+        for _ in 0..50 {
+            if config.current()["name"] == "second" {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(config.current()["name"], "second");
+
+        drop(config);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
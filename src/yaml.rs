@@ -0,0 +1,123 @@
+use crate::{Error, ErrorKind};
+
+/// Converts a parsed YAML document into the TOML value tree that env substitution walks.
+///
+/// Aliases are already resolved by `serde_yaml` while parsing, and `<<` merge keys are applied
+/// before conversion. As with JSON, `null`s are dropped and tags are ignored. Scalar mapping keys
+/// are converted to strings; sequence or mapping keys produce `ErrorKind::InvalidKey`.
+pub fn to_toml(mut value: serde_yaml::Value) -> Result<Option<toml::Value>, Error> {
+    value.apply_merge()?;
+    convert(value)
+}
+
+fn convert(value: serde_yaml::Value) -> Result<Option<toml::Value>, Error> {
+    Ok(match value {
+        serde_yaml::Value::Null =>
+            None,
+        serde_yaml::Value::Bool(b) =>
+            Some(toml::Value::Boolean(b)),
+        serde_yaml::Value::Number(n) =>
+            Some(match n.as_i64() {
+                Some(i) => toml::Value::Integer(i),
+                None => toml::Value::Float(n.as_f64().unwrap_or(f64::NAN))
+            }),
+        serde_yaml::Value::String(s) =>
+            Some(toml::Value::String(s)),
+        serde_yaml::Value::Sequence(values) => {
+            let mut array = Vec::new();
+            for value in values {
+                array.extend(convert(value)?);
+            }
+            Some(toml::Value::Array(array))
+        },
+        serde_yaml::Value::Mapping(mapping) => {
+            let mut table = toml::value::Table::new();
+            for (k, v) in mapping {
+                let key = convert_key(k)?;
+                if let Some(v) = convert(v)? {
+                    table.insert(key, v);
+                }
+            }
+            Some(toml::Value::Table(table))
+        },
+        serde_yaml::Value::Tagged(tagged) =>
+            convert(tagged.value)?
+    })
+}
+
+fn convert_key(key: serde_yaml::Value) -> Result<String, Error> {
+    match key {
+        serde_yaml::Value::String(s) => Ok(s),
+        serde_yaml::Value::Bool(b) => Ok(b.to_string()),
+        serde_yaml::Value::Number(n) => Ok(n.to_string()),
+        serde_yaml::Value::Null => Ok("null".to_owned()),
+        serde_yaml::Value::Tagged(tagged) => convert_key(tagged.value),
+        other => Err(ErrorKind::InvalidKey(format!("{:?}", other)).into())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::super::load_config_from_yaml_str;
+
+    #[derive(Debug, Deserialize)]
+    struct Server {
+        host: String,
+        port: u16
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Nested {
+        inner: Vec<String>
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Config {
+        name: String,
+        missing: Option<String>,
+        nested: Nested,
+        servers: Vec<Server>
+    }
+
+    #[test]
+    fn it_loads_yaml_with_env_vars() {
+        let config_str = r#"
+name: "<<ENV:YAML_NAME:-yaml name>>"
+missing: ~
+nested:
+  inner:
+    - "<<ENV:YAML_INNER:-yaml inner>>"
+    - literal
+defaults: &server_defaults
+  host: "<<ENV:YAML_HOST:-yaml host>>"
+  port: 8080
+servers:
+  - *server_defaults
+  - <<: *server_defaults
+    port: 8081
+"#;
+
+        let config: Config = load_config_from_yaml_str(config_str).unwrap();
+        assert_eq!(&config.name, "yaml name");
+        assert_eq!(&config.missing, &None);
+        assert_eq!(config.nested.inner, vec!["yaml inner", "literal"]);
+        assert_eq!(&config.servers[0].host, "yaml host");
+        assert_eq!(config.servers[0].port, 8080);
+        assert_eq!(&config.servers[1].host, "yaml host");
+        assert_eq!(config.servers[1].port, 8081);
+    }
+
+    #[test]
+    fn it_fails_when_required_env_var_missing_in_yaml_sequence() {
+        let config_str = r#"
+name: literal
+nested:
+  inner:
+    - "<<ENV:YAML_MISSING>>"
+servers: []
+"#;
+
+        assert!(load_config_from_yaml_str::<Config>(config_str).is_err())
+    }
+}